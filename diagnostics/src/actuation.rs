@@ -0,0 +1,195 @@
+//! Actuator tests (Input/output control)
+//!
+//! Taking control of an output (A fan, pump or lamp) is always done through an [ActuatorGuard], which
+//! returns control to the ECU when it is released or dropped, including when the thread holding it panics.
+//! A watchdog also returns control once the maximum duration of the test has passed, so an output is never
+//! left stuck if the tester forgets about it.
+//!
+//! If returning control fails (For example, because the adapter was disconnected), the ECU is switched back to
+//! its default session, which makes it release all outputs. If the ECU cannot be reached at all, it releases
+//! the outputs by itself once its session times out.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+use logger::Logger;
+
+use crate::protocols::detect::EcuClient;
+use crate::protocols::session::default_session_id;
+use crate::protocols::{ProtocolError, ProtocolResult, RequestEngine};
+
+pub use crate::protocols::IoControlAction;
+
+#[derive(Debug)]
+struct GuardState {
+    released: bool,
+    /// Set if control was returned by the watchdog
+    timed_out: bool
+}
+
+/// Control of an output of an ECU. Control is returned to the ECU when the guard is released or dropped,
+/// or when its maximum duration has passed
+#[derive(Debug)]
+pub struct ActuatorGuard<A: AdapterHardware + 'static> {
+    engine: RequestEngine<A>,
+    id: u16,
+    deadline: Instant,
+    state: Arc<(Mutex<GuardState>, Condvar)>,
+    watchdog: Option<JoinHandle<()>>
+}
+
+impl<A: AdapterHardware + 'static> ActuatorGuard<A> {
+    /// Takes control of an output
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU
+    /// * id - The local identifier (KWP2000) or data identifier (UDS) of the output
+    /// * action - How to control the output
+    /// * max_duration - Control is returned to the ECU once this has passed
+    ///
+    /// ## Returns
+    /// The guard, along with the state of the output the ECU reported
+    pub fn start(engine: RequestEngine<A>, id: u16, action: IoControlAction, max_duration: Duration) -> ProtocolResult<(Self, Vec<u8>)> {
+        let state = io_control(&engine, id, &action)?;
+        let guard_state = Arc::new((Mutex::new(GuardState { released: false, timed_out: false }), Condvar::new()));
+        let deadline = Instant::now() + max_duration;
+        let watchdog = {
+            let (engine, guard_state) = (engine.clone(), guard_state.clone());
+            std::thread::spawn(move || {
+                let (lock, cvar) = &*guard_state;
+                let mut state = lock.lock().unwrap();
+                while !state.released {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        Logger::new("Actuation").log_warn(format!("Maximum duration reached, returning control of 0x{:04X} to the ECU", id));
+                        let _ = return_control(&engine, id);
+                        state.released = true;
+                        state.timed_out = true;
+                        break;
+                    }
+                    state = cvar.wait_timeout(state, remaining).unwrap().0;
+                }
+            })
+        };
+        Ok((Self { engine, id, deadline, state: guard_state, watchdog: Some(watchdog) }, state))
+    }
+
+    /// Changes how the output is controlled, without returning control in between
+    ///
+    /// ## Returns
+    /// The state of the output the ECU reported
+    pub fn adjust(&self, action: IoControlAction) -> ProtocolResult<Vec<u8>> {
+        // Held so that the watchdog cannot return control whilst the adjustment is sent
+        let state = self.state.0.lock().unwrap();
+        if state.released {
+            return Err(ProtocolError::ServerError("Control of the output has already been returned to the ECU".into()));
+        }
+        io_control(&self.engine, self.id, &action)
+    }
+
+    /// Returns true if the output is still controlled by the tester
+    pub fn is_active(&self) -> bool {
+        !self.state.0.lock().unwrap().released
+    }
+
+    /// Returns true if control was returned to the ECU because the maximum duration passed
+    pub fn timed_out(&self) -> bool {
+        self.state.0.lock().unwrap().timed_out
+    }
+
+    /// Time left until the watchdog returns control to the ECU
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Returns control of the output to the ECU
+    pub fn release(mut self) -> ProtocolResult<()> {
+        self.release_control()
+    }
+
+    fn release_control(&mut self) -> ProtocolResult<()> {
+        let res = {
+            let (lock, cvar) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            let res = if state.released { Ok(()) } else { return_control(&self.engine, self.id) };
+            state.released = true;
+            cvar.notify_all();
+            res
+        };
+        if let Some(t) = self.watchdog.take() {
+            let _ = t.join();
+        }
+        res
+    }
+}
+
+impl<A: AdapterHardware + 'static> Drop for ActuatorGuard<A> {
+    fn drop(&mut self) {
+        if let Err(e) = self.release_control() {
+            Logger::new("Actuation").log_err(format!("Could not return control of 0x{:04X} to the ECU: {:?}", self.id, e));
+        }
+    }
+}
+
+/// Sends an input/output control request
+fn io_control<A: AdapterHardware>(engine: &RequestEngine<A>, id: u16, action: &IoControlAction) -> ProtocolResult<Vec<u8>> {
+    EcuClient::from_engine(engine.clone()).io_control(id, action)
+}
+
+/// Returns control of an output to the ECU. If that fails, the ECU is switched to its default session,
+/// which releases all outputs
+fn return_control<A: AdapterHardware>(engine: &RequestEngine<A>, id: u16) -> ProtocolResult<()> {
+    let res = EcuClient::from_engine(engine.clone()).return_control(id);
+    if res.is_err() {
+        let _ = engine.send_request(&[0x10, default_session_id(engine.protocol())]);
+    }
+    res
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_engine;
+    use crate::protocols::DiagProtocol;
+
+    #[test]
+    pub fn test_return_control() {
+        // Output state of the ECU. None means the ECU is in control
+        let output = Arc::new(Mutex::new(None));
+        let ecu_output = output.clone();
+        let (_, engine) = sim_engine(DiagProtocol::UDS, move |req| {
+            match req {
+                [0x2F, 0x41, 0x20, 0x03, state] => {
+                    *ecu_output.lock().unwrap() = Some(*state);
+                    vec![0x6F, 0x41, 0x20, 0x03, *state]
+                },
+                [0x2F, 0x41, 0x20, 0x00] => {
+                    *ecu_output.lock().unwrap() = None;
+                    vec![0x6F, 0x41, 0x20, 0x00, 0x00]
+                },
+                _ => vec![0x7F, req[0], 0x31]
+            }
+        });
+
+        // Control is returned when the thread holding the guard panics
+        let panic_engine = engine.clone();
+        let res = std::thread::spawn(move || {
+            let (_fan, state) = ActuatorGuard::start(panic_engine, 0x4120, IoControlAction::ShortTermAdjustment(vec![0xFF]), Duration::from_secs(10)).unwrap();
+            assert_eq!(vec![0xFF], state);
+            panic!("Tester crashed");
+        }).join();
+        assert!(res.is_err());
+        assert_eq!(None, *output.lock().unwrap());
+
+        // Control is returned by the watchdog
+        let (fan, _) = ActuatorGuard::start(engine, 0x4120, IoControlAction::ShortTermAdjustment(vec![0x80]), Duration::from_millis(50)).unwrap();
+        assert_eq!(Some(0x80), *output.lock().unwrap());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(None, *output.lock().unwrap());
+        assert!(fan.timed_out());
+        assert!(fan.adjust(IoControlAction::ShortTermAdjustment(vec![0x40])).is_err());
+        fan.release().unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! ECU address book
+//!
+//! Maps each chassis (W203, W211, W221...) to the ECUs which may be fitted to it, and how they are reached
+//! from the diagnostic connector. A built-in table is compiled in, and further tables can be loaded from
+//! TOML or JSON files to extend it without recompiling. See `data/ecu_addresses.toml` for the layout.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocols::DiagProtocol;
+use crate::quick_test::{EcuCandidate, VariantCandidate};
+
+const BUILTIN_ADDRESS_BOOK: &str = include_str!("../data/ecu_addresses.toml");
+
+lazy_static::lazy_static! {
+    static ref BUILTIN_BOOK: EcuAddressBook = EcuAddressBook::from_toml(BUILTIN_ADDRESS_BOOK).expect("Built-in address book is invalid");
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressBookError {
+    /// The file could not be read
+    IoError(String),
+    /// Malformed TOML
+    InvalidToml(String),
+    /// Malformed JSON
+    InvalidJson(String),
+    /// The file extension is neither `.toml` nor `.json`
+    UnknownFormat(String)
+}
+
+impl AddressBookError {
+    pub fn get_err_desc(&self) -> String {
+        match &self {
+            AddressBookError::IoError(e) => format!("IO Error: {}", e),
+            AddressBookError::InvalidToml(e) => format!("Address book TOML malformed: {}", e),
+            AddressBookError::InvalidJson(e) => format!("Address book JSON malformed: {}", e),
+            AddressBookError::UnknownFormat(p) => format!("{} is not a TOML or JSON file", p)
+        }
+    }
+}
+
+pub type AddressBookResult<T> = std::result::Result<T, AddressBookError>;
+
+/// How an ECU is reached
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EcuAddress {
+    /// ISO-TP over CAN
+    Can {
+        /// CAN ID requests are sent to
+        tx_id: u32,
+        /// CAN ID the ECU responds with
+        rx_id: u32
+    },
+    /// K-Line address of the ECU
+    KLine(u8)
+}
+
+/// An ECU (Or one variant of it) which may be fitted to a chassis
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuEntry {
+    /// Short name of the ECU, such as `ME` or `EZS`. Entries with the same name are variants of the same ECU
+    pub name: String,
+    /// Name of the variant, if the ECU has more than one
+    #[serde(default)]
+    pub variant: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    /// The bus the ECU is reached through, such as `CAN-D` or `K-Line`
+    pub bus: String,
+    /// Bitrate of the bus (In bps)
+    pub baud: u32,
+    /// The protocol the ECU speaks, or None if it differs between software versions and must be detected
+    #[serde(default)]
+    pub protocol: Option<DiagProtocol>,
+    pub address: EcuAddress
+}
+
+impl EcuEntry {
+    /// Returns the request and response CAN IDs, if the ECU is reached over CAN
+    pub fn can_ids(&self) -> Option<(u32, u32)> {
+        match self.address {
+            EcuAddress::Can { tx_id, rx_id } => Some((tx_id, rx_id)),
+            EcuAddress::KLine(_) => None
+        }
+    }
+
+    /// Name of the variant, which is the name of the ECU if it only has one
+    pub fn variant_name(&self) -> &str {
+        self.variant.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// The ECUs of one chassis. Closely related chassis (Saloon, estate, coupe) usually share an entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChassisEntry {
+    /// Chassis codes, such as `W211` and `S211`
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub ecus: Vec<EcuEntry>
+}
+
+impl ChassisEntry {
+    /// Returns true if the chassis has a name (Case insensitive)
+    pub fn has_name(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the variants of an ECU, in the order they should be probed
+    pub fn ecu_variants(&self, name: &str) -> Vec<&EcuEntry> {
+        self.ecus.iter().filter(|e| e.name.eq_ignore_ascii_case(name)).collect()
+    }
+
+    /// Builds the quick test candidates for the chassis. ECUs which are not reached over CAN are left out
+    pub fn quick_test_candidates(&self) -> Vec<EcuCandidate> {
+        let mut candidates: Vec<EcuCandidate> = Vec::new();
+        for ecu in &self.ecus {
+            let (tx_id, rx_id) = match ecu.can_ids() {
+                Some(ids) => ids,
+                None => continue
+            };
+            let variant = VariantCandidate { name: ecu.variant_name().to_string(), tx_id, rx_id, protocol: ecu.protocol };
+            match candidates.iter_mut().find(|c| c.name == ecu.name) {
+                Some(c) => c.variants.push(variant),
+                None => candidates.push(EcuCandidate { name: ecu.name.clone(), variants: vec![variant] })
+            }
+        }
+        candidates
+    }
+}
+
+/// Table of the ECUs of each chassis
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct EcuAddressBook {
+    #[serde(default)]
+    chassis: Vec<ChassisEntry>
+}
+
+impl EcuAddressBook {
+    /// Returns the shared built-in address book
+    pub fn builtin() -> &'static EcuAddressBook {
+        &BUILTIN_BOOK
+    }
+
+    pub fn from_toml(s: &str) -> AddressBookResult<Self> {
+        toml::from_str(s).map_err(|e| AddressBookError::InvalidToml(e.to_string()))
+    }
+
+    pub fn from_json(s: &str) -> AddressBookResult<Self> {
+        serde_json::from_str(s).map_err(|e| AddressBookError::InvalidJson(e.to_string()))
+    }
+
+    /// Loads an address book from a `.toml` or `.json` file
+    pub fn load<P: AsRef<Path>>(path: P) -> AddressBookResult<Self> {
+        let path = path.as_ref();
+        let is_toml = match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("toml") => true,
+            Some("json") => false,
+            _ => return Err(AddressBookError::UnknownFormat(path.display().to_string()))
+        };
+        let s = fs::read_to_string(path).map_err(|e| AddressBookError::IoError(e.to_string()))?;
+        if is_toml {
+            Self::from_toml(&s)
+        } else {
+            Self::from_json(&s)
+        }
+    }
+
+    /// Adds the entries of another address book. If a chassis is already known, its ECUs are merged, with
+    /// variants in the other book replacing variants of the same name
+    pub fn merge(&mut self, other: EcuAddressBook) {
+        for chassis in other.chassis {
+            let existing = self.chassis.iter_mut().find(|c| chassis.names.iter().any(|n| c.has_name(n)));
+            let existing = match existing {
+                Some(c) => c,
+                None => {
+                    self.chassis.push(chassis);
+                    continue;
+                }
+            };
+            for name in chassis.names {
+                if !existing.has_name(&name) {
+                    existing.names.push(name);
+                }
+            }
+            for ecu in chassis.ecus {
+                match existing.ecus.iter_mut().find(|e| e.name == ecu.name && e.variant_name() == ecu.variant_name()) {
+                    Some(e) => *e = ecu,
+                    None => existing.ecus.push(ecu)
+                }
+            }
+        }
+    }
+
+    /// Returns the entry of a chassis (Case insensitive)
+    pub fn chassis(&self, name: &str) -> Option<&ChassisEntry> {
+        self.chassis.iter().find(|c| c.has_name(name))
+    }
+
+    /// Returns every chassis in the address book
+    pub fn all(&self) -> &[ChassisEntry] {
+        &self.chassis
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_address_book() {
+        let builtin = EcuAddressBook::builtin();
+        let w211 = builtin.chassis("s211").unwrap();
+        assert_eq!(Some((0x07E1, 0x07E9)), w211.ecu_variants("EGS")[0].can_ids());
+        let candidates = w211.quick_test_candidates();
+        assert_eq!(vec!["ME97", "ME97_UDS"], candidates[0].variants.iter().map(|v| v.name.as_str()).collect::<Vec<_>>());
+        assert!(builtin.chassis("W210").unwrap().quick_test_candidates().is_empty());
+        assert_eq!(vec!["ME20", "CDI3"], builtin.chassis("W203").unwrap().ecu_variants("ME").iter().map(|e| e.variant_name()).collect::<Vec<_>>());
+        // ECUs which respond with the same ID would be probed at the same time by the quick test
+        for chassis in builtin.all() {
+            for a in &chassis.ecus {
+                let shared = chassis.ecus.iter().find(|b| b.name != a.name && b.can_ids().is_some() && b.can_ids().map(|i| i.1) == a.can_ids().map(|i| i.1));
+                assert!(shared.is_none(), "{} and {} of {} share a response ID", a.name, shared.unwrap().name, chassis.names[0]);
+            }
+        }
+
+        // Extend the W211 with an ECU from JSON, and add a chassis
+        let json = r#"{ "chassis": [
+            { "names": ["W211"], "ecus": [{ "name": "AGW", "bus": "CAN-D", "baud": 500000, "address": { "can": { "tx_id": 1866, "rx_id": 1226 } } }] },
+            { "names": ["W906"], "description": "Sprinter" }
+        ] }"#;
+        let mut book = builtin.clone();
+        book.merge(EcuAddressBook::from_json(json).unwrap());
+        assert_eq!(builtin.all().len() + 1, book.all().len());
+        let agw = book.chassis("W211").unwrap().ecu_variants("AGW")[0];
+        assert_eq!((EcuAddress::Can { tx_id: 0x074A, rx_id: 0x04CA }, None), (agw.address, agw.protocol));
+
+        assert_eq!(book, EcuAddressBook::from_json(&serde_json::to_string(&book).unwrap()).unwrap());
+        assert!(matches!(EcuAddressBook::load("ecus.xml"), Err(AddressBookError::UnknownFormat(_))));
+        assert!(matches!(EcuAddressBook::load("missing_ecus.json"), Err(AddressBookError::IoError(_))));
+
+        // Load from files of both formats. The toml crate cannot serialize the address enum, so the TOML file is the built-in book
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("address_book_{}.TOML", std::process::id()));
+        let json_path = dir.join(format!("address_book_{}.json", std::process::id()));
+        std::fs::write(&toml_path, BUILTIN_ADDRESS_BOOK).unwrap();
+        std::fs::write(&json_path, serde_json::to_string(&book).unwrap()).unwrap();
+        let from_toml = EcuAddressBook::load(&toml_path);
+        let from_json = EcuAddressBook::load(&json_path);
+        let _ = std::fs::remove_file(&toml_path);
+        let _ = std::fs::remove_file(&json_path);
+        assert_eq!(builtin, &from_toml.unwrap());
+        assert_eq!(book, from_json.unwrap());
+    }
+}
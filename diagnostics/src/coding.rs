@@ -0,0 +1,342 @@
+//! Variant coding with automatic backup
+//!
+//! Before a coding string is written, the coding currently stored in the ECU is read and saved to a
+//! [CodingJournal] on disk, keyed by the VIN, ECU and identifier. The new coding is then written and read back
+//! to verify it. Any write recorded in the journal can be undone with [Coder::rollback].
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hardware::AdapterHardware;
+use serde::{Deserialize, Serialize};
+
+use crate::protocols::detect::EcuClient;
+use crate::protocols::{DiagProtocol, ProtocolError, ProtocolResult, RequestEngine};
+
+/// State of a coding write recorded in the journal
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodingStatus {
+    /// The original coding was saved, but the write has not finished, or it failed without the ECU rejecting
+    /// it (For example, because the response timed out). The ECU may contain either coding
+    Pending,
+    /// The coding was written and read back successfully
+    Verified,
+    /// The coding was written, but the ECU returned a different coding when it was read back
+    VerifyFailed,
+    /// The ECU rejected the write with a negative response, so it still contains the original coding
+    WriteFailed,
+    /// The original coding was written back
+    RolledBack
+}
+
+/// A coding write recorded in the journal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub vin: String,
+    /// Name of the ECU
+    pub ecu: String,
+    /// The local identifier (KWP2000) or data identifier (UDS) of the coding
+    pub identifier: u16,
+    pub protocol: DiagProtocol,
+    /// Coding stored in the ECU before the write
+    pub original: Vec<u8>,
+    /// Coding which was written
+    pub written: Vec<u8>,
+    /// Time of the write (Seconds since the UNIX epoch)
+    pub timestamp: u64,
+    pub status: CodingStatus
+}
+
+impl JournalEntry {
+    fn matches(&self, vin: &str, ecu: &str, identifier: u16) -> bool {
+        self.vin == vin && self.ecu == ecu && self.identifier == identifier
+    }
+}
+
+/// Record of every coding write, saved as JSON. The file is rewritten after every change,
+/// so that the original coding is on disk before the ECU is touched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodingJournal {
+    path: PathBuf,
+    entries: Vec<JournalEntry>
+}
+
+impl CodingJournal {
+    /// Opens a journal file, or starts a new journal if the file does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        Ok(Self { path, entries })
+    }
+
+    /// Every write recorded in the journal, oldest first
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Returns the writes recorded for a coding, oldest first
+    pub fn history(&self, vin: &str, ecu: &str, identifier: u16) -> Vec<&JournalEntry> {
+        self.entries.iter().filter(|e| e.matches(vin, ecu, identifier)).collect()
+    }
+
+    /// Returns the coding the ECU had before it was first coded with this journal
+    pub fn original(&self, vin: &str, ecu: &str, identifier: u16) -> Option<&[u8]> {
+        self.entries.iter().find(|e| e.matches(vin, ecu, identifier)).map(|e| e.original.as_slice())
+    }
+
+    fn push(&mut self, entry: JournalEntry) -> io::Result<usize> {
+        self.entries.push(entry);
+        self.save()?;
+        Ok(self.entries.len() - 1)
+    }
+
+    fn set_status(&mut self, idx: usize, status: CodingStatus) -> io::Result<()> {
+        self.entries[idx].status = status;
+        self.save()
+    }
+
+    /// Writes the journal to a temporary file first, so a crash cannot leave a half written journal behind.
+    /// Both the file and the directory are synced, so the journal survives a power loss once this returns
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.entries).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        sync_dir(self.path.parent())
+    }
+}
+
+/// Syncs a directory, so a file renamed into it is on disk. Directories cannot be opened as files on Windows,
+/// where the rename itself is durable
+fn sync_dir(dir: Option<&Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match dir {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new(".")
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn journal_error(e: io::Error) -> ProtocolError {
+    ProtocolError::ServerError(format!("Could not update the coding journal: {}", e))
+}
+
+/// Reads and writes the coding of an ECU, recording every write in a journal
+#[derive(Debug)]
+pub struct Coder<'a, A: AdapterHardware> {
+    engine: RequestEngine<A>,
+    vin: String,
+    ecu: String,
+    journal: &'a mut CodingJournal
+}
+
+impl<'a, A: AdapterHardware> Coder<'a, A> {
+    /// Creates a coder for an ECU
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU
+    /// * vin - The VIN of the vehicle
+    /// * ecu - Name of the ECU
+    /// * journal - The journal to record writes in
+    pub fn new(engine: RequestEngine<A>, vin: &str, ecu: &str, journal: &'a mut CodingJournal) -> Self {
+        Self { engine, vin: vin.into(), ecu: ecu.into(), journal }
+    }
+
+    /// Reads a coding from the ECU
+    pub fn read_coding(&self, identifier: u16) -> ProtocolResult<Vec<u8>> {
+        EcuClient::from_engine(self.engine.clone()).read_data_record(identifier)
+    }
+
+    /// Writes a coding to the ECU. The current coding is saved to the journal first, and the new
+    /// coding is read back afterwards to verify it. The current coding is read twice, and nothing is
+    /// written unless both reads match, so that a corrupted read never becomes the backup
+    ///
+    /// ## Returns
+    /// The coding the ECU had before the write
+    pub fn write_coding(&mut self, identifier: u16, coding: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let original = self.read_coding(identifier)?;
+        let second = self.read_coding(identifier)?;
+        if second != original {
+            return Err(ProtocolError::ServerError(format!("Coding of 0x{:04X} read as {:02X?} and then {:02X?}, not writing", identifier, original, second)));
+        }
+        let idx = self.journal.push(JournalEntry {
+            vin: self.vin.clone(),
+            ecu: self.ecu.clone(),
+            identifier,
+            protocol: self.engine.protocol(),
+            original: original.clone(),
+            written: coding.to_vec(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            status: CodingStatus::Pending
+        }).map_err(journal_error)?;
+
+        if let Err(e) = self.write_raw(identifier, coding) {
+            // Only a negative response proves that the ECU kept the original coding. After any other error,
+            // the entry stays pending so that the write can still be rolled back
+            if let ProtocolError::ECUError { .. } = e {
+                self.journal.set_status(idx, CodingStatus::WriteFailed).map_err(journal_error)?;
+            }
+            return Err(e);
+        }
+        let verified = self.verify(identifier, coding);
+        let status = if verified.is_ok() { CodingStatus::Verified } else { CodingStatus::VerifyFailed };
+        self.journal.set_status(idx, status).map_err(journal_error)?;
+        verified.map(|_| original)
+    }
+
+    /// Undoes the last write of a coding which has not been rolled back yet, by writing back the coding
+    /// the ECU had before it. Pending writes are rolled back as well, as the ECU may have stored them
+    ///
+    /// ## Returns
+    /// The coding which was restored
+    pub fn rollback(&mut self, identifier: u16) -> ProtocolResult<Vec<u8>> {
+        let (vin, ecu) = (&self.vin, &self.ecu);
+        let idx = self.journal.entries.iter()
+            .rposition(|e| e.matches(vin, ecu, identifier) && !matches!(e.status, CodingStatus::RolledBack | CodingStatus::WriteFailed))
+            .ok_or_else(|| ProtocolError::ServerError(format!("No coding of 0x{:04X} to roll back in the journal", identifier)))?;
+        let original = self.journal.entries[idx].original.clone();
+        self.write_raw(identifier, &original)?;
+        self.verify(identifier, &original)?;
+        self.journal.set_status(idx, CodingStatus::RolledBack).map_err(journal_error)?;
+        Ok(original)
+    }
+
+    fn write_raw(&self, identifier: u16, coding: &[u8]) -> ProtocolResult<()> {
+        EcuClient::from_engine(self.engine.clone()).write_data_record(identifier, coding)
+    }
+
+    fn verify(&self, identifier: u16, coding: &[u8]) -> ProtocolResult<()> {
+        let read_back = self.read_coding(identifier)?;
+        if read_back != coding {
+            return Err(ProtocolError::ServerError(format!("Coding of 0x{:04X} reads back as {:02X?} after writing {:02X?}", identifier, read_back, coding)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::{faulty_sim_engine, sim_engine};
+    use crate::protocols::EcuTiming;
+    use hardware::fault_injection::FaultConfig;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    pub fn test_coding_rollback() {
+        let stored = Arc::new(Mutex::new(vec![0x01, 0x02, 0x03]));
+        let ecu_coding = stored.clone();
+        let (_, engine) = sim_engine(DiagProtocol::KWP2000, move |req| {
+            match req {
+                [0x21, 0x10] => [&[0x61, 0x10][..], &ecu_coding.lock().unwrap()].concat(),
+                [0x3B, 0x10, 0xEE, ..] => vec![0x7F, 0x3B, 0x31],
+                [0x3B, 0x10, coding @ ..] => {
+                    *ecu_coding.lock().unwrap() = coding.to_vec();
+                    // The ECU stores this coding, but its response is lost
+                    if coding[0] == 0xFF { Vec::new() } else { vec![0x7B, 0x10] }
+                },
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+
+        let path = std::env::temp_dir().join(format!("coding_journal_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut journal = CodingJournal::open(&path).unwrap();
+        {
+            let mut coder = Coder::new(engine.clone(), "WDD2040012A123456", "SAM", &mut journal);
+            assert_eq!(vec![0x01, 0x02, 0x03], coder.write_coding(0x10, &[0x01, 0x22, 0x03]).unwrap());
+            assert_eq!(vec![0x01, 0x22, 0x03], *stored.lock().unwrap());
+        }
+
+        // The journal survives the tester being restarted
+        let mut journal = CodingJournal::open(&path).unwrap();
+        assert_eq!(CodingStatus::Verified, journal.entries()[0].status);
+        let mut coder = Coder::new(engine, "WDD2040012A123456", "SAM", &mut journal);
+        assert_eq!(vec![0x01, 0x02, 0x03], coder.rollback(0x10).unwrap());
+        assert_eq!(vec![0x01, 0x02, 0x03], *stored.lock().unwrap());
+        assert!(coder.rollback(0x10).is_err());
+
+        // A rejected write can not be rolled back, but one whose response was lost can
+        assert!(matches!(coder.write_coding(0x10, &[0xEE, 0x02, 0x03]), Err(ProtocolError::ECUError { .. })));
+        assert!(coder.rollback(0x10).is_err());
+        assert!(coder.write_coding(0x10, &[0xFF, 0x02, 0x03]).unwrap_err().is_timeout());
+        assert_eq!(vec![0x01, 0x02, 0x03], coder.rollback(0x10).unwrap());
+        assert_eq!(vec![0x01, 0x02, 0x03], *stored.lock().unwrap());
+        let statuses: Vec<CodingStatus> = journal.entries().iter().map(|e| e.status).collect();
+        assert_eq!(vec![CodingStatus::RolledBack, CodingStatus::WriteFailed, CodingStatus::RolledBack], statuses);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_coding_under_faults() {
+        let config = FaultConfig {
+            seed: 0xC0DE,
+            drop_rate: 0.1,
+            delay_rate: 0.05,
+            delay_ms: (5, 80),
+            duplicate_rate: 0.1,
+            corrupt_rate: 0.1,
+            error_rate: 0.1
+        };
+        let stored = Arc::new(Mutex::new(vec![0x00, 0x00]));
+        let ecu_coding = stored.clone();
+        let (adapter, mut engine) = faulty_sim_engine(DiagProtocol::UDS, config, move |req| {
+            match req {
+                [0x22, 0x01, 0x10] => [&[0x62, 0x01, 0x10][..], &ecu_coding.lock().unwrap()].concat(),
+                [0x2E, 0x01, 0x10, coding @ ..] if coding.len() == 2 => {
+                    *ecu_coding.lock().unwrap() = coding.to_vec();
+                    vec![0x6E, 0x01, 0x10]
+                },
+                [0x2E, ..] => vec![0x7F, 0x2E, 0x13],
+                _ => vec![0x7F, req[0], 0x31]
+            }
+        });
+        engine.set_timing(EcuTiming { p2_ms: 50, p2_star_ms: 200, busy_retries: 1, busy_backoff_ms: 10 });
+
+        let path = std::env::temp_dir().join(format!("coding_journal_faults_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut journal = CodingJournal::open(&path).unwrap();
+        let mut coder = Coder::new(engine, "WDD2040012A123456", "SAM", &mut journal);
+        for i in 1..=40u8 {
+            let before = stored.lock().unwrap().clone();
+            let entries = coder.journal.entries().len();
+            let coding = [i, !i];
+            if coder.write_coding(0x0110, &coding).is_ok() {
+                assert_eq!(coding.to_vec(), *stored.lock().unwrap());
+                continue;
+            }
+            if coder.journal.entries().len() == entries {
+                // The original coding could not be read, so nothing was written
+                assert_eq!(before, *stored.lock().unwrap());
+                continue;
+            }
+            let entry = coder.journal.entries().last().unwrap().clone();
+            assert_eq!(before, entry.original);
+            if entry.status == CodingStatus::WriteFailed {
+                assert_eq!(before, *stored.lock().unwrap());
+                continue;
+            }
+            // Whatever the ECU ended up with, the write can be undone once the connection is good again
+            adapter.set_config(FaultConfig { seed: config.seed, ..Default::default() });
+            assert_eq!(before, coder.rollback(0x0110).unwrap());
+            assert_eq!(before, *stored.lock().unwrap());
+            adapter.set_config(config);
+        }
+        let stats = adapter.stats();
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.corrupted > 0 && stats.errors > 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
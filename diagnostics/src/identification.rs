@@ -0,0 +1,295 @@
+//! ECU identification, as shown on the first screen of DAS and Xentry
+//!
+//! KWP2000 ECUs are identified with ReadECUIdentification (0x1A) using the Mercedes options 0x86 - 0x89,
+//! and UDS ECUs with the identification data identifiers (0xF1xx).
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ecu_suppliers::{SupplierCode, SupplierLookup, SupplierRegistry};
+use crate::protocols::kwp2000::Kwp2000Server;
+use crate::protocols::uds::UdsServer;
+use crate::protocols::{DiagProtocol, ProtocolError, ProtocolResult};
+
+/// KWP2000 ReadECUIdentification option for the Mercedes ECU identification
+pub const KWP_ID_DAIMLER: u8 = 0x86;
+/// KWP2000 ReadECUIdentification option for the Mercedes (MMC) ECU identification. Its layout varies
+/// between ECU generations, so it is only kept raw
+pub const KWP_ID_DAIMLER_MMC: u8 = 0x87;
+/// KWP2000 ReadECUIdentification option for the original VIN
+pub const KWP_ID_VIN_ORIGINAL: u8 = 0x88;
+/// KWP2000 ReadECUIdentification option for the diagnostic variant code
+pub const KWP_ID_DIAG_VARIANT: u8 = 0x89;
+
+/// UDS DID of the Mercedes diagnostic information (Diagnostic version)
+pub const UDS_DID_DIAG_INFO: u16 = 0xF100;
+/// UDS DID of the Mercedes hardware part number
+pub const UDS_DID_PART_NUMBER: u16 = 0xF111;
+/// UDS DID of the hardware version
+pub const UDS_DID_HW_VERSION: u16 = 0xF150;
+/// UDS DID of the software version
+pub const UDS_DID_SW_VERSION: u16 = 0xF151;
+/// UDS DID of the boot software version
+pub const UDS_DID_BOOT_VERSION: u16 = 0xF152;
+/// UDS DID of the boot software identification
+pub const UDS_DID_BOOT_ID: u16 = 0xF153;
+/// UDS DID of the hardware supplier
+pub const UDS_DID_SUPPLIER: u16 = 0xF154;
+/// UDS DID of the system supplier
+pub const UDS_DID_SYSTEM_SUPPLIER: u16 = 0xF18A;
+/// UDS DID of the ECU production date
+pub const UDS_DID_PRODUCTION_DATE: u16 = 0xF18B;
+/// UDS DID of the ECU serial number
+pub const UDS_DID_SERIAL_NUMBER: u16 = 0xF18C;
+/// UDS DID of the VIN
+pub const UDS_DID_VIN: u16 = 0xF190;
+
+const UDS_IDENTIFICATION_DIDS: [u16; 11] = [
+    UDS_DID_DIAG_INFO, UDS_DID_PART_NUMBER, UDS_DID_HW_VERSION, UDS_DID_SW_VERSION, UDS_DID_BOOT_VERSION, UDS_DID_BOOT_ID,
+    UDS_DID_SUPPLIER, UDS_DID_SYSTEM_SUPPLIER, UDS_DID_PRODUCTION_DATE, UDS_DID_SERIAL_NUMBER, UDS_DID_VIN
+];
+
+/// Hardware or software version, which Mercedes encodes as the year and week of its release
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuVersion {
+    /// Year (Last 2 digits)
+    pub year: u8,
+    /// Week of the year
+    pub week: u8,
+    /// Patch level, if the ECU reports one
+    pub patch: Option<u8>
+}
+
+impl fmt::Display for EcuVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.patch {
+            Some(p) => write!(f, "{:02}/{:02} {:02}", self.year, self.week, p),
+            None => write!(f, "{:02}/{:02}", self.year, self.week)
+        }
+    }
+}
+
+/// A date reported by the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8
+}
+
+impl fmt::Display for EcuDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}.{:02}.{:04}", self.day, self.month, self.year)
+    }
+}
+
+/// Identification of an ECU
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuIdentification {
+    pub protocol: DiagProtocol,
+    /// Mercedes part number, such as `A2114460540`
+    pub part_number: Option<String>,
+    pub hardware_version: Option<EcuVersion>,
+    pub software_version: Option<EcuVersion>,
+    pub boot_software_version: Option<EcuVersion>,
+    /// Diagnostic version, which identifies the variant (And description file) of the ECU
+    pub diag_version: Option<u16>,
+    /// Diagnostic variant code (KWP2000)
+    pub variant_code: Option<u16>,
+    pub production_date: Option<EcuDate>,
+    pub supplier: Option<SupplierLookup>,
+    /// System supplier name (UDS)
+    pub system_supplier: Option<String>,
+    pub serial_number: Option<String>,
+    pub vin: Option<String>,
+    /// Every identification record read, keyed by KWP2000 option or UDS DID
+    pub raw: BTreeMap<u16, Vec<u8>>
+}
+
+fn bcd(b: u8) -> u8 {
+    (b >> 4) * 10 + (b & 0x0F)
+}
+
+fn ascii(data: &[u8]) -> Option<String> {
+    let s: String = data.iter().filter(|b| b.is_ascii_graphic() || **b == b' ').map(|b| *b as char).collect();
+    let s = s.trim();
+    if s.is_empty() { None } else { Some(s.to_string()) }
+}
+
+/// Decodes a version of 2 or 3 BCD bytes (Year, week and optional patch level)
+fn version(data: &[u8]) -> Option<EcuVersion> {
+    match data {
+        [y, w, rest @ ..] => Some(EcuVersion { year: bcd(*y), week: bcd(*w), patch: rest.first().map(|p| bcd(*p)) }),
+        _ => None
+    }
+}
+
+/// Decodes a date of 3 BCD bytes (Year, month and day)
+fn date(data: &[u8]) -> Option<EcuDate> {
+    match data {
+        [y, m, d, ..] => Some(EcuDate { year: 2000 + bcd(*y) as u16, month: bcd(*m), day: bcd(*d) }),
+        _ => None
+    }
+}
+
+/// Converts a negative response into None, so that optional identification records can be skipped
+fn optional<T>(res: ProtocolResult<T>) -> ProtocolResult<Option<T>> {
+    match res {
+        Ok(r) => Ok(Some(r)),
+        Err(ProtocolError::ECUError { .. }) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+impl EcuIdentification {
+    fn new(protocol: DiagProtocol) -> Self {
+        Self {
+            protocol,
+            part_number: None,
+            hardware_version: None,
+            software_version: None,
+            boot_software_version: None,
+            diag_version: None,
+            variant_code: None,
+            production_date: None,
+            supplier: None,
+            system_supplier: None,
+            serial_number: None,
+            vin: None,
+            raw: BTreeMap::new()
+        }
+    }
+
+    /// Reads the identification of a KWP2000 ECU. Only the Mercedes identification (0x86) is required,
+    /// the other records are skipped if the ECU does not support them
+    pub fn read_kwp<S: Kwp2000Server + ?Sized>(server: &mut S) -> ProtocolResult<Self> {
+        let mut ident = Self::new(DiagProtocol::KWP2000);
+        ident.decode_kwp_daimler(&server.read_ecu_identification(KWP_ID_DAIMLER)?)?;
+        for option in [KWP_ID_DAIMLER_MMC, KWP_ID_VIN_ORIGINAL, KWP_ID_DIAG_VARIANT] {
+            if let Some(data) = optional(server.read_ecu_identification(option))? {
+                match option {
+                    KWP_ID_VIN_ORIGINAL => ident.vin = ascii(&data),
+                    KWP_ID_DIAG_VARIANT if data.len() >= 2 => ident.variant_code = Some(u16::from_be_bytes([data[0], data[1]])),
+                    _ => {}
+                }
+                ident.raw.insert(option as u16, data);
+            }
+        }
+        Ok(ident)
+    }
+
+    /// Decodes the Mercedes ECU identification (0x1A 0x86):
+    ///
+    /// | Bytes | Content |
+    /// |-------|---------|
+    /// | 0-4   | Part number (BCD) |
+    /// | 5-6   | Hardware version (BCD year, week) |
+    /// | 7-8   | Software version (BCD year, week) |
+    /// | 9     | Supplier |
+    /// | 10-11 | Diagnostic version |
+    /// | 12    | Reserved |
+    /// | 13-15 | Production date (BCD year, month, day) |
+    fn decode_kwp_daimler(&mut self, data: &[u8]) -> ProtocolResult<()> {
+        if data.len() < 16 {
+            return Err(ProtocolError::InvalidResponse(data.to_vec()));
+        }
+        self.part_number = Some(format!("A{:02X}{:02X}{:02X}{:02X}{:02X}", data[0], data[1], data[2], data[3], data[4]));
+        self.hardware_version = version(&data[5..7]);
+        self.software_version = version(&data[7..9]);
+        self.supplier = Some(SupplierRegistry::builtin().lookup(SupplierCode::Kwp(data[9])));
+        self.diag_version = Some(u16::from_be_bytes([data[10], data[11]]));
+        self.production_date = date(&data[13..16]);
+        self.raw.insert(KWP_ID_DAIMLER as u16, data.to_vec());
+        Ok(())
+    }
+
+    /// Reads the identification of a UDS ECU. Identifiers the ECU does not support are skipped,
+    /// but at least one must be supported
+    pub fn read_uds<S: UdsServer + ?Sized>(server: &mut S) -> ProtocolResult<Self> {
+        let mut ident = Self::new(DiagProtocol::UDS);
+        let mut last_err = None;
+        for did in UDS_IDENTIFICATION_DIDS {
+            match server.read_data_by_identifier(did) {
+                Ok(data) => ident.decode_uds_did(did, data),
+                Err(e @ ProtocolError::ECUError { .. }) => last_err = Some(e),
+                Err(e) => return Err(e)
+            }
+        }
+        match last_err {
+            Some(e) if ident.raw.is_empty() => Err(e),
+            _ => Ok(ident)
+        }
+    }
+
+    fn decode_uds_did(&mut self, did: u16, data: Vec<u8>) {
+        match did {
+            UDS_DID_DIAG_INFO if data.len() >= 2 => self.diag_version = Some(u16::from_be_bytes([data[0], data[1]])),
+            UDS_DID_PART_NUMBER => self.part_number = ascii(&data).map(|p| if p.starts_with('A') { p } else { format!("A{}", p) }),
+            UDS_DID_HW_VERSION => self.hardware_version = version(&data),
+            UDS_DID_SW_VERSION => self.software_version = version(&data),
+            UDS_DID_BOOT_VERSION => self.boot_software_version = version(&data),
+            UDS_DID_SUPPLIER if data.len() >= 2 => {
+                self.supplier = Some(SupplierRegistry::builtin().lookup(SupplierCode::Uds(u16::from_be_bytes([data[0], data[1]]))));
+            },
+            UDS_DID_SYSTEM_SUPPLIER => self.system_supplier = ascii(&data),
+            UDS_DID_PRODUCTION_DATE => self.production_date = date(&data),
+            UDS_DID_SERIAL_NUMBER => self.serial_number = ascii(&data),
+            UDS_DID_VIN => self.vin = ascii(&data),
+            _ => {}
+        }
+        self.raw.insert(did, data);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::{GenericProtocolServer, DTC};
+
+    struct MockEcu;
+
+    impl GenericProtocolServer for MockEcu {
+        fn send_command_with_response(&mut self, send: &[u8]) -> ProtocolResult<Vec<u8>> {
+            match send {
+                [0x1A, 0x86] => Ok(vec![0x5A, 0x86, 0x21, 0x14, 0x46, 0x05, 0x40, 0x03, 0x48, 0x04, 0x12, 0x08, 0x02, 0x17, 0x00, 0x04, 0x02, 0x15]),
+                [0x1A, 0x88] => Ok([&[0x5A, 0x88][..], b"WDB2110161A123456"].concat()),
+                [0x22, 0xF1, 0x11] => Ok([&[0x62, 0xF1, 0x11][..], b"2129002104"].concat()),
+                [0x22, 0xF1, 0x50] => Ok(vec![0x62, 0xF1, 0x50, 0x09, 0x43, 0x00]),
+                [0x22, 0xF1, 0x54] => Ok(vec![0x62, 0xF1, 0x54, 0x00, 0x08]),
+                _ => Err(ProtocolError::ECUError { code: 0x31, desc: String::new() })
+            }
+        }
+
+        fn send_command(&mut self, _send: &[u8]) -> ProtocolResult<()> {
+            Ok(())
+        }
+
+        fn read_dtcs(&mut self) -> ProtocolResult<Vec<DTC>> {
+            Ok(Vec::new())
+        }
+    }
+
+    impl Kwp2000Server for MockEcu {}
+    impl UdsServer for MockEcu {}
+
+    #[test]
+    pub fn test_identification() {
+        let kwp = EcuIdentification::read_kwp(&mut MockEcu).unwrap();
+        assert_eq!(Some("A2114460540".into()), kwp.part_number);
+        assert_eq!("03/48", kwp.hardware_version.unwrap().to_string());
+        assert_eq!("04/12", kwp.software_version.unwrap().to_string());
+        assert_eq!("Siemens", kwp.supplier.as_ref().unwrap().display_name());
+        assert_eq!(Some(0x0217), kwp.diag_version);
+        assert_eq!("15.02.2004", kwp.production_date.unwrap().to_string());
+        assert_eq!(Some("WDB2110161A123456".into()), kwp.vin);
+        assert!(!kwp.raw.contains_key(&(KWP_ID_DAIMLER_MMC as u16)));
+
+        let uds = EcuIdentification::read_uds(&mut MockEcu).unwrap();
+        assert_eq!(Some("A2129002104".into()), uds.part_number);
+        assert_eq!("09/43 00", uds.hardware_version.unwrap().to_string());
+        assert_eq!(uds.supplier.unwrap().id(), kwp.supplier.unwrap().id());
+        assert_eq!(3, uds.raw.len());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod ecu_suppliers;
 pub mod protocols;
+pub mod identification;
+pub mod quick_test;
+pub mod live_data;
+pub mod actuation;
+pub mod routine;
+pub mod coding;
+pub mod memory;
+pub mod address_book;
 
 
 #[cfg(test)]
@@ -0,0 +1,302 @@
+//! Live data streaming
+//!
+//! Reading values one identifier at a time is too slow for graphs, so the values are first combined into a
+//! dynamically defined identifier (Service 0x2C), which the ECU is then asked to transmit periodically
+//! (UDS 0x2A or KWP2000 periodic ReadDataByLocalIdentifier). If the ECU does not support this, the stream falls
+//! back to polling the dynamic identifier, or the source identifiers themselves.
+//!
+//! Samples are delivered through [std::sync::mpsc] channels, which any number of subscribers can listen to.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+use logger::Logger;
+
+use crate::protocols::detect::EcuClient;
+use crate::protocols::kwp2000::KwpService;
+use crate::protocols::{DiagProtocol, DynamicIdentifierItem, PeriodicRate, ProtocolResult, RequestEngine};
+
+/// How long the stream thread waits for a periodic message before checking if it should stop (In ms)
+const RECEIVE_SLICE_MS: u128 = 20;
+
+/// A value to stream, which is part of a record of the ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveValue {
+    pub name: String,
+    /// The identifier of the record (KWP2000 local identifier or UDS data identifier)
+    pub source_id: u16,
+    /// Position of the value within the record (Starting at 1)
+    pub position: u8,
+    /// Length of the value (In bytes)
+    pub size: u8
+}
+
+impl LiveValue {
+    /// Extracts the value from its source record
+    fn extract<'a>(&self, record: &'a [u8]) -> Option<&'a [u8]> {
+        let start = (self.position as usize).checked_sub(1)?;
+        record.get(start..start + self.size as usize)
+    }
+}
+
+/// How the stream reads values from the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StreamMethod {
+    /// The ECU transmits the dynamic identifier periodically
+    Periodic,
+    /// The dynamic identifier is polled
+    PolledDynamic,
+    /// Each source identifier is polled
+    PolledSources
+}
+
+/// Live data stream settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StreamConfig {
+    /// The identifier to define dynamically. None uses 0xF0 (KWP2000) or 0xF200 (UDS)
+    pub dynamic_id: Option<u16>,
+    /// Rate to ask the ECU to transmit periodic data at
+    pub rate: PeriodicRate,
+    /// Time between reads when polling (In ms)
+    pub poll_interval_ms: u64,
+    /// Use a dynamically defined identifier if the ECU supports it
+    pub use_dynamic_id: bool,
+    /// Use periodic transmission if the ECU supports it
+    pub use_periodic: bool
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            dynamic_id: None,
+            rate: PeriodicRate::Fast,
+            poll_interval_ms: 100,
+            use_dynamic_id: true,
+            use_periodic: true
+        }
+    }
+}
+
+/// Values read at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveSample {
+    /// Time the values were received
+    pub timestamp: Instant,
+    /// The raw value of each [LiveValue], in the order they were given to the stream
+    pub values: Vec<Vec<u8>>
+}
+
+type Subscribers = Arc<Mutex<Vec<Sender<LiveSample>>>>;
+
+/// A running live data stream. The stream stops when it is dropped
+#[derive(Debug)]
+pub struct LiveDataStream<A: AdapterHardware + 'static> {
+    engine: RequestEngine<A>,
+    values: Vec<LiveValue>,
+    method: StreamMethod,
+    dynamic_id: u16,
+    subscribers: Subscribers,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl<A: AdapterHardware + 'static> LiveDataStream<A> {
+    /// Starts streaming values from an ECU. The best method the ECU supports is picked
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU
+    /// * values - The values to stream
+    /// * config - Stream settings
+    pub fn start(engine: RequestEngine<A>, values: Vec<LiveValue>, config: StreamConfig) -> ProtocolResult<Self> {
+        let protocol = engine.protocol();
+        let dynamic_id = config.dynamic_id.unwrap_or(match protocol {
+            DiagProtocol::KWP2000 => 0xF0,
+            DiagProtocol::UDS => 0xF200
+        });
+        let logger = Logger::new("Live data");
+        let items: Vec<DynamicIdentifierItem> = values.iter()
+            .map(|v| DynamicIdentifierItem { source_id: v.source_id, position: v.position, size: v.size })
+            .collect();
+
+        let mut client = EcuClient::from_engine(engine.clone());
+        let mut method = StreamMethod::PolledSources;
+        if config.use_dynamic_id {
+            // Clear any definition left over from a previous stream first
+            let _ = client.clear_dynamic_id(dynamic_id);
+            match client.define_dynamic_id(dynamic_id, &items) {
+                Ok(()) => method = StreamMethod::PolledDynamic,
+                Err(e) => logger.log_warn(format!("Could not define dynamic identifier 0x{:04X}, polling instead: {:?}", dynamic_id, e))
+            }
+        }
+        if method == StreamMethod::PolledDynamic && config.use_periodic {
+            match client.start_periodic(dynamic_id, config.rate) {
+                Ok(()) => method = StreamMethod::Periodic,
+                Err(e) => logger.log_warn(format!("ECU does not support periodic transmission, polling instead: {:?}", e))
+            }
+        }
+
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let (mut client, values, subscribers, running) = (client.clone(), values.clone(), subscribers.clone(), running.clone());
+            std::thread::spawn(move || {
+                let interval = Duration::from_millis(config.poll_interval_ms);
+                while running.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+                    let sample = match method {
+                        StreamMethod::Periodic => receive_periodic(client.engine(), dynamic_id, &values),
+                        StreamMethod::PolledDynamic => client.read_data_record(dynamic_id).ok().and_then(|r| split_dynamic(&r, &values)),
+                        StreamMethod::PolledSources => poll_sources(&mut client, &values)
+                    };
+                    if let Some(values) = sample {
+                        let sample = LiveSample { timestamp: Instant::now(), values };
+                        subscribers.lock().unwrap().retain(|s| s.send(sample.clone()).is_ok());
+                    }
+                    if method != StreamMethod::Periodic {
+                        std::thread::sleep(interval.saturating_sub(started.elapsed()));
+                    }
+                }
+            })
+        };
+
+        Ok(Self { engine, values, method, dynamic_id, subscribers, running, thread: Some(thread) })
+    }
+
+    /// Returns a receiver which gets every sample from now on
+    pub fn subscribe(&self) -> Receiver<LiveSample> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// The method used to read values from the ECU
+    pub fn method(&self) -> StreamMethod {
+        self.method
+    }
+
+    /// The values being streamed
+    pub fn values(&self) -> &[LiveValue] {
+        &self.values
+    }
+
+    /// Stops the stream, and removes the dynamic identifier from the ECU
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+            let mut client = EcuClient::from_engine(self.engine.clone());
+            if self.method == StreamMethod::Periodic {
+                let _ = client.stop_periodic(self.dynamic_id);
+            }
+            if self.method != StreamMethod::PolledSources {
+                let _ = client.clear_dynamic_id(self.dynamic_id);
+            }
+        }
+    }
+}
+
+impl<A: AdapterHardware + 'static> Drop for LiveDataStream<A> {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+/// Splits the record of the dynamic identifier into its values
+fn split_dynamic(record: &[u8], values: &[LiveValue]) -> Option<Vec<Vec<u8>>> {
+    let mut offset = 0;
+    values.iter().map(|v| {
+        let value = record.get(offset..offset + v.size as usize)?.to_vec();
+        offset += v.size as usize;
+        Some(value)
+    }).collect()
+}
+
+/// Waits for the next periodic transmission of the dynamic identifier
+fn receive_periodic<A: AdapterHardware>(engine: &RequestEngine<A>, id: u16, values: &[LiveValue]) -> Option<Vec<Vec<u8>>> {
+    let msg = match engine.try_receive_unsolicited(RECEIVE_SLICE_MS)? {
+        Ok(msg) => msg,
+        Err(_) => return None
+    };
+    let record = match (engine.protocol(), msg.as_slice()) {
+        (DiagProtocol::KWP2000, [sid, lid, record @ ..]) if *sid == KwpService::ReadDataByLocalIdentifier as u8 + 0x40 && *lid == id as u8 => record,
+        // UDS periodic data is sent without a service ID, though some ECUs include it
+        (DiagProtocol::UDS, [pid, record @ ..]) if *pid == id as u8 => record,
+        (DiagProtocol::UDS, [0x6A, pid, record @ ..]) if *pid == id as u8 => record,
+        _ => return None
+    };
+    split_dynamic(record, values)
+}
+
+/// Reads each source identifier once, and extracts the values from them
+fn poll_sources<A: AdapterHardware>(client: &mut EcuClient<A>, values: &[LiveValue]) -> Option<Vec<Vec<u8>>> {
+    let mut records: Vec<(u16, Vec<u8>)> = Vec::new();
+    values.iter().map(|v| {
+        if !records.iter().any(|(id, _)| *id == v.source_id) {
+            records.push((v.source_id, client.read_data_record(v.source_id).ok()?));
+        }
+        let (_, record) = records.iter().find(|(id, _)| *id == v.source_id)?;
+        v.extract(record).map(|d| d.to_vec())
+    }).collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_engine;
+    use hardware::AdapterChannel;
+
+    fn values() -> Vec<LiveValue> {
+        vec![
+            LiveValue { name: "Engine speed".into(), source_id: 0xF40C, position: 1, size: 2 },
+            LiveValue { name: "Coolant temperature".into(), source_id: 0xF405, position: 1, size: 1 }
+        ]
+    }
+
+    #[test]
+    pub fn test_periodic_stream() {
+        let (sim, engine) = sim_engine(DiagProtocol::UDS, |req| {
+            match req {
+                [0x2C, 0x03, 0xF2, 0x00] => vec![0x6C, 0x03, 0xF2, 0x00],
+                [0x2C, 0x01, 0xF2, 0x00, 0xF4, 0x0C, 0x01, 0x02, 0xF4, 0x05, 0x01, 0x01] => vec![0x6C, 0x01, 0xF2, 0x00],
+                [0x2A, 0x03, 0x00] | [0x2A, 0x04, 0x00] => vec![0x6A],
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+        let mut stream = LiveDataStream::start(engine, values(), StreamConfig::default()).unwrap();
+        assert_eq!(StreamMethod::Periodic, stream.method());
+
+        let samples = stream.subscribe();
+        for rpm in [0x1AF8u16, 0x1B00] {
+            let [hi, lo] = rpm.to_be_bytes();
+            sim.inject_frame(AdapterChannel::IsoTp, 0x04C4, &[0x00, hi, lo, 0x82]);
+        }
+        let first = samples.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(vec![vec![0x1A, 0xF8], vec![0x82]], first.values);
+        let second = samples.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(second.timestamp >= first.timestamp);
+        stream.stop();
+    }
+
+    #[test]
+    pub fn test_polling_fallback() {
+        let (_, engine) = sim_engine(DiagProtocol::KWP2000, |req| {
+            match req {
+                [0x21, 0x0C] => vec![0x61, 0x0C, 0x1A, 0xF8, 0x00],
+                [0x21, 0x05] => vec![0x61, 0x05, 0x82],
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+        let values = vec![
+            LiveValue { name: "Engine speed".into(), source_id: 0x0C, position: 1, size: 2 },
+            LiveValue { name: "Coolant temperature".into(), source_id: 0x05, position: 1, size: 1 }
+        ];
+        let stream = LiveDataStream::start(engine, values, StreamConfig { poll_interval_ms: 10, ..Default::default() }).unwrap();
+        assert_eq!(StreamMethod::PolledSources, stream.method());
+        let sample = stream.subscribe().recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(vec![vec![0x1A, 0xF8], vec![0x82]], sample.values);
+    }
+}
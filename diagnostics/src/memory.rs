@@ -0,0 +1,228 @@
+//! Memory readout (EEPROM and flash backups)
+//!
+//! Memory can be read with ReadMemoryByAddress, which the tester splits into blocks of a fixed size, or
+//! with RequestUpload, where the ECU sends the area in blocks of the length it negotiated. Either way,
+//! the result can be written to a binary image, for example to back up the EEPROM of an ECU before it is replaced.
+//!
+//! Most ECUs only allow memory to be read after security access has been granted.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+use logger::Logger;
+
+use crate::protocols::detect::EcuClient;
+use crate::protocols::{DiagProtocol, MemoryAddressFormat, ProtocolError, ProtocolResult, RequestEngine};
+
+/// How memory is read from the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadoutMethod {
+    /// ReadMemoryByAddress, reading at most `block_size` bytes per request
+    ReadMemoryByAddress { block_size: u32 },
+    /// RequestUpload followed by TransferData. The block length is negotiated with the ECU
+    Upload {
+        /// The data format identifier. 0x00 requests the data unencrypted and uncompressed
+        data_format: u8
+    }
+}
+
+/// Memory readout settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReadoutConfig {
+    pub method: ReadoutMethod,
+    /// How addresses and sizes are encoded. KWP2000 uploads always use a 3 byte address and size
+    pub format: MemoryAddressFormat
+}
+
+impl ReadoutConfig {
+    /// Default settings for a protocol
+    pub fn for_protocol(protocol: DiagProtocol) -> Self {
+        match protocol {
+            DiagProtocol::KWP2000 => Self {
+                method: ReadoutMethod::ReadMemoryByAddress { block_size: 0xFE },
+                format: MemoryAddressFormat::new(3, 1)
+            },
+            DiagProtocol::UDS => Self {
+                method: ReadoutMethod::ReadMemoryByAddress { block_size: 0x400 },
+                format: MemoryAddressFormat::new(4, 2)
+            }
+        }
+    }
+}
+
+/// Progress of a readout
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReadoutProgress {
+    /// Bytes read so far
+    pub bytes_read: u32,
+    /// Total bytes to read
+    pub total: u32,
+    /// Time since the readout was started
+    pub elapsed: Duration
+}
+
+impl ReadoutProgress {
+    /// Progress in percent
+    pub fn percent(&self) -> u8 {
+        if self.total == 0 {
+            100
+        } else {
+            (self.bytes_read as u64 * 100 / self.total as u64) as u8
+        }
+    }
+}
+
+/// Reads areas of an ECU's memory
+#[derive(Debug)]
+pub struct MemoryReader<A: AdapterHardware> {
+    engine: RequestEngine<A>,
+    config: ReadoutConfig,
+    logger: Logger
+}
+
+impl<A: AdapterHardware> MemoryReader<A> {
+    /// Creates a memory reader
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU
+    /// * config - Readout settings
+    pub fn new(engine: RequestEngine<A>, config: ReadoutConfig) -> Self {
+        Self { engine, config, logger: Logger::new("Memory") }
+    }
+
+    /// Reads an area of memory
+    ///
+    /// ## Arguments
+    /// * address - The start address of the area
+    /// * size - The number of bytes to read
+    /// * on_progress - Called after every block
+    pub fn read<F: FnMut(ReadoutProgress)>(&self, address: u32, size: u32, mut on_progress: F) -> ProtocolResult<Vec<u8>> {
+        // The area may end at the last byte of the address space, but must not go past it
+        if address.checked_add(size.saturating_sub(1)).is_none() {
+            return Err(ProtocolError::ServerError(format!("{} bytes from 0x{:08X} go past the end of the address space", size, address)));
+        }
+        let start = Instant::now();
+        let mut progress = |bytes_read: usize| on_progress(ReadoutProgress { bytes_read: bytes_read as u32, total: size, elapsed: start.elapsed() });
+        match self.config.method {
+            ReadoutMethod::ReadMemoryByAddress { block_size } => self.read_by_address(address, size, block_size, &mut progress),
+            ReadoutMethod::Upload { data_format } => self.upload(address, size, data_format, &mut progress)
+        }
+    }
+
+    /// Reads an area of memory and writes it to a binary image
+    ///
+    /// ## Arguments
+    /// * address - The start address of the area
+    /// * size - The number of bytes to read
+    /// * path - The image file to create. Nothing is written if the readout fails
+    /// * on_progress - Called after every block
+    pub fn read_to_file<P: AsRef<Path>, F: FnMut(ReadoutProgress)>(&self, address: u32, size: u32, path: P, on_progress: F) -> ProtocolResult<Vec<u8>> {
+        let data = self.read(address, size, on_progress)?;
+        fs::write(path.as_ref(), &data)
+            .map_err(|e| ProtocolError::ServerError(format!("Could not write image {}: {}", path.as_ref().display(), e)))?;
+        Ok(data)
+    }
+
+    fn read_by_address(&self, address: u32, size: u32, block_size: u32, progress: &mut dyn FnMut(usize)) -> ProtocolResult<Vec<u8>> {
+        let block_size = block_size.min(self.config.format.max_size());
+        if block_size == 0 {
+            return Err(ProtocolError::ServerError("Block size must not be 0".into()));
+        }
+        let mut client = EcuClient::from_engine(self.engine.clone());
+        let mut data = Vec::with_capacity(size as usize);
+        while (data.len() as u32) < size {
+            let block_address = address + data.len() as u32;
+            let block_len = block_size.min(size - data.len() as u32);
+            let block = client.read_memory_by_address(self.config.format, block_address, block_len)?;
+            if block.len() != block_len as usize {
+                return Err(ProtocolError::ServerError(format!("ECU returned {} bytes at 0x{:08X}, but {} were requested", block.len(), block_address, block_len)));
+            }
+            data.extend_from_slice(&block);
+            progress(data.len());
+        }
+        Ok(data)
+    }
+
+    fn upload(&self, address: u32, size: u32, data_format: u8, progress: &mut dyn FnMut(usize)) -> ProtocolResult<Vec<u8>> {
+        let mut client = EcuClient::from_engine(self.engine.clone());
+        let max_block = client.request_upload(self.config.format, data_format, address, size)?;
+        self.logger.log_debug(format!("Uploading {} bytes from 0x{:08X} in blocks of up to {} bytes", size, address, max_block));
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut sequence = 1u8;
+        let transfer = loop {
+            if data.len() as u32 >= size {
+                break Ok(());
+            }
+            match client.transfer_data(sequence) {
+                Ok(block) if block.is_empty() || (max_block != 0 && block.len() as u32 > max_block) => {
+                    break Err(ProtocolError::ServerError(format!("ECU sent a block of {} bytes, but negotiated at most {}", block.len(), max_block)));
+                },
+                Ok(block) => {
+                    data.extend_from_slice(&block);
+                    sequence = sequence.wrapping_add(1);
+                    progress(data.len().min(size as usize));
+                },
+                Err(e) => break Err(e)
+            }
+        };
+        // The transfer is always ended, so the ECU does not stay in upload mode after an error
+        let exit = client.request_transfer_exit();
+        transfer?;
+        exit?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_engine;
+
+    fn eeprom() -> Vec<u8> {
+        (0..0x300u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn sim_ecu(req: &[u8]) -> Vec<u8> {
+        let mem = eeprom();
+        match req {
+            [0x23, 0x24, a, b, c, d, s1, s2] => {
+                let addr = u32::from_be_bytes([*a, *b, *c, *d]) as usize - 0x1000;
+                let size = u16::from_be_bytes([*s1, *s2]) as usize;
+                [&[0x63][..], &mem[addr..addr + size]].concat()
+            },
+            // Upload of 0x300 bytes from 0x1000 in blocks of 0x100 bytes
+            [0x35, 0x00, 0x24, 0x00, 0x00, 0x10, 0x00, 0x03, 0x00] => vec![0x75, 0x20, 0x01, 0x02],
+            [0x36, seq @ 1..=3] => [&[0x76, *seq][..], &mem[(*seq as usize - 1) * 0x100..*seq as usize * 0x100]].concat(),
+            [0x37] => vec![0x77],
+            _ => vec![0x7F, req[0], 0x31]
+        }
+    }
+
+    #[test]
+    pub fn test_memory_readout() {
+        let (_, engine) = sim_engine(DiagProtocol::UDS, sim_ecu);
+        let format = MemoryAddressFormat::new(4, 2);
+
+        let mut reports = Vec::new();
+        let reader = MemoryReader::new(engine.clone(), ReadoutConfig { method: ReadoutMethod::ReadMemoryByAddress { block_size: 0x100 }, format });
+        assert_eq!(eeprom()[0x10..0x210], reader.read(0x1010, 0x200, |p| reports.push(p.bytes_read)).unwrap()[..]);
+        assert_eq!(vec![0x100, 0x200], reports);
+
+        let path = std::env::temp_dir().join(format!("eeprom_{}.bin", std::process::id()));
+        let mut percent = 0;
+        let reader = MemoryReader::new(engine, ReadoutConfig { method: ReadoutMethod::Upload { data_format: 0x00 }, format });
+        reader.read_to_file(0x1000, 0x300, &path, |p| percent = p.percent()).unwrap();
+        assert_eq!(100, percent);
+        assert_eq!(eeprom(), fs::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // An address which does not fit in the address format is refused before anything is sent
+        let mut client = EcuClient::from_engine(reader.engine.clone());
+        assert!(client.read_memory_by_address(MemoryAddressFormat::new(2, 1), 0x1_0000, 1).is_err());
+        assert!(reader.read(0xFFFF_FF00, 0x101, |_| ()).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Shared ISO-TP channel for diagnostic communication
+//!
+//! A single [DiagChannel] can be used by any number of ECU clients at once (Including from different threads).
+//! Every ECU registers the CAN ID it responds with, and messages received from the adapter are routed to
+//! a mailbox per response ID, so that clients never receive each others responses. Security access lockouts
+//! are also kept on the channel, so they apply to every client of an ECU.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hardware::data_structures::{HwDataFrame, HwIsoTpFrame};
+use hardware::{AdapterBuffer, AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError};
+
+use super::security::SecurityLevelState;
+use super::ProtocolResult;
+
+/// Default bitrate of the Mercedes diagnostic CAN bus
+pub const DEFAULT_DIAG_BAUD: u32 = 500_000;
+
+/// Maximum time the channel is locked for while polling the adapter for messages
+const POLL_SLICE_MS: u128 = 10;
+
+#[derive(Debug)]
+struct ChannelState<A: AdapterHardware> {
+    adapter: A,
+    channel_id: u32,
+    baud: u32,
+    flags: Vec<ChannelFlags>,
+    /// Flow control filter of each registered ECU, keyed by response ID
+    filters: HashMap<u32, u32>,
+    /// Received messages which have not been picked up yet, keyed by response ID
+    mailboxes: HashMap<u32, VecDeque<Vec<u8>>>,
+    /// Security access attempts of each ECU and access level, keyed by request ID and level
+    security: HashMap<(u32, u8), SecurityLevelState>
+}
+
+impl<A: AdapterHardware> ChannelState<A> {
+    fn poll(&mut self, timeout_ms: u128) -> ProtocolResult<()> {
+        for msg in self.adapter.read_data::<HwIsoTpFrame>(32, timeout_ms)? {
+            // Messages from IDs which are no longer registered are dropped
+            if let Some(mailbox) = self.mailboxes.get_mut(&msg.get_id()) {
+                mailbox.push_back(msg.get_data().to_vec());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ISO-TP channel which can be shared between ECU clients. Cloning the channel is cheap,
+/// and all clones refer to the same adapter channel.
+#[derive(Debug, Clone)]
+pub struct DiagChannel<A: AdapterHardware> {
+    state: Arc<Mutex<ChannelState<A>>>
+}
+
+impl<A: AdapterHardware> DiagChannel<A> {
+    /// Opens an ISO-TP channel on the adapter. No messages are received until an ECU is registered
+    ///
+    /// ## Arguments
+    /// * adapter - The adapter to communicate with. It must already be open
+    /// * baud - The bitrate of the diagnostic bus (In bps)
+    /// * flags - Channel flags, such as [ChannelFlags::CAN_USE_29BIT_ADDR]
+    pub fn open(mut adapter: A, baud: u32, flags: &[ChannelFlags]) -> ProtocolResult<Self> {
+        let channel_id = adapter.open_channel(AdapterChannel::IsoTp)?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(ChannelState {
+                adapter,
+                channel_id,
+                baud,
+                flags: flags.to_vec(),
+                filters: HashMap::new(),
+                mailboxes: HashMap::new(),
+                security: HashMap::new()
+            }))
+        })
+    }
+
+    /// Sets up flow control for an ECU, so its responses can be received. Registering an ECU
+    /// which is already registered does nothing.
+    ///
+    /// ## Arguments
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * rx_id - The CAN ID the ECU responds with
+    pub fn register_ecu(&self, tx_id: u32, rx_id: u32) -> ProtocolResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.filters.contains_key(&rx_id) {
+            return Ok(());
+        }
+        let ChannelState { adapter, channel_id, baud, flags, .. } = &mut *state;
+        let filter = AdapterFilter::IsoTP { mask: 0xFFFF_FFFF, id: rx_id, fc: tx_id };
+        let filter_id = adapter.add_channel_filter(*channel_id, filter, *baud, flags)?;
+        state.filters.insert(rx_id, filter_id);
+        state.mailboxes.insert(rx_id, VecDeque::new());
+        Ok(())
+    }
+
+    /// Sets up flow control for several ECUs at once. If any ECU cannot be registered, the ECUs
+    /// registered by this call are removed again
+    ///
+    /// ## Arguments
+    /// * ecus - The request and response CAN ID of each ECU
+    ///
+    /// ## Returns
+    /// The response IDs which were not registered before
+    pub fn register_ecus(&self, ecus: &[(u32, u32)]) -> ProtocolResult<Vec<u32>> {
+        let mut added = Vec::new();
+        for (tx_id, rx_id) in ecus {
+            if self.is_registered(*rx_id) {
+                continue;
+            }
+            if let Err(e) = self.register_ecu(*tx_id, *rx_id) {
+                for rx_id in added {
+                    let _ = self.unregister_ecu(rx_id);
+                }
+                return Err(e);
+            }
+            added.push(*rx_id);
+        }
+        Ok(added)
+    }
+
+    /// Returns true if an ECU is registered with its response ID
+    pub fn is_registered(&self, rx_id: u32) -> bool {
+        self.state.lock().unwrap().filters.contains_key(&rx_id)
+    }
+
+    /// Removes the flow control filter of an ECU. Any responses not yet read are discarded
+    pub fn unregister_ecu(&self, rx_id: u32) -> ProtocolResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.mailboxes.remove(&rx_id);
+        if let Some(filter_id) = state.filters.remove(&rx_id) {
+            let channel_id = state.channel_id;
+            state.adapter.del_channel_filter(channel_id, filter_id)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a message to an ECU
+    ///
+    /// ## Arguments
+    /// * tx_id - The CAN ID to send the message with
+    /// * data - The message payload
+    pub fn send(&self, tx_id: u32, data: &[u8]) -> ProtocolResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let ext_addr = state.flags.iter().any(|f| matches!(f, ChannelFlags::ISOTP_USE_EXT_ADDR));
+        state.adapter.write_data(&[HwIsoTpFrame::new(tx_id, ext_addr, data)], 0)?;
+        Ok(())
+    }
+
+    /// Waits for the next message from an ECU
+    ///
+    /// ## Arguments
+    /// * rx_id - The CAN ID of the ECU to receive from. It must be registered with [DiagChannel::register_ecu]
+    /// * timeout_ms - The maximum time to wait for a message
+    ///
+    /// ## Returns
+    /// The message payload, or [HardwareError::Timeout] if nothing was received in time
+    pub fn receive(&self, rx_id: u32, timeout_ms: u128) -> ProtocolResult<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                match state.mailboxes.get_mut(&rx_id) {
+                    Some(mailbox) => if let Some(msg) = mailbox.pop_front() {
+                        return Ok(msg);
+                    },
+                    None => return Err(HardwareError::Other(format!("ECU 0x{:04X} is not registered", rx_id)).into())
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now()).as_millis();
+                if remaining == 0 {
+                    return Err(HardwareError::Timeout.into());
+                }
+                state.poll(remaining.min(POLL_SLICE_MS))?;
+            }
+            // Give other clients the chance to lock the channel between polls
+            std::thread::yield_now();
+        }
+    }
+
+    /// Waits for the next message from any of several ECUs. Used when a request is sent to multiple ECUs at once
+    ///
+    /// ## Arguments
+    /// * rx_ids - The CAN IDs of the ECUs to receive from. They must be registered with [DiagChannel::register_ecu]
+    /// * timeout_ms - The maximum time to wait for a message
+    ///
+    /// ## Returns
+    /// The CAN ID and payload of the message, or [HardwareError::Timeout] if nothing was received in time
+    pub fn receive_any(&self, rx_ids: &[u32], timeout_ms: u128) -> ProtocolResult<(u32, Vec<u8>)> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                for rx_id in rx_ids {
+                    match state.mailboxes.get_mut(rx_id) {
+                        Some(mailbox) => if let Some(msg) = mailbox.pop_front() {
+                            return Ok((*rx_id, msg));
+                        },
+                        None => return Err(HardwareError::Other(format!("ECU 0x{:04X} is not registered", rx_id)).into())
+                    }
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now()).as_millis();
+                if remaining == 0 {
+                    return Err(HardwareError::Timeout.into());
+                }
+                state.poll(remaining.min(POLL_SLICE_MS))?;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Discards all messages received from an ECU which have not been read yet
+    pub fn clear(&self, rx_id: u32) -> ProtocolResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.poll(0)?;
+        if let Some(mailbox) = state.mailboxes.get_mut(&rx_id) {
+            mailbox.clear();
+        }
+        Ok(())
+    }
+
+    /// Gives access to the security access attempts of an ECU's access level
+    ///
+    /// ## Arguments
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * level - The access level (The seed request sub-function)
+    pub(crate) fn with_security_state<R, F: FnOnce(&mut SecurityLevelState) -> R>(&self, tx_id: u32, level: u8, f: F) -> R {
+        f(self.state.lock().unwrap().security.entry((tx_id, level)).or_default())
+    }
+
+    /// Longest payload which fits in a single ISO-TP frame on the channel
+    pub fn max_single_frame_len(&self) -> usize {
+        let ext_addr = self.state.lock().unwrap().flags.iter().any(|f| matches!(f, ChannelFlags::ISOTP_USE_EXT_ADDR));
+        if ext_addr { 6 } else { 7 }
+    }
+
+    /// Returns the bitrate of the channel
+    pub fn baud(&self) -> u32 {
+        self.state.lock().unwrap().baud
+    }
+
+    /// Closes the ISO-TP channel. Any clients still using the channel will fail to communicate
+    pub fn close(&self) -> ProtocolResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let channel_id = state.channel_id;
+        state.filters.clear();
+        state.mailboxes.clear();
+        state.adapter.clear_channel_buffer(channel_id, AdapterBuffer::Both)?;
+        state.adapter.close_channel(channel_id)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+//! KWP2000 / UDS protocol detection
+//!
+//! Vehicles from the transition between DAS and Xentry mix KWP2000 and UDS ECUs on the same bus, and the same
+//! ECU can speak either protocol depending on its software. The protocol is detected with requests which do not
+//! change the state of the ECU first: The Mercedes KWP2000 identification, which UDS does not have, then
+//! TesterPresent, whose sub-function 0x00 only exists in UDS. Only if neither tells the protocols apart is the
+//! ECU asked for a default session, which only exists with ID 0x01 in UDS and with ID 0x81 in KWP2000. This
+//! ends any session another client has open with the ECU.
+
+use hardware::AdapterHardware;
+
+use super::kwp2000::{Kwp2000Client, Kwp2000Server, KwpIoControlParameter, KwpSession};
+use super::nrc::{NRC_SERVICE_NOT_SUPPORTED, NRC_SUB_FUNCTION_NOT_SUPPORTED};
+use super::uds::{UdsClient, UdsIoControlParameter, UdsRoutineControlType, UdsServer, UdsSession};
+use super::{
+    DiagChannel, DiagProtocol, DynamicIdentifierItem, EcuTiming, GenericProtocolServer, IoControlAction, MemoryAddressFormat, PeriodicRate,
+    ProtocolError, ProtocolResult, RequestEngine, DTC
+};
+use crate::identification::KWP_ID_DAIMLER;
+
+/// Client for an ECU speaking either protocol. Like the [RequestEngine] it is created from, it does not
+/// change the session of the ECU
+#[derive(Debug, Clone)]
+pub enum EcuClient<A: AdapterHardware> {
+    Kwp2000(Kwp2000Client<A>),
+    Uds(UdsClient<A>)
+}
+
+impl<A: AdapterHardware> EcuClient<A> {
+    /// Creates a client for the protocol of the request engine
+    pub fn from_engine(engine: RequestEngine<A>) -> Self {
+        match engine.protocol() {
+            DiagProtocol::KWP2000 => EcuClient::Kwp2000(Kwp2000Client::from_engine(engine)),
+            DiagProtocol::UDS => EcuClient::Uds(UdsClient::from_engine(engine))
+        }
+    }
+
+    /// Detects the protocol of an ECU, and creates a client for it
+    ///
+    /// ## Arguments
+    /// * channel - The channel the ECU is connected to
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * rx_id - The CAN ID the ECU responds with
+    /// * timeout_ms - Time to wait for each response whilst detecting
+    pub fn connect(channel: &DiagChannel<A>, tx_id: u32, rx_id: u32, timeout_ms: u128) -> ProtocolResult<Self> {
+        let protocol = detect_protocol(channel, tx_id, rx_id, timeout_ms)?;
+        Ok(Self::from_engine(RequestEngine::new(channel.clone(), tx_id, rx_id, protocol)?))
+    }
+
+    pub fn protocol(&self) -> DiagProtocol {
+        self.engine().protocol()
+    }
+
+    pub fn engine(&self) -> &RequestEngine<A> {
+        match self {
+            EcuClient::Kwp2000(c) => c.engine(),
+            EcuClient::Uds(c) => c.engine()
+        }
+    }
+
+    pub fn engine_mut(&mut self) -> &mut RequestEngine<A> {
+        match self {
+            EcuClient::Kwp2000(c) => c.engine_mut(),
+            EcuClient::Uds(c) => c.engine_mut()
+        }
+    }
+
+    /// Requests a security access seed from the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level to request the seed for. This is always an odd number below 0x7F
+    pub fn security_access_request_seed(&mut self, level: u8) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.security_access_request_seed(level),
+            EcuClient::Uds(c) => c.security_access_request_seed(level)
+        }
+    }
+
+    /// Sends the key computed from a seed to the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level the seed was requested for. The key is sent with level + 1
+    /// * key - The key
+    pub fn security_access_send_key(&mut self, level: u8, key: &[u8]) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.security_access_send_key(level, key),
+            EcuClient::Uds(c) => c.security_access_send_key(level, key)
+        }
+    }
+
+    /// Reads a record from the ECU
+    ///
+    /// ## Arguments
+    /// * id - The local identifier (KWP2000) or data identifier (UDS) of the record
+    pub fn read_data_record(&mut self, id: u16) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.read_data_by_local_id(local_id(id)?),
+            EcuClient::Uds(c) => c.read_data_by_identifier(id)
+        }
+    }
+
+    /// Writes a record to the ECU
+    ///
+    /// ## Arguments
+    /// * id - The local identifier (KWP2000) or data identifier (UDS) of the record
+    /// * data - The new record
+    pub fn write_data_record(&mut self, id: u16, data: &[u8]) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.write_data_by_local_id(local_id(id)?, data),
+            EcuClient::Uds(c) => c.write_data_by_identifier(id, data)
+        }
+    }
+
+    /// Controls an output of the ECU
+    ///
+    /// ## Arguments
+    /// * id - The local identifier (KWP2000) or data identifier (UDS) of the output
+    /// * action - How to control the output
+    ///
+    /// ## Returns
+    /// The state of the output the ECU reported
+    pub fn io_control(&mut self, id: u16, action: &IoControlAction) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => {
+                let (param, state) = match action {
+                    IoControlAction::ShortTermAdjustment(state) => (KwpIoControlParameter::ShortTermAdjustment, state.as_slice()),
+                    IoControlAction::FreezeCurrentState => (KwpIoControlParameter::FreezeCurrentState, &[][..]),
+                    IoControlAction::ResetToDefault => (KwpIoControlParameter::ResetToDefault, &[][..])
+                };
+                c.io_control_by_local_id(local_id(id)?, param, state)
+            },
+            EcuClient::Uds(c) => {
+                let (param, state) = match action {
+                    IoControlAction::ShortTermAdjustment(state) => (UdsIoControlParameter::ShortTermAdjustment, state.as_slice()),
+                    IoControlAction::FreezeCurrentState => (UdsIoControlParameter::FreezeCurrentState, &[][..]),
+                    IoControlAction::ResetToDefault => (UdsIoControlParameter::ResetToDefault, &[][..])
+                };
+                c.io_control_by_identifier(id, param, state)
+            }
+        }
+    }
+
+    /// Returns control of an output to the ECU
+    ///
+    /// ## Arguments
+    /// * id - The local identifier (KWP2000) or data identifier (UDS) of the output
+    pub fn return_control(&mut self, id: u16) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.io_control_by_local_id(local_id(id)?, KwpIoControlParameter::ReturnControlToEcu, &[]).map(|_| ()),
+            EcuClient::Uds(c) => c.io_control_by_identifier(id, UdsIoControlParameter::ReturnControlToEcu, &[]).map(|_| ())
+        }
+    }
+
+    /// Starts a routine
+    ///
+    /// ## Arguments
+    /// * routine_id - The local identifier (KWP2000) or routine identifier (UDS) of the routine
+    /// * params - Parameters the routine is started with
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn start_routine(&mut self, routine_id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.start_routine_by_local_id(local_id(routine_id)?, params),
+            EcuClient::Uds(c) => c.routine_control(UdsRoutineControlType::Start, routine_id, params)
+        }
+    }
+
+    /// Stops a running routine
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn stop_routine(&mut self, routine_id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.stop_routine_by_local_id(local_id(routine_id)?, params),
+            EcuClient::Uds(c) => c.routine_control(UdsRoutineControlType::Stop, routine_id, params)
+        }
+    }
+
+    /// Requests the results of a routine
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn request_routine_results(&mut self, routine_id: u16) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.request_routine_results_by_local_id(local_id(routine_id)?),
+            EcuClient::Uds(c) => c.routine_control(UdsRoutineControlType::RequestResults, routine_id, &[])
+        }
+    }
+
+    /// Defines an identifier whose record is made up of parts of other records
+    ///
+    /// ## Arguments
+    /// * dynamic_id - The identifier to define (Usually 0xF0 - 0xF9 with KWP2000, or 0xF200 - 0xF2FF with UDS)
+    /// * items - The parts of other records to copy, in order
+    pub fn define_dynamic_id(&mut self, dynamic_id: u16, items: &[DynamicIdentifierItem]) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.dynamically_define_local_id(local_id(dynamic_id)?, items),
+            EcuClient::Uds(c) => c.dynamically_define_data_identifier(dynamic_id, items)
+        }
+    }
+
+    /// Clears the definition of a dynamically defined identifier
+    pub fn clear_dynamic_id(&mut self, dynamic_id: u16) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.clear_dynamically_defined_local_id(local_id(dynamic_id)?),
+            EcuClient::Uds(c) => c.clear_dynamically_defined_data_identifier(dynamic_id)
+        }
+    }
+
+    /// Asks the ECU to transmit a record periodically, until [EcuClient::stop_periodic] is called. The records
+    /// are received as unsolicited messages
+    ///
+    /// ## Arguments
+    /// * id - The local identifier (KWP2000) or periodic data identifier (UDS, 0xF200 - 0xF2FF) to transmit
+    /// * rate - The transmission rate
+    pub fn start_periodic(&mut self, id: u16, rate: PeriodicRate) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.start_periodic_local_id(local_id(id)?, rate).map(|_| ()),
+            EcuClient::Uds(c) => c.read_data_by_periodic_identifier(rate, &[periodic_id(id)?])
+        }
+    }
+
+    /// Stops the periodic transmission of a record
+    pub fn stop_periodic(&mut self, id: u16) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.stop_periodic_local_id(local_id(id)?),
+            EcuClient::Uds(c) => c.stop_periodic_identifiers(&[periodic_id(id)?])
+        }
+    }
+
+    /// Reads an area of the ECU's memory
+    ///
+    /// ## Arguments
+    /// * format - How the address and size are encoded
+    /// * address - The start address of the area
+    /// * size - The number of bytes to read
+    pub fn read_memory_by_address(&mut self, format: MemoryAddressFormat, address: u32, size: u32) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.read_memory_by_address(format, address, size),
+            EcuClient::Uds(c) => c.read_memory_by_address(format, address, size)
+        }
+    }
+
+    /// Asks the ECU to upload an area of its memory to the tester. The data is then read with
+    /// [EcuClient::transfer_data], and the upload is ended with [EcuClient::request_transfer_exit]
+    ///
+    /// ## Arguments
+    /// * format - How the address and size are encoded. KWP2000 always uses a 3 byte address and size
+    /// * data_format - The data format identifier. 0x00 requests the data unencrypted and uncompressed
+    /// * address - The start address of the area
+    /// * size - The number of bytes to upload
+    ///
+    /// ## Returns
+    /// The maximum number of data bytes in a block, or 0 if the ECU did not limit it
+    pub fn request_upload(&mut self, format: MemoryAddressFormat, data_format: u8, address: u32, size: u32) -> ProtocolResult<u32> {
+        // The negotiated length includes the service ID, and the block sequence counter with UDS
+        match self {
+            EcuClient::Kwp2000(c) => c.request_upload(address, data_format, size).map(|len| len.saturating_sub(1)),
+            EcuClient::Uds(c) => c.request_upload(format, data_format, address, size).map(|len| len.saturating_sub(2))
+        }
+    }
+
+    /// Transfers the next block of an upload
+    ///
+    /// ## Arguments
+    /// * sequence - The block sequence counter, starting at 1. KWP2000 does not use it
+    pub fn transfer_data(&mut self, sequence: u8) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.transfer_data(&[]),
+            EcuClient::Uds(c) => c.transfer_data(sequence, &[])
+        }
+    }
+
+    /// Ends an upload or download
+    pub fn request_transfer_exit(&mut self) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.request_transfer_exit(),
+            EcuClient::Uds(c) => c.request_transfer_exit().map(|_| ())
+        }
+    }
+}
+
+/// Converts an identifier to a KWP2000 local identifier, which is a single byte
+fn local_id(id: u16) -> ProtocolResult<u8> {
+    if id > 0xFF {
+        return Err(ProtocolError::ServerError(format!("0x{:04X} is not a KWP2000 local identifier", id)));
+    }
+    Ok(id as u8)
+}
+
+/// Converts a UDS periodic data identifier (0xF200 - 0xF2FF) to the low byte ReadDataByPeriodicIdentifier uses
+fn periodic_id(id: u16) -> ProtocolResult<u8> {
+    match id.to_be_bytes() {
+        [0xF2, low] => Ok(low),
+        _ => Err(ProtocolError::ServerError(format!("0x{:04X} is not a periodic data identifier (0xF200 - 0xF2FF)", id)))
+    }
+}
+
+impl<A: AdapterHardware> GenericProtocolServer for EcuClient<A> {
+    fn send_command_with_response(&mut self, send: &[u8]) -> ProtocolResult<Vec<u8>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.send_command_with_response(send),
+            EcuClient::Uds(c) => c.send_command_with_response(send)
+        }
+    }
+
+    fn send_command(&mut self, send: &[u8]) -> ProtocolResult<()> {
+        match self {
+            EcuClient::Kwp2000(c) => c.send_command(send),
+            EcuClient::Uds(c) => c.send_command(send)
+        }
+    }
+
+    fn read_dtcs(&mut self) -> ProtocolResult<Vec<DTC>> {
+        match self {
+            EcuClient::Kwp2000(c) => c.read_dtcs(),
+            EcuClient::Uds(c) => c.read_dtcs()
+        }
+    }
+}
+
+/// Detects which protocol an ECU speaks. The ECU is left registered on the channel. Its session is only
+/// changed (To the default session) if the protocol cannot be told apart otherwise
+///
+/// ## Arguments
+/// * channel - The channel the ECU is connected to
+/// * tx_id - The CAN ID requests are sent to the ECU with
+/// * rx_id - The CAN ID the ECU responds with
+/// * timeout_ms - Time to wait for each response
+///
+/// ## Returns
+/// The protocol of the ECU, or a timeout error if the ECU does not respond at all
+pub fn detect_protocol<A: AdapterHardware>(channel: &DiagChannel<A>, tx_id: u32, rx_id: u32, timeout_ms: u128) -> ProtocolResult<DiagProtocol> {
+    // The protocol of the engine only affects how negative responses are described
+    let mut engine = RequestEngine::new(channel.clone(), tx_id, rx_id, DiagProtocol::UDS)?;
+    engine.set_timing(EcuTiming { p2_ms: timeout_ms, busy_retries: 0, ..Default::default() });
+
+    // ReadECUIdentification does not exist in UDS
+    match engine.send_request(&[0x1A, KWP_ID_DAIMLER]) {
+        Ok(_) => return Ok(DiagProtocol::KWP2000),
+        Err(ProtocolError::ECUError { code: NRC_SERVICE_NOT_SUPPORTED, .. }) => return Ok(DiagProtocol::UDS),
+        // An ECU which does not respond at all is not there
+        Err(e) if e.is_timeout() => return Err(e),
+        Err(_) => {}
+    }
+    // KWP2000 TesterPresent only has the sub-functions 0x01 and 0x02
+    match engine.send_request(&[0x3E, 0x00]) {
+        Ok(_) => return Ok(DiagProtocol::UDS),
+        Err(ProtocolError::ECUError { code: NRC_SUB_FUNCTION_NOT_SUPPORTED, .. }) => return Ok(DiagProtocol::KWP2000),
+        Err(_) => {}
+    }
+    if engine.send_request(&[0x10, UdsSession::Default.id()]).is_ok() {
+        return Ok(DiagProtocol::UDS);
+    }
+    if engine.send_request(&[0x10, KwpSession::Normal.id()]).is_ok() {
+        return Ok(DiagProtocol::KWP2000);
+    }
+    Err(ProtocolError::ServerError(format!("Could not detect the protocol of ECU 0x{:04X}", tx_id)))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_channel;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    pub fn test_detect_protocol() {
+        let session_requests = Arc::new(AtomicU32::new(0));
+        let counter = session_requests.clone();
+        let (_, channel) = sim_channel(move |_, id, req| {
+            if req[0] == 0x10 {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            let res = match (id, req) {
+                // UDS
+                (0x0744, [0x1A, _]) => vec![0x7F, 0x1A, 0x11],
+                // KWP2000
+                (0x07E0, [0x1A, 0x86]) => vec![0x5A, 0x86, 0x02, 0x71],
+                // KWP2000 which only identifies itself after security access
+                (0x0740, [0x1A, _]) => vec![0x7F, 0x1A, 0x33],
+                (0x0740, [0x3E, 0x00]) => vec![0x7F, 0x3E, 0x12],
+                // UDS which rejects both, so only its default session tells it apart
+                (0x0746, [0x10, 0x01]) => vec![0x50, 0x01, 0x00, 0x32, 0x01, 0xF4],
+                (0x0746, _) => vec![0x7F, req[0], 0x22],
+                _ => return Vec::new()
+            };
+            let rx_id = if id == 0x07E0 { 0x07E8 } else { id - 0x0280 };
+            vec![(rx_id, res)]
+        });
+
+        assert_eq!(DiagProtocol::UDS, detect_protocol(&channel, 0x0744, 0x04C4, 50).unwrap());
+        assert_eq!(DiagProtocol::KWP2000, detect_protocol(&channel, 0x0740, 0x04C0, 50).unwrap());
+        assert!(detect_protocol(&channel, 0x0745, 0x04C5, 50).unwrap_err().is_timeout());
+        match EcuClient::connect(&channel, 0x07E0, 0x07E8, 50).unwrap() {
+            EcuClient::Kwp2000(mut kwp) => assert_eq!(vec![0x02, 0x71], kwp.read_ecu_identification(KWP_ID_DAIMLER).unwrap()),
+            EcuClient::Uds(_) => panic!("ECU was detected as UDS")
+        }
+        // Identifiers which do not fit the protocol are refused before anything is sent
+        let mut kwp = EcuClient::from_engine(RequestEngine::new(channel.clone(), 0x07E0, 0x07E8, DiagProtocol::KWP2000).unwrap());
+        assert!(matches!(kwp.read_data_record(0x0110), Err(ProtocolError::ServerError(_))));
+        let items = [DynamicIdentifierItem { source_id: 0x0105, position: 1, size: 1 }];
+        assert!(matches!(kwp.define_dynamic_id(0xF0, &items), Err(ProtocolError::ServerError(_))));
+        let mut uds = EcuClient::from_engine(RequestEngine::new(channel.clone(), 0x0744, 0x04C4, DiagProtocol::UDS).unwrap());
+        assert!(matches!(uds.start_periodic(0xF100, PeriodicRate::Fast), Err(ProtocolError::ServerError(_))));
+        // Sessions are left alone unless nothing else tells the protocols apart
+        assert_eq!(0, session_requests.load(Ordering::Relaxed));
+        assert_eq!(DiagProtocol::UDS, detect_protocol(&channel, 0x0746, 0x04C6, 50).unwrap());
+        assert_eq!(1, session_requests.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,177 @@
+//! DTC display formatting (SAE J2012), as shown by DAS and Xentry
+//!
+//! The top 2 bits of a DTC select the system (P, C, B or U), the next 2 bits are the first digit, and the remaining
+//! 3 nibbles are shown in hex. Manufacturer specific codes commonly use hex digits (Such as `B10A1`), whilst
+//! SAE defined codes only use decimal digits. 3 byte UDS DTCs append the failure type byte in hex (Such as `B1A2315`).
+//!
+//! This module also contains the environment data of DTCs (Freeze frames, occurrence counters, mileage).
+//! The layout of environment data is ECU specific, so it is kept as raw bytes, and only decoded into
+//! named values if an [EnvironmentLayout] for the ECU is known.
+
+/// System a DTC belongs to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcSystem {
+    /// P - Powertrain
+    Powertrain,
+    /// C - Chassis
+    Chassis,
+    /// B - Body
+    Body,
+    /// U - Network
+    Network
+}
+
+impl DtcSystem {
+    /// Returns the system of a 2 byte DTC
+    pub fn from_code(code: u16) -> Self {
+        match code >> 14 {
+            0 => DtcSystem::Powertrain,
+            1 => DtcSystem::Chassis,
+            2 => DtcSystem::Body,
+            _ => DtcSystem::Network
+        }
+    }
+
+    pub fn letter(&self) -> char {
+        match self {
+            DtcSystem::Powertrain => 'P',
+            DtcSystem::Chassis => 'C',
+            DtcSystem::Body => 'B',
+            DtcSystem::Network => 'U'
+        }
+    }
+}
+
+/// Formats a 2 byte DTC, such as 0x9001 as `B1001`
+pub fn format_dtc(code: u16) -> String {
+    format!("{}{:01X}{:03X}", DtcSystem::from_code(code).letter(), (code >> 12) & 0x03, code & 0x0FFF)
+}
+
+/// Formats a 3 byte DTC (2 byte DTC and failure type byte), such as 0x9A2315 as `B1A2315`
+pub fn format_dtc_with_failure_type(code: u32) -> String {
+    format!("{}{:02X}", format_dtc((code >> 8) as u16), code as u8)
+}
+
+/// Returns true if a 2 byte DTC is manufacturer specific rather than defined by SAE
+pub fn is_manufacturer_specific(code: u16) -> bool {
+    let digit = (code >> 12) & 0x03;
+    match DtcSystem::from_code(code) {
+        DtcSystem::Powertrain => digit == 1 || digit == 3,
+        _ => digit == 1 || digit == 2
+    }
+}
+
+/// A freeze frame (Snapshot) the ECU recorded when a DTC occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreezeFrame {
+    pub record_number: u8,
+    /// Number of data identifiers in the record
+    pub identifier_count: u8,
+    /// The data identifiers and their values. These cannot be split without knowing the length of each value
+    pub data: Vec<u8>
+}
+
+/// An extended data record of a DTC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedDataRecord {
+    /// Record number. 0xFF means the data contains all records, each prefixed with its record number
+    pub record_number: u8,
+    pub data: Vec<u8>
+}
+
+/// Location of a value within the environment data of a DTC. Values are big endian unsigned integers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EnvironmentField {
+    /// The extended data record the value is in (UDS), or None for the KWP2000 environment data
+    pub record_number: Option<u8>,
+    /// Offset of the value within the data (In bytes)
+    pub offset: usize,
+    /// Length of the value (In bytes, up to 4)
+    pub len: usize
+}
+
+/// Describes where an ECU stores values within the environment data of its DTCs
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EnvironmentLayout {
+    /// Number of times the DTC occurred
+    pub frequency_counter: Option<EnvironmentField>,
+    /// Odometer reading when the DTC first occurred (In km)
+    pub first_occurrence_km: Option<EnvironmentField>,
+    /// Odometer reading when the DTC last occurred (In km)
+    pub last_occurrence_km: Option<EnvironmentField>,
+    /// The UDS extended data records to read. If empty, all records are read as one
+    pub extended_data_records: Vec<u8>
+}
+
+/// Environment data of a DTC
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DtcEnvironment {
+    /// Number of times the DTC occurred
+    pub frequency_counter: Option<u32>,
+    /// Odometer reading when the DTC first occurred (In km)
+    pub first_occurrence_km: Option<u32>,
+    /// Odometer reading when the DTC last occurred (In km)
+    pub last_occurrence_km: Option<u32>,
+    /// Freeze frames (UDS)
+    pub freeze_frames: Vec<FreezeFrame>,
+    /// Extended data records (UDS)
+    pub extended_data: Vec<ExtendedDataRecord>,
+    /// Environment data following the DTC status (KWP2000)
+    pub raw: Vec<u8>
+}
+
+impl DtcEnvironment {
+    /// Decodes the values described by a layout. Values which are not present in the data are left as None
+    pub fn apply_layout(&mut self, layout: &EnvironmentLayout) {
+        self.frequency_counter = layout.frequency_counter.and_then(|f| self.read_field(f));
+        self.first_occurrence_km = layout.first_occurrence_km.and_then(|f| self.read_field(f));
+        self.last_occurrence_km = layout.last_occurrence_km.and_then(|f| self.read_field(f));
+    }
+
+    fn read_field(&self, field: EnvironmentField) -> Option<u32> {
+        let data = match field.record_number {
+            Some(n) => &self.extended_data.iter().find(|r| r.record_number == n)?.data,
+            None => &self.raw
+        };
+        if field.len > 4 {
+            return None;
+        }
+        let bytes = data.get(field.offset..field.offset + field.len)?;
+        Some(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_dtc_format() {
+        assert_eq!("P0300", format_dtc(0x0300));
+        assert_eq!("C1234", format_dtc(0x5234));
+        assert_eq!("B10A1", format_dtc(0x90A1));
+        assert_eq!("U0100", format_dtc(0xC100));
+        assert_eq!("B1A2315", format_dtc_with_failure_type(0x9A2315));
+        assert_eq!("P062B00", format_dtc_with_failure_type(0x062B00));
+        assert!(is_manufacturer_specific(0x90A1));
+        assert!(!is_manufacturer_specific(0xC100));
+    }
+
+    #[test]
+    pub fn test_environment_layout() {
+        let mut env = DtcEnvironment {
+            extended_data: vec![ExtendedDataRecord { record_number: 0x01, data: vec![0x05, 0x01, 0xE2, 0x40] }],
+            raw: vec![0x12, 0x00, 0x30, 0x39],
+            ..Default::default()
+        };
+        env.apply_layout(&EnvironmentLayout {
+            frequency_counter: Some(EnvironmentField { record_number: Some(0x01), offset: 0, len: 1 }),
+            first_occurrence_km: Some(EnvironmentField { record_number: Some(0x01), offset: 1, len: 3 }),
+            last_occurrence_km: Some(EnvironmentField { record_number: None, offset: 1, len: 3 }),
+            extended_data_records: vec![0x01]
+        });
+        assert_eq!(Some(5), env.frequency_counter);
+        assert_eq!(Some(123_456), env.first_occurrence_km);
+        assert_eq!(Some(12_345), env.last_occurrence_km);
+    }
+}
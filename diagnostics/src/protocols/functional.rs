@@ -0,0 +1,274 @@
+//! Functional (broadcast) addressing
+//!
+//! A functional request is sent once to an ID every ECU listens to (Such as 0x7DF for OBD), and every ECU
+//! which supports the request responds with its own response ID. Responses are collected until no ECU has
+//! responded within P2, and are returned tagged with the response ID of the ECU. This is used for vehicle
+//! wide TesterPresent, clearing the DTCs of every ECU, and quickly finding out which ECUs are fitted.
+//!
+//! Functional requests must fit in a single CAN frame. Responses can span multiple frames, so every ECU
+//! which may respond still needs a flow control filter with its physical request ID.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+
+use super::nrc::NRC_RESPONSE_PENDING;
+use super::request::{ResponseType, NEGATIVE_RESPONSE_SID};
+use super::session::tester_present_request;
+use super::{DiagChannel, DiagProtocol, ProtocolError, ProtocolResult};
+
+/// Maximum time to wait for ECUs which reported that their response is pending (In ms)
+const RESPONSE_PENDING_TIMEOUT_MS: u128 = 5000;
+
+/// The functional request ID, and the ECUs which may respond to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionalAddressing {
+    /// CAN ID functional requests are sent with
+    pub request_id: u32,
+    /// The physical request ID and response ID of each ECU which may respond
+    pub ecus: Vec<(u32, u32)>
+}
+
+impl FunctionalAddressing {
+    pub fn new(request_id: u32, ecus: Vec<(u32, u32)>) -> Self {
+        Self { request_id, ecus }
+    }
+
+    /// Listens to a range of response IDs
+    ///
+    /// ## Arguments
+    /// * request_id - CAN ID functional requests are sent with
+    /// * response_ids - The response IDs of the ECUs which may respond. Adapters only support a limited number
+    ///   of filters, so keep the range small
+    /// * physical_request_id - Returns the physical request ID of an ECU from its response ID
+    pub fn with_response_range(request_id: u32, response_ids: RangeInclusive<u32>, physical_request_id: fn(u32) -> u32) -> Self {
+        Self { request_id, ecus: response_ids.map(|rx| (physical_request_id(rx), rx)).collect() }
+    }
+
+    /// OBD (ISO 15765-4) with 11 bit IDs. Requests are sent to 0x7DF, and ECUs respond with 0x7E8 - 0x7EF
+    pub fn obd_11bit() -> Self {
+        Self::with_response_range(0x07DF, 0x07E8..=0x07EF, |rx| rx - 8)
+    }
+
+    /// OBD (ISO 15765-4) with 29 bit IDs. Requests are sent to 0x18DB33F1, and ECUs respond with 0x18DAF1xx.
+    /// Only ECU addresses 0x10 - 0x17 are listened to
+    pub fn obd_29bit() -> Self {
+        Self::with_response_range(0x18DB_33F1, 0x18DA_F110..=0x18DA_F117, |rx| 0x18DA_00F1 | ((rx & 0xFF) << 8))
+    }
+
+    /// The response IDs of the ECUs
+    pub fn response_ids(&self) -> Vec<u32> {
+        self.ecus.iter().map(|(_, rx)| *rx).collect()
+    }
+}
+
+/// A response to a functional request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionalResponse {
+    /// CAN ID the ECU responded with
+    pub rx_id: u32,
+    /// The response, including its service ID
+    pub data: Vec<u8>
+}
+
+impl FunctionalResponse {
+    /// Returns true if the response is positive
+    pub fn is_positive(&self) -> bool {
+        self.data.first().is_some_and(|sid| *sid != NEGATIVE_RESPONSE_SID)
+    }
+
+    /// Returns the negative response code, if the response is negative
+    pub fn nrc(&self) -> Option<u8> {
+        match self.data.as_slice() {
+            [NEGATIVE_RESPONSE_SID, _, code, ..] => Some(*code),
+            _ => None
+        }
+    }
+}
+
+/// Sends functional requests over a [DiagChannel]
+#[derive(Debug, Clone)]
+pub struct FunctionalClient<A: AdapterHardware> {
+    channel: DiagChannel<A>,
+    addressing: FunctionalAddressing,
+    /// Response IDs registered by this client, which are unregistered when it is closed
+    registered: Vec<u32>,
+    timeout_ms: u128
+}
+
+impl<A: AdapterHardware> FunctionalClient<A> {
+    /// Registers every ECU which may respond on the channel. ECUs which are already registered (By a
+    /// physical client) stay registered when the functional client is closed. Whilst both are in use,
+    /// either may receive the responses of the other
+    pub fn open(channel: DiagChannel<A>, addressing: FunctionalAddressing) -> ProtocolResult<Self> {
+        let registered = channel.register_ecus(&addressing.ecus)?;
+        Ok(Self { channel, addressing, registered, timeout_ms: 100 })
+    }
+
+    /// Sets the time to wait for ECUs to respond (P2, in ms). Collection of responses ends once no ECU has
+    /// responded for this long
+    pub fn set_timeout(&mut self, timeout_ms: u128) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn addressing(&self) -> &FunctionalAddressing {
+        &self.addressing
+    }
+
+    /// Sends a functional request, and collects the responses of every ECU. ECUs which report that their
+    /// response is pending are waited for up to P2*
+    ///
+    /// ## Returns
+    /// The positive and negative responses, in the order they were received. Empty if no ECU responded
+    pub fn request(&self, request: &[u8]) -> ProtocolResult<Vec<FunctionalResponse>> {
+        let sid = self.check_request(request)?;
+        let response_ids = self.addressing.response_ids();
+        for rx_id in &response_ids {
+            self.channel.clear(*rx_id)?;
+        }
+        self.channel.send(self.addressing.request_id, request)?;
+        let responses = collect_responses(sid, self.timeout_ms, |timeout| self.channel.receive_any(&response_ids, timeout).map(|m| vec![m]))?;
+        Ok(responses.into_iter()
+            .filter(|(_, data)| ResponseType::decode(sid, data) != ResponseType::Unrelated)
+            .map(|(rx_id, data)| FunctionalResponse { rx_id, data })
+            .collect())
+    }
+
+    /// Sends a functional request without waiting for responses
+    pub fn send(&self, request: &[u8]) -> ProtocolResult<()> {
+        self.check_request(request)?;
+        self.channel.send(self.addressing.request_id, request)
+    }
+
+    /// Checks that a request fits in a single frame, as no ECU sends flow control for a functional request
+    ///
+    /// ## Returns
+    /// The service ID of the request
+    fn check_request(&self, request: &[u8]) -> ProtocolResult<u8> {
+        let sid = *request.first().ok_or_else(|| ProtocolError::ServerError("Request is empty".into()))?;
+        let max_len = self.channel.max_single_frame_len();
+        if request.len() > max_len {
+            return Err(ProtocolError::ServerError(format!("Functional requests cannot be longer than {} bytes", max_len)));
+        }
+        Ok(sid)
+    }
+
+    /// Sends TesterPresent to every ECU, keeping their sessions alive
+    ///
+    /// ## Arguments
+    /// * protocol - The protocol of the ECUs
+    /// * response_required - If false, the ECUs are told not to respond, and nothing is returned
+    pub fn tester_present(&self, protocol: DiagProtocol, response_required: bool) -> ProtocolResult<Vec<FunctionalResponse>> {
+        let request = tester_present_request(protocol, response_required);
+        if response_required {
+            self.request(&request)
+        } else {
+            self.send(&request).map(|_| Vec::new())
+        }
+    }
+
+    /// Clears all DTCs of every ECU
+    pub fn clear_all_dtcs(&self, protocol: DiagProtocol) -> ProtocolResult<Vec<FunctionalResponse>> {
+        match protocol {
+            DiagProtocol::KWP2000 => self.request(&[0x14, 0xFF, 0x00]),
+            DiagProtocol::UDS => self.request(&[0x14, 0xFF, 0xFF, 0xFF])
+        }
+    }
+
+    /// Finds the ECUs which are fitted, by sending TesterPresent
+    ///
+    /// ## Returns
+    /// The response IDs of the ECUs which responded, positively or negatively
+    pub fn discover(&self, protocol: DiagProtocol) -> ProtocolResult<Vec<u32>> {
+        let mut ids = self.tester_present(protocol, true)?.into_iter().map(|r| r.rx_id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Unregisters the ECUs registered by this client
+    pub fn close(&mut self) -> ProtocolResult<()> {
+        for rx_id in self.registered.drain(..) {
+            self.channel.unregister_ecu(rx_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects responses until no response has been received for the timeout. An ECU which reports that its
+/// response is pending is waited for up to [RESPONSE_PENDING_TIMEOUT_MS], until it sends its response
+///
+/// ## Arguments
+/// * sid - Service ID of the request
+/// * timeout_ms - Time to wait for the next response
+/// * receive - Waits up to the given time for messages, returning them tagged with the ID of their sender
+pub(crate) fn collect_responses<F>(sid: u8, timeout_ms: u128, mut receive: F) -> ProtocolResult<Vec<(u32, Vec<u8>)>>
+where F: FnMut(u128) -> ProtocolResult<Vec<(u32, Vec<u8>)>> {
+    let timeout = Duration::from_millis(timeout_ms as u64);
+    let mut res = Vec::new();
+    let mut last_response = Instant::now();
+    // Deadline of each ECU whose response is pending
+    let mut pending: HashMap<u32, Instant> = HashMap::new();
+    loop {
+        let deadline = pending.values().copied().fold(last_response + timeout, Instant::max);
+        let remaining = deadline.saturating_duration_since(Instant::now()).as_millis();
+        if remaining == 0 {
+            return Ok(res);
+        }
+        match receive(remaining) {
+            Ok(msgs) => {
+                for (ecu, data) in msgs {
+                    last_response = Instant::now();
+                    if matches!(data.as_slice(), [NEGATIVE_RESPONSE_SID, s, NRC_RESPONSE_PENDING] if *s == sid) {
+                        pending.insert(ecu, last_response + Duration::from_millis(RESPONSE_PENDING_TIMEOUT_MS as u64));
+                    } else {
+                        pending.remove(&ecu);
+                        res.push((ecu, data));
+                    }
+                }
+            },
+            Err(e) if e.is_timeout() => return Ok(res),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hardware::SimAdapter;
+
+    #[test]
+    pub fn test_functional_request() {
+        let mut sim = SimAdapter::new(|_, id, req| {
+            if id != 0x07DF {
+                return Vec::new();
+            }
+            match req {
+                [0x3E, 0x00] => vec![(0x07E8, vec![0x7E, 0x00]), (0x07EA, vec![0x7F, 0x3E, 0x22])],
+                [0x14, 0xFF, 0xFF, 0xFF] => vec![(0x07EA, vec![0x7F, 0x14, 0x78]), (0x07E8, vec![0x54]), (0x07EA, vec![0x54])],
+                _ => Vec::new()
+            }
+        });
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        // An ECU which is also used physically stays registered after the functional client is closed
+        channel.register_ecu(0x07E0, 0x07E8).unwrap();
+        let mut functional = FunctionalClient::open(channel.clone(), FunctionalAddressing::obd_11bit()).unwrap();
+        functional.set_timeout(50);
+
+        assert_eq!(vec![0x07E8, 0x07EA], functional.discover(DiagProtocol::UDS).unwrap());
+        let cleared = functional.clear_all_dtcs(DiagProtocol::UDS).unwrap();
+        assert_eq!(vec![0x07E8, 0x07EA], cleared.iter().map(|r| r.rx_id).collect::<Vec<_>>());
+        assert!(cleared.iter().all(|r| r.is_positive() && r.nrc().is_none()));
+        assert!(functional.tester_present(DiagProtocol::UDS, false).unwrap().is_empty());
+        assert!(matches!(functional.request(&[]), Err(ProtocolError::ServerError(_))));
+        assert!(matches!(functional.send(&[0x2E, 0xF1, 0x90, 0x57, 0x44, 0x44, 0x32, 0x32]), Err(ProtocolError::ServerError(_))));
+
+        functional.close().unwrap();
+        assert!(channel.is_registered(0x07E8));
+        assert!(!channel.is_registered(0x07E9));
+    }
+}
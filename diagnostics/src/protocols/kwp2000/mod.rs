@@ -1,10 +1,571 @@
+//! KWP2000 (ISO 14230-3) diagnostic client, as used on CAN by most Mercedes ECUs from the DAS era
 
+use hardware::AdapterHardware;
 
+use super::dtc::{format_dtc, DtcEnvironment, EnvironmentLayout};
+use super::request::strip_echo;
+use super::security::key_level;
+use super::{DiagChannel, DiagProtocol, DTCState, DynamicIdentifierItem, GenericProtocolServer, MemoryAddressFormat, PeriodicRate, ProtocolError, ProtocolResult, RequestEngine, DTC};
+
+/// KWP2000 service IDs
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KwpService {
+    StartDiagnosticSession = 0x10,
+    ECUReset = 0x11,
+    ClearDiagnosticInformation = 0x14,
+    ReadStatusOfDTC = 0x17,
+    ReadDTCByStatus = 0x18,
+    ReadECUIdentification = 0x1A,
+    ReadDataByLocalIdentifier = 0x21,
+    ReadMemoryByAddress = 0x23,
+    SecurityAccess = 0x27,
+    DynamicallyDefineLocalIdentifier = 0x2C,
+    InputOutputControlByLocalIdentifier = 0x30,
+    StartRoutineByLocalIdentifier = 0x31,
+    StopRoutineByLocalIdentifier = 0x32,
+    RequestRoutineResultsByLocalIdentifier = 0x33,
+    RequestUpload = 0x35,
+    TransferData = 0x36,
+    RequestTransferExit = 0x37,
+    WriteDataByLocalIdentifier = 0x3B,
+    TesterPresent = 0x3E
+}
+
+/// KWP2000 diagnostic sessions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KwpSession {
+    /// Default session
+    Normal,
+    /// ECU programming (Flashing) session
+    Reprogramming,
+    /// ECU standby session
+    Standby,
+    /// ECU passive session
+    Passive,
+    /// Extended diagnostic session, required for coding and actuation
+    ExtendedDiagnostics,
+    /// Any other session ID
+    Custom(u8)
+}
+
+impl KwpSession {
+    pub fn id(&self) -> u8 {
+        match self {
+            KwpSession::Normal => 0x81,
+            KwpSession::Reprogramming => 0x85,
+            KwpSession::Standby => 0x89,
+            KwpSession::Passive => 0x90,
+            KwpSession::ExtendedDiagnostics => 0x92,
+            KwpSession::Custom(id) => *id
+        }
+    }
+
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0x81 => KwpSession::Normal,
+            0x85 => KwpSession::Reprogramming,
+            0x89 => KwpSession::Standby,
+            0x90 => KwpSession::Passive,
+            0x92 => KwpSession::ExtendedDiagnostics,
+            _ => KwpSession::Custom(id)
+        }
+    }
+}
+
+/// ECU reset types
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KwpResetMode {
+    /// Simulates a power cycle of the ECU
+    PowerOn,
+    /// Resets the ECU's non-volatile memory
+    NonVolatileMemory,
+    /// Any other reset mode
+    Custom(u8)
+}
+
+impl KwpResetMode {
+    pub fn id(&self) -> u8 {
+        match self {
+            KwpResetMode::PowerOn => 0x01,
+            KwpResetMode::NonVolatileMemory => 0x02,
+            KwpResetMode::Custom(id) => *id
+        }
+    }
+}
+
+/// Input/Output control parameters
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KwpIoControlParameter {
+    /// Gives control of the output back to the ECU
+    ReturnControlToEcu,
+    /// Reads the current state of the output
+    ReportCurrentState,
+    /// Resets the output to its default state
+    ResetToDefault,
+    /// Freezes the output in its current state
+    FreezeCurrentState,
+    /// Temporarily sets the output to the supplied state
+    ShortTermAdjustment,
+    /// Permanently sets the output to the supplied state
+    LongTermAdjustment
+}
+
+impl KwpIoControlParameter {
+    pub fn id(&self) -> u8 {
+        match self {
+            KwpIoControlParameter::ReturnControlToEcu => 0x00,
+            KwpIoControlParameter::ReportCurrentState => 0x01,
+            KwpIoControlParameter::ResetToDefault => 0x04,
+            KwpIoControlParameter::FreezeCurrentState => 0x05,
+            KwpIoControlParameter::ShortTermAdjustment => 0x07,
+            KwpIoControlParameter::LongTermAdjustment => 0x08
+        }
+    }
+}
+
+/// A DTC as reported by ReadDTCByStatus
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KwpDtcRecord {
+    /// Raw 2 byte DTC
+    pub code: u16,
+    /// Status byte of the DTC
+    pub status: u8
+}
+
+impl KwpDtcRecord {
+    /// Returns true if the ECU requests the warning lamp to be on for this DTC
+    pub fn warning_lamp(&self) -> bool {
+        self.status & 0x80 != 0
+    }
+
+    /// Converts the storage state bits of the status byte into a [DTCState]
+    pub fn state(&self) -> DTCState {
+        match (self.status >> 5) & 0b11 {
+            0b00 => DTCState::None,
+            0b01 => DTCState::Stored,
+            0b10 => DTCState::Pending,
+            _ => DTCState::Active
+        }
+    }
+}
 
+impl From<KwpDtcRecord> for DTC {
+    fn from(r: KwpDtcRecord) -> Self {
+        DTC {
+            code: format_dtc(r.code),
+            state: r.state(),
+            mil_on: r.warning_lamp(),
+            environment: None
+        }
+    }
 }
 
+/// Typed KWP2000 services. These are implemented on top of [GenericProtocolServer::send_command_with_response]
+pub trait Kwp2000Server: GenericProtocolServer {
+    /// Switches the ECU to another diagnostic session
+    fn start_diagnostic_session(&mut self, session: KwpSession) -> ProtocolResult<()> {
+        let res = self.send_command_with_response(&[KwpService::StartDiagnosticSession as u8, session.id()])?;
+        strip_echo(&res, &[session.id()]).map(|_| ())
+    }
+
+    /// Resets the ECU
+    fn ecu_reset(&mut self, mode: KwpResetMode) -> ProtocolResult<()> {
+        self.send_command_with_response(&[KwpService::ECUReset as u8, mode.id()]).map(|_| ())
+    }
+
+    /// Reads an identification record from the ECU
+    ///
+    /// ## Arguments
+    /// * option - The identification option to read, such as 0x86 for the Mercedes ECU identification
+    ///
+    /// ## Returns
+    /// The identification data, excluding the echoed option
+    fn read_ecu_identification(&mut self, option: u8) -> ProtocolResult<Vec<u8>> {
+        let res = self.send_command_with_response(&[KwpService::ReadECUIdentification as u8, option])?;
+        strip_echo(&res, &[option]).map(|d| d.to_vec())
+    }
+
+    /// Reads a record from the ECU by its local identifier
+    fn read_data_by_local_id(&mut self, local_id: u8) -> ProtocolResult<Vec<u8>> {
+        let res = self.send_command_with_response(&[KwpService::ReadDataByLocalIdentifier as u8, local_id])?;
+        strip_echo(&res, &[local_id]).map(|d| d.to_vec())
+    }
+
+    /// Writes a record to the ECU by its local identifier
+    fn write_data_by_local_id(&mut self, local_id: u8, data: &[u8]) -> ProtocolResult<()> {
+        let mut req = vec![KwpService::WriteDataByLocalIdentifier as u8, local_id];
+        req.extend_from_slice(data);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[local_id]).map(|_| ())
+    }
+
+    /// Reads an area of the ECU's memory
+    ///
+    /// ## Arguments
+    /// * format - How the address and size are encoded. Most ECUs use a 3 byte address and a 1 byte size
+    /// * address - The start address of the area
+    /// * size - The number of bytes to read
+    fn read_memory_by_address(&mut self, format: MemoryAddressFormat, address: u32, size: u32) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![KwpService::ReadMemoryByAddress as u8];
+        req.extend_from_slice(&format.encode(address, size)?);
+        let res = self.send_command_with_response(&req)?;
+        Ok(res[1..].to_vec())
+    }
+
+    /// Asks the ECU to upload an area of its memory to the tester. The data is then read with
+    /// [Kwp2000Server::transfer_data], and the upload is ended with [Kwp2000Server::request_transfer_exit]
+    ///
+    /// ## Arguments
+    /// * address - The start address of the area (3 bytes)
+    /// * data_format - The data format identifier. 0x00 requests the data unencrypted and uncompressed
+    /// * size - The number of bytes to upload (3 bytes)
+    ///
+    /// ## Returns
+    /// The maximum length of a TransferData response, including the service ID
+    fn request_upload(&mut self, address: u32, data_format: u8, size: u32) -> ProtocolResult<u32> {
+        let area = MemoryAddressFormat::new(3, 3).encode(address, size)?;
+        let mut req = vec![KwpService::RequestUpload as u8];
+        req.extend_from_slice(&area[..3]);
+        req.push(data_format);
+        req.extend_from_slice(&area[3..]);
+        let res = self.send_command_with_response(&req)?;
+        match res.get(1..) {
+            Some(len) if !len.is_empty() && len.len() <= 4 => Ok(len.iter().fold(0, |acc, b| (acc << 8) | *b as u32)),
+            _ => Err(ProtocolError::InvalidResponse(res))
+        }
+    }
+
+    /// Transfers the next block of an upload or download
+    ///
+    /// ## Arguments
+    /// * params - Transfer request parameters. Empty for uploads
+    ///
+    /// ## Returns
+    /// The data in the response
+    fn transfer_data(&mut self, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![KwpService::TransferData as u8];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        Ok(res[1..].to_vec())
+    }
+
+    /// Ends an upload or download
+    fn request_transfer_exit(&mut self) -> ProtocolResult<()> {
+        self.send_command_with_response(&[KwpService::RequestTransferExit as u8]).map(|_| ())
+    }
+
+    /// Asks the ECU to transmit a record periodically, until [Kwp2000Server::stop_periodic_local_id] is called.
+    /// The records are received as unsolicited positive responses
+    ///
+    /// ## Returns
+    /// The first transmission of the record
+    fn start_periodic_local_id(&mut self, local_id: u8, rate: PeriodicRate) -> ProtocolResult<Vec<u8>> {
+        let mode = match rate {
+            PeriodicRate::Slow => 0x02,
+            PeriodicRate::Medium => 0x03,
+            PeriodicRate::Fast => 0x04
+        };
+        let res = self.send_command_with_response(&[KwpService::ReadDataByLocalIdentifier as u8, local_id, mode])?;
+        strip_echo(&res, &[local_id]).map(|d| d.to_vec())
+    }
+
+    /// Stops the periodic transmission of a record
+    fn stop_periodic_local_id(&mut self, local_id: u8) -> ProtocolResult<()> {
+        self.send_command_with_response(&[KwpService::ReadDataByLocalIdentifier as u8, local_id, 0x05]).map(|_| ())
+    }
+
+    /// Defines a local identifier whose record is made up of parts of other records. Each item is
+    /// sent as its own request, as not all ECUs accept multiple items at once
+    ///
+    /// ## Arguments
+    /// * dynamic_id - The local identifier to define (Usually 0xF0 - 0xF9)
+    /// * items - The parts of other records to copy, in order
+    fn dynamically_define_local_id(&mut self, dynamic_id: u8, items: &[DynamicIdentifierItem]) -> ProtocolResult<()> {
+        if let Some(item) = items.iter().find(|i| i.source_id > 0xFF) {
+            return Err(ProtocolError::ServerError(format!("0x{:04X} is not a KWP2000 local identifier", item.source_id)));
+        }
+        let mut position = 1u8;
+        for item in items {
+            let req = [KwpService::DynamicallyDefineLocalIdentifier as u8, dynamic_id, 0x01, position, item.size, item.source_id as u8, item.position];
+            let res = self.send_command_with_response(&req)?;
+            strip_echo(&res, &[dynamic_id])?;
+            position = position.wrapping_add(item.size);
+        }
+        Ok(())
+    }
+
+    /// Clears the definition of a dynamically defined local identifier
+    fn clear_dynamically_defined_local_id(&mut self, dynamic_id: u8) -> ProtocolResult<()> {
+        let res = self.send_command_with_response(&[KwpService::DynamicallyDefineLocalIdentifier as u8, dynamic_id, 0x04])?;
+        strip_echo(&res, &[dynamic_id]).map(|_| ())
+    }
 
-pub trait Kwp2000Server {
-    
-}
\ No newline at end of file
+    /// Reads all identified DTCs and their status from the ECU
+    ///
+    /// ## Arguments
+    /// * group - The group of DTCs to read. 0xFF00 reads all DTCs
+    fn read_dtc_by_status(&mut self, group: u16) -> ProtocolResult<Vec<KwpDtcRecord>> {
+        let [hi, lo] = group.to_be_bytes();
+        let res = self.send_command_with_response(&[KwpService::ReadDTCByStatus as u8, 0x02, hi, lo])?;
+        let count = *res.get(1).ok_or_else(|| ProtocolError::InvalidResponse(res.clone()))? as usize;
+        let records = &res[2..];
+        if records.len() != count * 3 {
+            return Err(ProtocolError::InvalidResponse(res));
+        }
+        Ok(records.chunks(3).map(|r| KwpDtcRecord { code: u16::from_be_bytes([r[0], r[1]]), status: r[2] }).collect())
+    }
+
+    /// Reads the status and environment data of a single DTC
+    ///
+    /// ## Returns
+    /// The DTC and its status, and the raw environment data following it
+    fn read_status_of_dtc(&mut self, code: u16) -> ProtocolResult<(KwpDtcRecord, Vec<u8>)> {
+        let [hi, lo] = code.to_be_bytes();
+        let res = self.send_command_with_response(&[KwpService::ReadStatusOfDTC as u8, hi, lo])?;
+        match res.as_slice() {
+            [_, count, hi, lo, status, env @ ..] if *count > 0 && u16::from_be_bytes([*hi, *lo]) == code => {
+                Ok((KwpDtcRecord { code, status: *status }, env.to_vec()))
+            },
+            _ => Err(ProtocolError::InvalidResponse(res))
+        }
+    }
+
+    /// Reads the environment data of a DTC
+    ///
+    /// ## Arguments
+    /// * code - The DTC to read the environment data of
+    /// * layout - The layout of the ECU's environment data, if known
+    fn read_dtc_environment(&mut self, code: u16, layout: Option<&EnvironmentLayout>) -> ProtocolResult<DtcEnvironment> {
+        let (_, raw) = self.read_status_of_dtc(code)?;
+        let mut env = DtcEnvironment { raw, ..Default::default() };
+        if let Some(layout) = layout {
+            env.apply_layout(layout);
+        }
+        Ok(env)
+    }
+
+    /// Reads all DTCs, and the environment data of each. DTCs whose environment data the ECU refuses to
+    /// return are listed without it
+    ///
+    /// ## Arguments
+    /// * layout - The layout of the ECU's environment data, if known
+    fn read_dtcs_with_environment(&mut self, layout: Option<&EnvironmentLayout>) -> ProtocolResult<Vec<DTC>> {
+        let mut dtcs = Vec::new();
+        for record in self.read_dtc_by_status(0xFF00)? {
+            let mut dtc = DTC::from(record);
+            dtc.environment = match self.read_dtc_environment(record.code, layout) {
+                Ok(env) => Some(env),
+                Err(ProtocolError::ECUError { .. }) => None,
+                Err(e) => return Err(e)
+            };
+            dtcs.push(dtc);
+        }
+        Ok(dtcs)
+    }
+
+    /// Clears DTCs stored in the ECU
+    ///
+    /// ## Arguments
+    /// * group - The group of DTCs to clear. 0xFF00 clears all DTCs
+    fn clear_diagnostic_information(&mut self, group: u16) -> ProtocolResult<()> {
+        let [hi, lo] = group.to_be_bytes();
+        self.send_command_with_response(&[KwpService::ClearDiagnosticInformation as u8, hi, lo]).map(|_| ())
+    }
+
+    /// Requests a security access seed from the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level to request the seed for. This is always an odd number below 0x7F
+    ///
+    /// ## Returns
+    /// The seed. A seed of all zeros means the level is already unlocked
+    fn security_access_request_seed(&mut self, level: u8) -> ProtocolResult<Vec<u8>> {
+        key_level(level)?;
+        let res = self.send_command_with_response(&[KwpService::SecurityAccess as u8, level])?;
+        strip_echo(&res, &[level]).map(|d| d.to_vec())
+    }
+
+    /// Sends the key computed from a seed to the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level the seed was requested for. The key is sent with level + 1
+    /// * key - The key
+    fn security_access_send_key(&mut self, level: u8, key: &[u8]) -> ProtocolResult<()> {
+        let key_level = key_level(level)?;
+        let mut req = vec![KwpService::SecurityAccess as u8, key_level];
+        req.extend_from_slice(key);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[key_level]).map(|_| ())
+    }
+
+    /// Starts a routine in the ECU
+    ///
+    /// ## Returns
+    /// The routine entry status returned by the ECU
+    fn start_routine_by_local_id(&mut self, routine_id: u8, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![KwpService::StartRoutineByLocalIdentifier as u8, routine_id];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[routine_id]).map(|d| d.to_vec())
+    }
+
+    /// Stops a running routine in the ECU
+    ///
+    /// ## Returns
+    /// The routine exit status returned by the ECU
+    fn stop_routine_by_local_id(&mut self, routine_id: u8, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![KwpService::StopRoutineByLocalIdentifier as u8, routine_id];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[routine_id]).map(|d| d.to_vec())
+    }
+
+    /// Requests the results of a routine from the ECU
+    fn request_routine_results_by_local_id(&mut self, routine_id: u8) -> ProtocolResult<Vec<u8>> {
+        let res = self.send_command_with_response(&[KwpService::RequestRoutineResultsByLocalIdentifier as u8, routine_id])?;
+        strip_echo(&res, &[routine_id]).map(|d| d.to_vec())
+    }
+
+    /// Controls an input or output of the ECU
+    ///
+    /// ## Arguments
+    /// * local_id - The local identifier of the input or output
+    /// * param - How to control the input or output
+    /// * state - The state to set. This is only used for adjustments
+    ///
+    /// ## Returns
+    /// The current state of the input or output, as reported by the ECU
+    fn io_control_by_local_id(&mut self, local_id: u8, param: KwpIoControlParameter, state: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![KwpService::InputOutputControlByLocalIdentifier as u8, local_id, param.id()];
+        req.extend_from_slice(state);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[local_id, param.id()]).map(|d| d.to_vec())
+    }
+
+    /// Tells the ECU that the tester is still connected, so it stays in its current session
+    ///
+    /// ## Arguments
+    /// * response_required - If false, the ECU is told not to respond
+    fn tester_present(&mut self, response_required: bool) -> ProtocolResult<()> {
+        if response_required {
+            self.send_command_with_response(&[KwpService::TesterPresent as u8, 0x01]).map(|_| ())
+        } else {
+            self.send_command(&[KwpService::TesterPresent as u8, 0x02])
+        }
+    }
+}
+
+/// KWP2000 client for a single ECU
+#[derive(Debug, Clone)]
+pub struct Kwp2000Client<A: AdapterHardware> {
+    engine: RequestEngine<A>
+}
+
+impl<A: AdapterHardware> Kwp2000Client<A> {
+    /// Creates a new KWP2000 client
+    ///
+    /// ## Arguments
+    /// * channel - The channel the ECU is connected to
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * rx_id - The CAN ID the ECU responds with
+    pub fn new(channel: DiagChannel<A>, tx_id: u32, rx_id: u32) -> ProtocolResult<Self> {
+        Ok(Self { engine: RequestEngine::new(channel, tx_id, rx_id, DiagProtocol::KWP2000)? })
+    }
+
+    /// Creates a KWP2000 client from an existing request engine. Clones of the engine share the ECU
+    pub fn from_engine(engine: RequestEngine<A>) -> Self {
+        Self { engine }
+    }
+
+    pub fn engine(&self) -> &RequestEngine<A> {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut RequestEngine<A> {
+        &mut self.engine
+    }
+}
+
+impl<A: AdapterHardware> GenericProtocolServer for Kwp2000Client<A> {
+    fn send_command_with_response(&mut self, send: &[u8]) -> ProtocolResult<Vec<u8>> {
+        self.engine.send_request(send)
+    }
+
+    fn send_command(&mut self, send: &[u8]) -> ProtocolResult<()> {
+        self.engine.send_request_no_response(send)
+    }
+
+    fn read_dtcs(&mut self) -> ProtocolResult<Vec<DTC>> {
+        Ok(self.read_dtc_by_status(0xFF00)?.into_iter().map(DTC::from).collect())
+    }
+}
+
+impl<A: AdapterHardware> Kwp2000Server for Kwp2000Client<A> {}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::dtc::EnvironmentField;
+    use hardware::{AdapterChannel, SimAdapter};
+
+    fn sim_ecu(_: AdapterChannel, id: u32, req: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        if id != 0x07E0 {
+            return Vec::new();
+        }
+        let res = match req {
+            [0x10, session] => vec![0x50, *session],
+            [0x1A, 0x86] => vec![0x5A, 0x86, 0x21, 0x14, 0x46, 0x05, 0x40],
+            [0x18, 0x02, 0xFF, 0x00] => vec![0x58, 0x02, 0x90, 0x01, 0xE0, 0x10, 0x05, 0x20],
+            [0x17, 0x90, 0x01] => vec![0x7F, 0x17, 0x31],
+            [0x17, hi, lo] => vec![0x57, 0x01, *hi, *lo, 0xE0, 0x03, 0x00, 0x30, 0x39],
+            [0x21, lid] => vec![0x7F, 0x21, 0x31, *lid],
+            [0x30, lid, param, state @ ..] => [&[0x70, *lid, *param], state].concat(),
+            [0x3E, 0x02] => return Vec::new(),
+            _ => vec![0x7F, req[0], 0x11]
+        };
+        vec![(0x07E8, res)]
+    }
+
+    fn client() -> Kwp2000Client<SimAdapter> {
+        let mut sim = SimAdapter::new(sim_ecu);
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        Kwp2000Client::new(channel, 0x07E0, 0x07E8).unwrap()
+    }
+
+    #[test]
+    pub fn test_kwp_services() {
+        let mut kwp = client();
+        kwp.start_diagnostic_session(KwpSession::ExtendedDiagnostics).unwrap();
+        assert_eq!(vec![0x21, 0x14, 0x46, 0x05, 0x40], kwp.read_ecu_identification(0x86).unwrap());
+        assert_eq!(vec![0x05], kwp.io_control_by_local_id(0x10, KwpIoControlParameter::ShortTermAdjustment, &[0x05]).unwrap());
+        kwp.tester_present(false).unwrap();
+        assert!(matches!(kwp.read_data_by_local_id(0xE0), Err(ProtocolError::ECUError { code: 0x31, .. })));
+        assert!(matches!(kwp.ecu_reset(KwpResetMode::PowerOn), Err(ProtocolError::ECUError { code: 0x11, .. })));
+    }
+
+    #[test]
+    pub fn test_kwp_dtcs() {
+        let mut kwp = client();
+        let records = kwp.read_dtc_by_status(0xFF00).unwrap();
+        assert_eq!(vec![KwpDtcRecord { code: 0x9001, status: 0xE0 }, KwpDtcRecord { code: 0x1005, status: 0x20 }], records);
+        let dtcs = kwp.read_dtcs().unwrap();
+        assert_eq!("B1001", dtcs[0].code);
+        assert_eq!("P1005", dtcs[1].code);
+        assert!(dtcs[0].mil_on && matches!(dtcs[0].state, DTCState::Active));
+        assert!(!dtcs[1].mil_on && matches!(dtcs[1].state, DTCState::Stored));
+
+        let layout = EnvironmentLayout {
+            frequency_counter: Some(EnvironmentField { record_number: None, offset: 0, len: 1 }),
+            last_occurrence_km: Some(EnvironmentField { record_number: None, offset: 1, len: 3 }),
+            ..Default::default()
+        };
+        let dtcs = kwp.read_dtcs_with_environment(Some(&layout)).unwrap();
+        assert!(dtcs[0].environment.is_none());
+        let env = dtcs[1].environment.as_ref().unwrap();
+        assert_eq!(vec![0x03, 0x00, 0x30, 0x39], env.raw);
+        assert_eq!(Some(3), env.frequency_counter);
+        assert_eq!(Some(12_345), env.last_occurrence_km);
+    }
+}
@@ -1,20 +1,56 @@
 pub mod uds;
 pub mod kwp2000;
+pub mod channel;
+pub mod request;
+pub mod nrc;
+pub mod session;
+pub mod security;
+pub mod dtc;
+pub mod obd2;
+pub mod detect;
+pub mod functional;
+
+use serde::{Deserialize, Serialize};
+
+pub use channel::DiagChannel;
+pub use request::{EcuTiming, RequestEngine};
 
 #[derive(Debug)]
 pub enum ProtocolError {
-    ECUError(u8),
+    /// The ECU responded negatively to a request
+    ECUError { code: u8, desc: String },
     ServerError(String),
-    DeviceError(hardware::HardwareError)
+    DeviceError(hardware::HardwareError),
+    /// The ECU sent a positive response which could not be decoded
+    InvalidResponse(Vec<u8>),
+    /// Security access is locked out for the remaining time, after too many invalid keys
+    SecurityLockedOut(std::time::Duration)
 }
 
-impl Into<ProtocolError> for hardware::HardwareError {
-    fn into(self) -> ProtocolError {
-        ProtocolError::DeviceError(self)
+/// Diagnostic protocols spoken by ECUs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DiagProtocol {
+    /// KWP2000 (ISO 14230-3)
+    KWP2000,
+    /// UDS (ISO 14229)
+    UDS
+}
+
+impl ProtocolError {
+    /// Returns true if the ECU did not respond in time
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ProtocolError::DeviceError(hardware::HardwareError::Timeout))
     }
 }
 
-#[derive(Debug, Clone)]
+// Allows for the '?' operator on hardware results
+impl From<hardware::HardwareError> for ProtocolError {
+    fn from(e: hardware::HardwareError) -> Self {
+        ProtocolError::DeviceError(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DTCState {
     None,
     Stored,
@@ -24,9 +60,87 @@ pub enum DTCState {
 
 #[derive(Debug, Clone)]
 pub struct DTC {
-    code: String,
-    state: DTCState,
-    mil_on: bool
+    pub code: String,
+    pub state: DTCState,
+    pub mil_on: bool,
+    /// Environment data, if it was read
+    pub environment: Option<dtc::DtcEnvironment>
+}
+
+/// Part of a record which is copied into a dynamically defined identifier
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DynamicIdentifierItem {
+    /// The identifier to copy from (KWP2000 local identifier or UDS data identifier)
+    pub source_id: u16,
+    /// Position of the first byte to copy within the source record (Starting at 1)
+    pub position: u8,
+    /// Number of bytes to copy
+    pub size: u8
+}
+
+/// Rates at which an ECU can transmit data periodically. The actual rates are defined by the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PeriodicRate {
+    Slow,
+    Medium,
+    Fast
+}
+
+/// How to control an output of an ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoControlAction {
+    /// Sets the output to a state until control is returned. The state is followed by the control
+    /// enable mask if the ECU requires one
+    ShortTermAdjustment(Vec<u8>),
+    /// Holds the output in its current state
+    FreezeCurrentState,
+    /// Sets the output to its default state
+    ResetToDefault
+}
+
+/// Number of bytes used to encode the address and size of a memory area in a request
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryAddressFormat {
+    /// Bytes of the memory address (1 - 4)
+    pub address_len: u8,
+    /// Bytes of the memory size (1 - 4)
+    pub size_len: u8
+}
+
+impl MemoryAddressFormat {
+    pub const fn new(address_len: u8, size_len: u8) -> Self {
+        Self { address_len, size_len }
+    }
+
+    /// The largest size which can be encoded
+    pub fn max_size(&self) -> u32 {
+        match self.size_len {
+            0 => 0,
+            1..=3 => (1 << (8 * self.size_len as u32)) - 1,
+            _ => u32::MAX
+        }
+    }
+
+    /// The addressAndLengthFormatIdentifier byte used by UDS
+    pub fn format_identifier(&self) -> u8 {
+        (self.size_len << 4) | (self.address_len & 0x0F)
+    }
+
+    /// Encodes an address followed by a size, both big endian
+    pub fn encode(&self, address: u32, size: u32) -> ProtocolResult<Vec<u8>> {
+        if !(1..=4).contains(&self.address_len) || !(1..=4).contains(&self.size_len) {
+            return Err(ProtocolError::ServerError(format!("Invalid memory address format {:?}", self)));
+        }
+        if self.address_len < 4 && address >> (8 * self.address_len as u32) != 0 {
+            return Err(ProtocolError::ServerError(format!("Address 0x{:08X} does not fit in {} bytes", address, self.address_len)));
+        }
+        if size > self.max_size() {
+            return Err(ProtocolError::ServerError(format!("Size {} does not fit in {} bytes", size, self.size_len)));
+        }
+        let mut bytes = address.to_be_bytes()[4 - self.address_len as usize..].to_vec();
+        bytes.extend_from_slice(&size.to_be_bytes()[4 - self.size_len as usize..]);
+        Ok(bytes)
+    }
 }
 
 pub type ProtocolResult<T> = std::result::Result<T, ProtocolError>;
@@ -39,4 +153,67 @@ pub trait GenericProtocolServer {
     fn read_dtcs(&mut self) -> ProtocolResult<Vec<DTC>>;
 
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hardware::fault_injection::{FaultConfig, FaultInjector};
+    use hardware::{AdapterChannel, AdapterHardware, SimAdapter};
+
+    /// Opens a 500kbps diagnostic channel on a simulated adapter
+    ///
+    /// ## Arguments
+    /// * handler - Returns the frames the vehicle responds to each request with, see [SimAdapter::new]
+    pub fn sim_channel<F>(handler: F) -> (SimAdapter, DiagChannel<SimAdapter>)
+    where F: Fn(AdapterChannel, u32, &[u8]) -> Vec<(u32, Vec<u8>)> + Send + Sync + 'static {
+        let mut sim = SimAdapter::new(handler);
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim.clone(), 500_000, &[]).unwrap();
+        (sim, channel)
+    }
+
+    /// CAN IDs of a simulated ECU. KWP2000 ECUs are addressed with 0x07E0 / 0x07E8, UDS ECUs with 0x0744 / 0x04C4
+    fn sim_ecu_ids(protocol: DiagProtocol) -> (u32, u32) {
+        match protocol {
+            DiagProtocol::KWP2000 => (0x07E0, 0x07E8),
+            DiagProtocol::UDS => (0x0744, 0x04C4)
+        }
+    }
+
+    fn sim_ecu<F>(protocol: DiagProtocol, responder: F) -> SimAdapter
+    where F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static {
+        let (tx_id, rx_id) = sim_ecu_ids(protocol);
+        SimAdapter::new(move |_, id, req| {
+            let res = responder(req);
+            if id != tx_id || res.is_empty() { Vec::new() } else { vec![(rx_id, res)] }
+        })
+    }
+
+    /// Creates a request engine for a simulated ECU, see [sim_ecu_ids]
+    ///
+    /// ## Arguments
+    /// * protocol - The protocol of the ECU
+    /// * responder - Returns the response of the ECU to a request. The ECU does not respond if it is empty
+    pub fn sim_engine<F>(protocol: DiagProtocol, responder: F) -> (SimAdapter, RequestEngine<SimAdapter>)
+    where F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static {
+        let (tx_id, rx_id) = sim_ecu_ids(protocol);
+        let mut sim = sim_ecu(protocol, responder);
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim.clone(), 500_000, &[]).unwrap();
+        (sim, RequestEngine::new(channel, tx_id, rx_id, protocol).unwrap())
+    }
+
+    /// Like [sim_engine], but the ECU is connected through a [FaultInjector]. Faults are only injected
+    /// once the channel is open
+    pub fn faulty_sim_engine<F>(protocol: DiagProtocol, config: FaultConfig, responder: F) -> (FaultInjector<SimAdapter>, RequestEngine<FaultInjector<SimAdapter>>)
+    where F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static {
+        let (tx_id, rx_id) = sim_ecu_ids(protocol);
+        let mut adapter = FaultInjector::new(sim_ecu(protocol, responder), FaultConfig::default());
+        adapter.open_device().unwrap();
+        let channel = DiagChannel::open(adapter.clone(), 500_000, &[]).unwrap();
+        let engine = RequestEngine::new(channel, tx_id, rx_id, protocol).unwrap();
+        adapter.set_config(config);
+        (adapter, engine)
+    }
+}
@@ -0,0 +1,138 @@
+//! Negative response codes (NRCs) of KWP2000 (ISO 14230-3) and UDS (ISO 14229)
+
+use super::DiagProtocol;
+
+/// The ECU does not support the service
+pub const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+/// The ECU does not support the sub-function of the service
+pub const NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+/// The ECU cannot process the request right now, and the request should be repeated
+pub const NRC_BUSY_REPEAT_REQUEST: u8 = 0x21;
+/// The ECU is not in the correct state to process the request
+pub const NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+/// The routine has not finished yet, so its results are not available (KWP2000)
+pub const NRC_ROUTINE_NOT_COMPLETE: u8 = 0x23;
+/// The request was sent out of order
+pub const NRC_REQUEST_SEQUENCE_ERROR: u8 = 0x24;
+/// A parameter of the request is not supported
+pub const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+/// The request requires security access to be granted first
+pub const NRC_SECURITY_ACCESS_DENIED: u8 = 0x33;
+/// The key sent for security access was wrong
+pub const NRC_INVALID_KEY: u8 = 0x35;
+/// Too many wrong keys were sent for security access
+pub const NRC_EXCEEDED_NUMBER_OF_ATTEMPTS: u8 = 0x36;
+/// Security access was requested again before the lockout time expired
+pub const NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED: u8 = 0x37;
+/// The request was received, but the ECU needs more time (Up to P2*) to respond
+pub const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+/// Returns a description of a KWP2000 negative response code
+pub fn get_kwp_nrc_description(code: u8) -> &'static str {
+    match code {
+        0x10 => "General reject",
+        0x11 => "Service not supported",
+        0x12 => "Sub-function not supported or invalid format",
+        0x21 => "Busy, repeat request",
+        0x22 => "Conditions not correct or request sequence error",
+        0x23 => "Routine not complete",
+        0x31 => "Request out of range",
+        0x33 => "Security access denied",
+        0x35 => "Invalid key",
+        0x36 => "Exceeded number of attempts",
+        0x37 => "Required time delay not expired",
+        0x40 => "Download not accepted",
+        0x41 => "Improper download type",
+        0x42 => "Cannot download to specified address",
+        0x43 => "Cannot download number of bytes requested",
+        0x50 => "Upload not accepted",
+        0x51 => "Improper upload type",
+        0x52 => "Cannot upload from specified address",
+        0x53 => "Cannot upload number of bytes requested",
+        0x71 => "Transfer suspended",
+        0x72 => "Transfer aborted",
+        0x74 => "Illegal address in block transfer",
+        0x75 => "Illegal byte count in block transfer",
+        0x76 => "Illegal block transfer type",
+        0x77 => "Block transfer data checksum error",
+        0x78 => "Request correctly received, response pending",
+        0x79 => "Incorrect byte count during block transfer",
+        0x80 => "Service not supported in active diagnostic session",
+        0x90..=0xF9 => "Vehicle manufacturer specific",
+        0xFA..=0xFE => "System supplier specific",
+        _ => "Reserved"
+    }
+}
+
+/// Returns a description of a UDS negative response code
+pub fn get_uds_nrc_description(code: u8) -> &'static str {
+    match code {
+        0x10 => "General reject",
+        0x11 => "Service not supported",
+        0x12 => "Sub-function not supported",
+        0x13 => "Incorrect message length or invalid format",
+        0x14 => "Response too long",
+        0x21 => "Busy, repeat request",
+        0x22 => "Conditions not correct",
+        0x24 => "Request sequence error",
+        0x25 => "No response from sub-net component",
+        0x26 => "Failure prevents execution of requested action",
+        0x31 => "Request out of range",
+        0x33 => "Security access denied",
+        0x35 => "Invalid key",
+        0x36 => "Exceeded number of attempts",
+        0x37 => "Required time delay not expired",
+        0x70 => "Upload/download not accepted",
+        0x71 => "Transfer data suspended",
+        0x72 => "General programming failure",
+        0x73 => "Wrong block sequence counter",
+        0x78 => "Request correctly received, response pending",
+        0x7E => "Sub-function not supported in active session",
+        0x7F => "Service not supported in active session",
+        0x81 => "RPM too high",
+        0x82 => "RPM too low",
+        0x83 => "Engine is running",
+        0x84 => "Engine is not running",
+        0x85 => "Engine run time too low",
+        0x86 => "Temperature too high",
+        0x87 => "Temperature too low",
+        0x88 => "Vehicle speed too high",
+        0x89 => "Vehicle speed too low",
+        0x8A => "Throttle/pedal too high",
+        0x8B => "Throttle/pedal too low",
+        0x8C => "Transmission range not in neutral",
+        0x8D => "Transmission range not in gear",
+        0x8F => "Brake switch(es) not closed",
+        0x90 => "Shifter lever not in park",
+        0x91 => "Torque converter clutch locked",
+        0x92 => "Voltage too high",
+        0x93 => "Voltage too low",
+        0xF0..=0xFE => "Vehicle manufacturer specific condition not correct",
+        _ => "ISO SAE reserved"
+    }
+}
+
+/// Returns a description of a negative response code
+///
+/// ## Arguments
+/// * protocol - The protocol the code was received with, as the tables differ slightly
+/// * code - The negative response code
+pub fn get_nrc_description(protocol: DiagProtocol, code: u8) -> &'static str {
+    match protocol {
+        DiagProtocol::KWP2000 => get_kwp_nrc_description(code),
+        DiagProtocol::UDS => get_uds_nrc_description(code)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_nrc_tables() {
+        assert_eq!("Service not supported in active diagnostic session", get_nrc_description(DiagProtocol::KWP2000, 0x80));
+        assert_eq!("Reserved", get_nrc_description(DiagProtocol::KWP2000, 0x7E));
+        assert_eq!("Sub-function not supported in active session", get_nrc_description(DiagProtocol::UDS, 0x7E));
+        assert_eq!("Vehicle manufacturer specific", get_kwp_nrc_description(0xA0));
+    }
+}
@@ -0,0 +1,588 @@
+//! Generic OBD-II (SAE J1979) client
+//!
+//! OBD-II is supported by the emissions related ECUs of almost every car, so it works as a fallback when the
+//! manufacturer protocol of a vehicle is not known. Requests are sent to all emissions related ECUs at once, and
+//! every ECU which supports the request responds. Responses are therefore returned per ECU, keyed by the CAN ID
+//! of the ECU (ISO 15765-4) or its K-Line source address (ISO 9141-2 / ISO 14230-4).
+
+pub mod pids;
+
+use std::collections::BTreeMap;
+
+use hardware::data_structures::{HwDataFrame, HwKwpFrame, HwObdFrame};
+use hardware::{AdapterChannel, AdapterFilter, AdapterHardware, HardwareError, LinInitType};
+use logger::Logger;
+
+use self::pids::{decode_pid, decode_supported_pids, PidValue};
+use super::dtc::format_dtc;
+use super::functional::{collect_responses, FunctionalAddressing, FunctionalClient};
+use super::request::NEGATIVE_RESPONSE_SID;
+use super::{DiagChannel, DTCState, ProtocolError, ProtocolResult, DTC};
+
+/// Bitrate of OBD K-Line
+pub const KLINE_BAUD: u32 = 10_400;
+
+/// K-Line address of the OBD ECUs (Functional)
+const KLINE_FUNCTIONAL_ADDRESS: u8 = 0x33;
+
+/// K-Line address of the tester
+const KLINE_TESTER_ADDRESS: u8 = 0xF1;
+
+/// OBD-II modes (Services)
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObdMode {
+    ShowCurrentData = 0x01,
+    ShowFreezeFrameData = 0x02,
+    ShowStoredDtcs = 0x03,
+    ClearDtcs = 0x04,
+    OxygenSensorMonitoring = 0x05,
+    OnBoardMonitoring = 0x06,
+    ShowPendingDtcs = 0x07,
+    ControlOnBoardSystem = 0x08,
+    RequestVehicleInformation = 0x09,
+    ShowPermanentDtcs = 0x0A
+}
+
+/// Vehicle information types of mode 09
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObdInfoType {
+    Vin = 0x02,
+    CalibrationId = 0x04,
+    CalibrationVerificationNumber = 0x06,
+    EcuName = 0x0A
+}
+
+/// CAN addressing of ISO 15765-4
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObdCanAddressing {
+    /// Requests are sent to 0x7DF, and ECUs respond with 0x7E8 - 0x7EF
+    Standard11Bit,
+    /// Requests are sent to 0x18DB33F1, and ECUs respond with 0x18DAF1xx. Only ECU addresses
+    /// 0x10 - 0x17 are listened to
+    Extended29Bit
+}
+
+impl ObdCanAddressing {
+    pub fn functional_addressing(&self) -> FunctionalAddressing {
+        match self {
+            ObdCanAddressing::Standard11Bit => FunctionalAddressing::obd_11bit(),
+            ObdCanAddressing::Extended29Bit => FunctionalAddressing::obd_29bit()
+        }
+    }
+}
+
+/// K-Line standards of OBD-II
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KLineStandard {
+    /// ISO 9141-2, 5 baud initialization
+    Iso9141,
+    /// ISO 14230-4 (KWP2000), 5 baud initialization
+    Iso14230SlowInit,
+    /// ISO 14230-4 (KWP2000), fast initialization
+    Iso14230FastInit
+}
+
+impl KLineStandard {
+    fn channel_type(&self) -> AdapterChannel {
+        match self {
+            KLineStandard::Iso9141 => AdapterChannel::Obd,
+            _ => AdapterChannel::Kwp
+        }
+    }
+
+    /// Adds the header to a request
+    fn add_header(&self, request: &[u8]) -> Vec<u8> {
+        let header = match self {
+            KLineStandard::Iso9141 => [0x68, 0x6A, KLINE_TESTER_ADDRESS],
+            _ => [0xC0 | request.len() as u8, KLINE_FUNCTIONAL_ADDRESS, KLINE_TESTER_ADDRESS]
+        };
+        [&header[..], request].concat()
+    }
+
+    /// Removes the header from a response
+    ///
+    /// ## Returns
+    /// The source address of the ECU and the response, or None if the message is not an OBD response
+    fn strip_header(&self, msg: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let payload = match self {
+            KLineStandard::Iso9141 => msg.get(3..)?,
+            _ => {
+                // ISO 14230 uses a separate length byte if the length does not fit into the format byte
+                let fmt_len = (msg.first()? & 0x3F) as usize;
+                let (len, start) = if fmt_len == 0 { (*msg.get(3)? as usize, 4) } else { (fmt_len, 3) };
+                msg.get(start..start + len)?
+            }
+        };
+        Some((msg[2], payload.to_vec()))
+    }
+}
+
+/// DTC memories of OBD-II
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObdDtcKind {
+    /// Confirmed DTCs (Mode 03)
+    Stored,
+    /// DTCs detected during the current or last drive cycle (Mode 07)
+    Pending,
+    /// DTCs which cannot be cleared with mode 04 (Mode 0A)
+    Permanent
+}
+
+impl ObdDtcKind {
+    fn mode(&self) -> ObdMode {
+        match self {
+            ObdDtcKind::Stored => ObdMode::ShowStoredDtcs,
+            ObdDtcKind::Pending => ObdMode::ShowPendingDtcs,
+            ObdDtcKind::Permanent => ObdMode::ShowPermanentDtcs
+        }
+    }
+}
+
+/// Positive response of an ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObdResponse {
+    /// CAN ID or K-Line source address of the ECU
+    pub ecu: u32,
+    /// The response, including the response service ID
+    pub data: Vec<u8>
+}
+
+/// A PID read from an ECU
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidReading {
+    /// CAN ID or K-Line source address of the ECU
+    pub ecu: u32,
+    pub pid: u8,
+    pub raw: Vec<u8>,
+    /// The decoded values, or empty if the PID is not a known standard PID
+    pub values: Vec<PidValue>
+}
+
+/// Result of an on-board monitoring test (Mode 06)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MonitorTestResult {
+    /// On-board monitor ID (CAN only)
+    pub monitor_id: Option<u8>,
+    pub test_id: u8,
+    /// Unit and scaling ID of the values (CAN only)
+    pub unit_scaling_id: Option<u8>,
+    /// Component ID (K-Line only)
+    pub component_id: Option<u8>,
+    pub value: u16,
+    pub min: Option<u16>,
+    pub max: Option<u16>
+}
+
+impl MonitorTestResult {
+    /// Returns true if the test value is within its limits
+    pub fn passed(&self) -> bool {
+        self.min.is_none_or(|min| self.value >= min) && self.max.is_none_or(|max| self.value <= max)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ObdLink<A: AdapterHardware> {
+    Can(FunctionalClient<A>),
+    KLine { adapter: A, channel_id: u32, standard: KLineStandard }
+}
+
+/// OBD-II client. Requests are sent to all OBD ECUs of the vehicle at once
+#[derive(Debug, Clone)]
+pub struct Obd2Client<A: AdapterHardware> {
+    link: ObdLink<A>,
+    timeout_ms: u128,
+    logger: Logger
+}
+
+impl<A: AdapterHardware> Obd2Client<A> {
+    /// Creates an OBD-II client on CAN (ISO 15765-4)
+    ///
+    /// ## Arguments
+    /// * channel - The diagnostic channel. It must use the bitrate of the OBD CAN bus (250 or 500kbps)
+    /// * addressing - 11 or 29 bit addressing
+    pub fn open_can(channel: DiagChannel<A>, addressing: ObdCanAddressing) -> ProtocolResult<Self> {
+        let mut client = FunctionalClient::open(channel, addressing.functional_addressing())?;
+        client.set_timeout(100);
+        Ok(Self {
+            link: ObdLink::Can(client),
+            timeout_ms: 100,
+            logger: Logger::new("OBD")
+        })
+    }
+
+    /// Creates an OBD-II client on K-Line, and initializes the bus
+    ///
+    /// ## Arguments
+    /// * adapter - The adapter to communicate with. It must already be open
+    /// * standard - The K-Line standard used by the vehicle
+    ///
+    /// ## Returns
+    /// The client, along with the key bytes the ECUs responded to the initialization with
+    pub fn open_kline(mut adapter: A, standard: KLineStandard) -> ProtocolResult<(Self, Vec<u8>)> {
+        let channel_id = adapter.open_channel(standard.channel_type())?;
+        adapter.add_channel_filter(channel_id, AdapterFilter::Pass { mask: 0, id: 0 }, KLINE_BAUD, &[])?;
+        let mut init = match standard {
+            KLineStandard::Iso14230FastInit => LinInitType::FastInit { id: 0, data: standard.add_header(&[0x81]) },
+            _ => LinInitType::FiveBaudInit(vec![KLINE_FUNCTIONAL_ADDRESS])
+        };
+        adapter.channel_lin_init(channel_id, &mut init)?;
+        let key_bytes = match init {
+            LinInitType::FastInit { data, .. } => data,
+            LinInitType::FiveBaudInit(data) => data
+        };
+        let client = Self {
+            link: ObdLink::KLine { adapter, channel_id, standard },
+            // ISO 9141-2 and ISO 14230-4 allow the ECUs up to P2 max (50ms) between each response
+            timeout_ms: 100,
+            logger: Logger::new("OBD")
+        };
+        Ok((client, key_bytes))
+    }
+
+    /// Sets the time to wait for ECUs to respond (In ms). Collection of responses ends once no ECU has
+    /// responded for this long
+    pub fn set_timeout(&mut self, timeout_ms: u128) {
+        self.timeout_ms = timeout_ms;
+        if let ObdLink::Can(client) = &mut self.link {
+            client.set_timeout(timeout_ms);
+        }
+    }
+
+    /// Stops listening for OBD responses. On K-Line, the channel is closed
+    pub fn close(&mut self) -> ProtocolResult<()> {
+        match &mut self.link {
+            ObdLink::Can(client) => client.close()?,
+            ObdLink::KLine { adapter, channel_id, .. } => adapter.close_channel(*channel_id)?
+        }
+        Ok(())
+    }
+
+    /// Returns true if the client communicates over CAN
+    pub fn is_can(&self) -> bool {
+        matches!(self.link, ObdLink::Can(_))
+    }
+
+    /// Sends a request to all OBD ECUs, and collects their positive responses
+    ///
+    /// ## Returns
+    /// The positive responses. If no ECU responded positively, the first negative response is
+    /// returned as [ProtocolError::ECUError], or [HardwareError::Timeout] if no ECU responded at all
+    pub fn request(&mut self, request: &[u8]) -> ProtocolResult<Vec<ObdResponse>> {
+        let sid = *request.first().ok_or_else(|| ProtocolError::ServerError("Request is empty".into()))?;
+        self.logger.log_debug(format!("Request: {:02X?}", request));
+        let messages = match &mut self.link {
+            ObdLink::Can(client) => client.request(request)?.into_iter().map(|r| (r.rx_id, r.data)).collect::<Vec<_>>(),
+            ObdLink::KLine { adapter, standard, .. } => {
+                let standard = *standard;
+                let msg = standard.add_header(request);
+                let received = match standard.channel_type() {
+                    AdapterChannel::Obd => kline_exchange::<A, HwObdFrame>(adapter, &msg, sid, self.timeout_ms)?,
+                    _ => kline_exchange::<A, HwKwpFrame>(adapter, &msg, sid, self.timeout_ms)?
+                };
+                received.iter().filter_map(|m| standard.strip_header(m)).map(|(src, data)| (src as u32, data)).collect()
+            }
+        };
+
+        let mut negative = None;
+        let mut responses = Vec::new();
+        for (ecu, data) in messages {
+            self.logger.log_debug(format!("Response from 0x{:04X}: {:02X?}", ecu, data));
+            match data.as_slice() {
+                [NEGATIVE_RESPONSE_SID, _, code, ..] => {
+                    negative.get_or_insert(*code);
+                },
+                [res_sid, ..] if Some(*res_sid) == sid.checked_add(0x40) => responses.push(ObdResponse { ecu, data }),
+                _ => {}
+            }
+        }
+        match (responses.is_empty(), negative) {
+            (false, _) => Ok(responses),
+            (true, Some(code)) => Err(ProtocolError::ECUError { code, desc: super::nrc::get_kwp_nrc_description(code).into() }),
+            (true, None) => Err(HardwareError::Timeout.into())
+        }
+    }
+
+    /// Reads which PIDs (Or test/info IDs) of a mode each ECU supports
+    ///
+    /// ## Arguments
+    /// * mode - One of [ObdMode::ShowCurrentData], [ObdMode::ShowFreezeFrameData] (Frame 0),
+    ///   [ObdMode::OnBoardMonitoring] (CAN only) or [ObdMode::RequestVehicleInformation]
+    pub fn supported_pids(&mut self, mode: ObdMode) -> ProtocolResult<BTreeMap<u32, Vec<u8>>> {
+        let mut supported: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let mut base = 0x00u8;
+        loop {
+            let (request, skip) = match mode {
+                ObdMode::ShowFreezeFrameData => (vec![mode as u8, base, 0x00], 3),
+                _ => (vec![mode as u8, base], 2)
+            };
+            let responses = match self.request(&request) {
+                Ok(r) => r,
+                // Nothing supported beyond the first range
+                Err(_) if base != 0 => break,
+                Err(e) => return Err(e)
+            };
+            for res in responses {
+                let pids = decode_supported_pids(base, res.data.get(skip..).unwrap_or_default());
+                supported.entry(res.ecu).or_default().extend(pids);
+            }
+            let next = match base.checked_add(0x20) {
+                Some(n) => n,
+                None => break
+            };
+            if !supported.values().any(|p| p.contains(&next)) {
+                break;
+            }
+            base = next;
+        }
+        Ok(supported)
+    }
+
+    /// Reads the current value of a PID (Mode 01)
+    pub fn read_pid(&mut self, pid: u8) -> ProtocolResult<Vec<PidReading>> {
+        let res = self.request(&[ObdMode::ShowCurrentData as u8, pid])?;
+        Ok(res.into_iter()
+            .filter(|r| r.data.get(1) == Some(&pid))
+            .map(|r| pid_reading(r.ecu, pid, r.data.get(2..).unwrap_or_default()))
+            .collect())
+    }
+
+    /// Reads the value of a PID stored in a freeze frame (Mode 02)
+    ///
+    /// ## Arguments
+    /// * pid - The PID to read. PID 0x02 returns the DTC which caused the freeze frame
+    /// * frame - The freeze frame number. Most ECUs only store frame 0
+    pub fn read_freeze_frame_pid(&mut self, pid: u8, frame: u8) -> ProtocolResult<Vec<PidReading>> {
+        let res = self.request(&[ObdMode::ShowFreezeFrameData as u8, pid, frame])?;
+        Ok(res.into_iter().filter(|r| r.data.len() >= 3 && r.data[1] == pid).map(|r| pid_reading(r.ecu, pid, &r.data[3..])).collect())
+    }
+
+    /// Reads DTCs from all ECUs (Modes 03, 07 and 0A)
+    pub fn read_dtcs(&mut self, kind: ObdDtcKind) -> ProtocolResult<BTreeMap<u32, Vec<DTC>>> {
+        let is_can = self.is_can();
+        let state = match kind {
+            ObdDtcKind::Pending => DTCState::Pending,
+            _ => DTCState::Stored
+        };
+        let mut dtcs: BTreeMap<u32, Vec<DTC>> = BTreeMap::new();
+        for res in self.request(&[kind.mode() as u8])? {
+            // CAN responses start with the number of DTCs, K-Line responses hold 3 DTCs padded with 0x0000
+            let codes = if is_can { res.data.get(2..).unwrap_or_default() } else { &res.data[1..] };
+            let entry = dtcs.entry(res.ecu).or_default();
+            for code in codes.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])) {
+                if code != 0 || is_can {
+                    entry.push(DTC { code: format_dtc(code), state, mil_on: false, environment: None });
+                }
+            }
+        }
+        Ok(dtcs)
+    }
+
+    /// Clears DTCs, freeze frames and monitor results of all ECUs (Mode 04)
+    ///
+    /// ## Returns
+    /// The ECUs which confirmed the clear
+    pub fn clear_dtcs(&mut self) -> ProtocolResult<Vec<u32>> {
+        Ok(self.request(&[ObdMode::ClearDtcs as u8])?.into_iter().map(|r| r.ecu).collect())
+    }
+
+    /// Reads an oxygen sensor monitoring test result (Mode 05, K-Line only)
+    ///
+    /// ## Arguments
+    /// * test_id - The test to read
+    /// * sensor - The oxygen sensor (Bank and sensor bits, as in PID 0x13)
+    pub fn read_oxygen_sensor_test(&mut self, test_id: u8, sensor: u8) -> ProtocolResult<BTreeMap<u32, Vec<u8>>> {
+        let res = self.request(&[ObdMode::OxygenSensorMonitoring as u8, test_id, sensor])?;
+        Ok(res.into_iter().map(|r| (r.ecu, r.data.get(3..).unwrap_or_default().to_vec())).collect())
+    }
+
+    /// Reads on-board monitoring test results (Mode 06)
+    ///
+    /// ## Arguments
+    /// * id - The monitor ID (CAN) or test ID (K-Line) to read
+    pub fn read_monitor_tests(&mut self, id: u8) -> ProtocolResult<BTreeMap<u32, Vec<MonitorTestResult>>> {
+        let is_can = self.is_can();
+        let mut results: BTreeMap<u32, Vec<MonitorTestResult>> = BTreeMap::new();
+        for res in self.request(&[ObdMode::OnBoardMonitoring as u8, id])? {
+            let entry = results.entry(res.ecu).or_default();
+            if is_can {
+                // MID, TID, unit and scaling ID, value, min, max
+                entry.extend(res.data[1..].chunks_exact(9).map(|r| MonitorTestResult {
+                    monitor_id: Some(r[0]),
+                    test_id: r[1],
+                    unit_scaling_id: Some(r[2]),
+                    component_id: None,
+                    value: u16::from_be_bytes([r[3], r[4]]),
+                    min: Some(u16::from_be_bytes([r[5], r[6]])),
+                    max: Some(u16::from_be_bytes([r[7], r[8]]))
+                }));
+            } else {
+                // TID, component ID, value, limit. Bit 7 of the component ID is set if the limit is a minimum
+                entry.extend(res.data[1..].chunks_exact(6).map(|r| {
+                    let limit = u16::from_be_bytes([r[4], r[5]]);
+                    let is_min = r[1] & 0x80 != 0;
+                    MonitorTestResult {
+                        monitor_id: None,
+                        test_id: r[0],
+                        unit_scaling_id: None,
+                        component_id: Some(r[1] & 0x7F),
+                        value: u16::from_be_bytes([r[2], r[3]]),
+                        min: if is_min { Some(limit) } else { None },
+                        max: if is_min { None } else { Some(limit) }
+                    }
+                }));
+            }
+        }
+        Ok(results)
+    }
+
+    /// Requests control of an on-board system, test or component (Mode 08)
+    ///
+    /// ## Returns
+    /// The response data of each ECU which accepted the request
+    pub fn control_on_board_system(&mut self, test_id: u8, data: &[u8]) -> ProtocolResult<BTreeMap<u32, Vec<u8>>> {
+        let res = self.request(&[&[ObdMode::ControlOnBoardSystem as u8, test_id], data].concat())?;
+        Ok(res.into_iter().map(|r| (r.ecu, r.data.get(2..).unwrap_or_default().to_vec())).collect())
+    }
+
+    /// Reads vehicle information (Mode 09)
+    ///
+    /// ## Returns
+    /// The information of each ECU. On K-Line, the messages of each ECU are joined together
+    pub fn read_vehicle_info(&mut self, info_type: u8) -> ProtocolResult<BTreeMap<u32, Vec<u8>>> {
+        let is_can = self.is_can();
+        let mut info: BTreeMap<u32, Vec<(u8, Vec<u8>)>> = BTreeMap::new();
+        for res in self.request(&[ObdMode::RequestVehicleInformation as u8, info_type])? {
+            // CAN: Number of data items, then the data. K-Line: Message sequence number, then 4 bytes of data
+            if let [_, _, seq, data @ ..] = res.data.as_slice() {
+                info.entry(res.ecu).or_default().push((if is_can { 0 } else { *seq }, data.to_vec()));
+            }
+        }
+        Ok(info.into_iter().map(|(ecu, mut msgs)| {
+            msgs.sort_by_key(|(seq, _)| *seq);
+            (ecu, msgs.into_iter().flat_map(|(_, d)| d).collect())
+        }).collect())
+    }
+
+    /// Reads the VIN. If multiple ECUs report a VIN, the VIN of the first ECU is returned
+    pub fn read_vin(&mut self) -> ProtocolResult<String> {
+        let info = self.read_vehicle_info(ObdInfoType::Vin as u8)?;
+        let (_, data) = info.into_iter().next().ok_or(ProtocolError::ServerError("No ECU reported a VIN".into()))?;
+        // K-Line VINs are padded to 20 bytes
+        let start = data.len().saturating_sub(17);
+        Ok(String::from_utf8_lossy(&data[start..]).into_owned())
+    }
+
+    /// Reads the calibration IDs of each ECU
+    pub fn read_calibration_ids(&mut self) -> ProtocolResult<BTreeMap<u32, Vec<String>>> {
+        let info = self.read_vehicle_info(ObdInfoType::CalibrationId as u8)?;
+        Ok(info.into_iter().map(|(ecu, data)| {
+            let ids = data.chunks(16).map(|c| String::from_utf8_lossy(c).trim_end_matches('\0').to_string()).collect();
+            (ecu, ids)
+        }).collect())
+    }
+
+    /// Reads the calibration verification numbers (Checksums of the calibrations) of each ECU
+    pub fn read_cvns(&mut self) -> ProtocolResult<BTreeMap<u32, Vec<u32>>> {
+        let info = self.read_vehicle_info(ObdInfoType::CalibrationVerificationNumber as u8)?;
+        Ok(info.into_iter().map(|(ecu, data)| {
+            (ecu, data.chunks_exact(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect())
+        }).collect())
+    }
+}
+
+fn pid_reading(ecu: u32, pid: u8, raw: &[u8]) -> PidReading {
+    PidReading { ecu, pid, raw: raw.to_vec(), values: decode_pid(pid, raw).unwrap_or_default() }
+}
+
+/// Sends a K-Line message and collects the raw messages received in response
+fn kline_exchange<A: AdapterHardware, T: HwDataFrame>(adapter: &mut A, msg: &[u8], sid: u8, timeout_ms: u128) -> ProtocolResult<Vec<Vec<u8>>> {
+    let mut frame = T::default();
+    frame.set_data(msg);
+    adapter.write_data(&[frame], 0)?;
+    let res = collect_responses(sid, timeout_ms, |timeout| {
+        let frames = adapter.read_data::<T>(16, timeout)?;
+        if frames.is_empty() {
+            return Err(HardwareError::Timeout.into());
+        }
+        // The source address is only known once the header is stripped
+        Ok(frames.iter().map(|f| (0, f.get_data().to_vec())).collect())
+    })?;
+    Ok(res.into_iter().map(|(_, data)| data).collect())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hardware::SimAdapter;
+
+    #[test]
+    pub fn test_obd_can() {
+        let mut sim = SimAdapter::new(|_, id, req| {
+            if id != 0x07DF {
+                return Vec::new();
+            }
+            match req {
+                [0x01, 0x00] => vec![(0x07E8, vec![0x41, 0x00, 0x98, 0x18, 0x00, 0x01]), (0x07E9, vec![0x41, 0x00, 0x80, 0x00, 0x00, 0x00])],
+                [0x01, 0x20] => vec![(0x07E8, vec![0x41, 0x20, 0x80, 0x00, 0x00, 0x00])],
+                [0x01, 0x0C] => vec![(0x07E8, vec![0x41, 0x0C, 0x1A, 0xF8]), (0x07E9, vec![0x7F, 0x01, 0x12])],
+                // Truncated response, and a response for the wrong PID
+                [0x01, 0x0D] => vec![(0x07E8, vec![0x41]), (0x07E9, vec![0x41, 0x0C, 0x1A, 0xF8])],
+                [0xC0] => vec![(0x07E8, vec![0x00])],
+                [0x03] => vec![(0x07E8, vec![0x43, 0x02, 0x01, 0x71, 0xC1, 0x00]), (0x07E9, vec![0x43, 0x00])],
+                [0x06, 0x01] => vec![(0x07E8, vec![0x46, 0x01, 0x80, 0x0A, 0x00, 0x20, 0x00, 0x00, 0x01, 0x00])],
+                [0x09, 0x02] => vec![(0x07E8, [&[0x49, 0x02, 0x01][..], b"WDD2040012A123456"].concat())],
+                [0x09, 0x06] => vec![(0x07E8, vec![0x49, 0x06, 0x02, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0])],
+                _ => Vec::new()
+            }
+        });
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        let mut obd = Obd2Client::open_can(channel, ObdCanAddressing::Standard11Bit).unwrap();
+        obd.set_timeout(30);
+
+        let supported = obd.supported_pids(ObdMode::ShowCurrentData).unwrap();
+        assert_eq!(vec![0x01, 0x04, 0x05, 0x0C, 0x0D, 0x20, 0x21], supported[&0x07E8]);
+        assert_eq!(vec![0x01], supported[&0x07E9]);
+
+        let rpm = obd.read_pid(0x0C).unwrap();
+        assert_eq!(1, rpm.len());
+        assert_eq!(1726.0, rpm[0].values[0].value);
+        assert!(obd.read_pid(0x0D).unwrap().is_empty());
+        assert!(matches!(obd.request(&[]), Err(ProtocolError::ServerError(_))));
+        assert!(obd.request(&[0xC0]).unwrap_err().is_timeout());
+
+        let dtcs = obd.read_dtcs(ObdDtcKind::Stored).unwrap();
+        assert_eq!(vec!["P0171", "U0100"], dtcs[&0x07E8].iter().map(|d| d.code.as_str()).collect::<Vec<_>>());
+        assert!(dtcs[&0x07E9].is_empty());
+
+        let tests = obd.read_monitor_tests(0x01).unwrap();
+        assert!(tests[&0x07E8][0].passed());
+
+        assert_eq!("WDD2040012A123456", obd.read_vin().unwrap());
+        assert_eq!(vec![0x1234_5678, 0x9ABC_DEF0], obd.read_cvns().unwrap()[&0x07E8]);
+    }
+
+    #[test]
+    pub fn test_obd_kline() {
+        let mut sim = SimAdapter::new(|channel, _, req| {
+            assert_eq!(AdapterChannel::Obd, channel);
+            let vin = b"\0\0\0WDB2030461A123456";
+            match req {
+                [0x68, 0x6A, 0xF1, 0x09, 0x02] => vin.chunks(4).enumerate()
+                    .map(|(i, c)| (0, [&[0x48, 0x6B, 0x10, 0x49, 0x02, i as u8 + 1][..], c].concat()))
+                    .collect(),
+                [0x68, 0x6A, 0xF1, 0x07] => vec![(0, vec![0x48, 0x6B, 0x10, 0x47, 0x01, 0x71, 0x00, 0x00, 0x00, 0x00])],
+                _ => Vec::new()
+            }
+        });
+        sim.open_device().unwrap();
+        let (mut obd, _) = Obd2Client::open_kline(sim, KLineStandard::Iso9141).unwrap();
+        obd.set_timeout(30);
+        assert_eq!("WDB2030461A123456", obd.read_vin().unwrap());
+        let dtcs = obd.read_dtcs(ObdDtcKind::Pending).unwrap();
+        assert_eq!(1, dtcs[&0x10].len());
+        assert_eq!(DTCState::Pending, dtcs[&0x10][0].state);
+    }
+}
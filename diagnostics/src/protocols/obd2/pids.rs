@@ -0,0 +1,281 @@
+//! SAE J1979 parameter IDs (PIDs) of mode 01 (Current data) and mode 02 (Freeze frame data)
+//!
+//! Almost all standard PIDs are linear, so each value is described by its position in the PID data,
+//! and the scale and offset applied to it.
+
+/// A value within the data of a PID. The value is `raw * scale + offset`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PidSignal {
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Position of the value in the PID data (In bytes)
+    pub start: usize,
+    /// Length of the value (In bytes, up to 4)
+    pub len: usize,
+    /// Bits of the raw value which belong to the value. The value is shifted down to the lowest set bit
+    pub mask: u32,
+    /// The raw value is two's complement
+    pub signed: bool,
+    pub scale: f32,
+    pub offset: f32
+}
+
+const fn lin(name: &'static str, unit: &'static str, start: usize, len: usize, scale: f32, offset: f32) -> PidSignal {
+    PidSignal { name, unit, start, len, mask: u32::MAX, signed: false, scale, offset }
+}
+
+const fn signed(name: &'static str, unit: &'static str, start: usize, len: usize, scale: f32, offset: f32) -> PidSignal {
+    PidSignal { name, unit, start, len, mask: u32::MAX, signed: true, scale, offset }
+}
+
+const fn bits(name: &'static str, start: usize, mask: u32) -> PidSignal {
+    PidSignal { name, unit: "", start, len: 1, mask, signed: false, scale: 1.0, offset: 0.0 }
+}
+
+impl PidSignal {
+    /// Decodes the value from the PID data, or returns None if the data is too short
+    pub fn decode(&self, data: &[u8]) -> Option<f32> {
+        let bytes = data.get(self.start..self.start + self.len)?;
+        let raw = bytes.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        let raw = (raw & self.mask) >> self.mask.trailing_zeros();
+        let raw = if self.signed {
+            let shift = 32 - 8 * self.len as u32;
+            (((raw << shift) as i32) >> shift) as f32
+        } else {
+            raw as f32
+        };
+        Some(raw * self.scale + self.offset)
+    }
+}
+
+/// A standard PID
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PidDefinition {
+    pub pid: u8,
+    pub name: &'static str,
+    /// Length of the PID data (In bytes)
+    pub len: usize,
+    pub signals: &'static [PidSignal]
+}
+
+/// A decoded value of a PID
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidValue {
+    pub name: &'static str,
+    pub value: f32,
+    pub unit: &'static str
+}
+
+const PIDS: &[PidDefinition] = &[
+    PidDefinition { pid: 0x01, name: "Monitor status since DTCs cleared", len: 4, signals: &[
+        bits("MIL", 0, 0x80),
+        bits("DTC count", 0, 0x7F)
+    ] },
+    PidDefinition { pid: 0x02, name: "DTC that caused freeze frame", len: 2, signals: &[lin("DTC", "", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x03, name: "Fuel system status", len: 2, signals: &[
+        lin("Fuel system 1", "", 0, 1, 1.0, 0.0),
+        lin("Fuel system 2", "", 1, 1, 1.0, 0.0)
+    ] },
+    PidDefinition { pid: 0x04, name: "Calculated engine load", len: 1, signals: &[lin("Load", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x05, name: "Engine coolant temperature", len: 1, signals: &[lin("Temperature", "°C", 0, 1, 1.0, -40.0)] },
+    PidDefinition { pid: 0x06, name: "Short term fuel trim - Bank 1", len: 1, signals: &[lin("Fuel trim", "%", 0, 1, 0.78125, -100.0)] },
+    PidDefinition { pid: 0x07, name: "Long term fuel trim - Bank 1", len: 1, signals: &[lin("Fuel trim", "%", 0, 1, 0.78125, -100.0)] },
+    PidDefinition { pid: 0x08, name: "Short term fuel trim - Bank 2", len: 1, signals: &[lin("Fuel trim", "%", 0, 1, 0.78125, -100.0)] },
+    PidDefinition { pid: 0x09, name: "Long term fuel trim - Bank 2", len: 1, signals: &[lin("Fuel trim", "%", 0, 1, 0.78125, -100.0)] },
+    PidDefinition { pid: 0x0A, name: "Fuel pressure", len: 1, signals: &[lin("Pressure", "kPa", 0, 1, 3.0, 0.0)] },
+    PidDefinition { pid: 0x0B, name: "Intake manifold absolute pressure", len: 1, signals: &[lin("Pressure", "kPa", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x0C, name: "Engine speed", len: 2, signals: &[lin("Speed", "rpm", 0, 2, 0.25, 0.0)] },
+    PidDefinition { pid: 0x0D, name: "Vehicle speed", len: 1, signals: &[lin("Speed", "km/h", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x0E, name: "Timing advance", len: 1, signals: &[lin("Advance", "°", 0, 1, 0.5, -64.0)] },
+    PidDefinition { pid: 0x0F, name: "Intake air temperature", len: 1, signals: &[lin("Temperature", "°C", 0, 1, 1.0, -40.0)] },
+    PidDefinition { pid: 0x10, name: "Mass air flow rate", len: 2, signals: &[lin("Flow rate", "g/s", 0, 2, 0.01, 0.0)] },
+    PidDefinition { pid: 0x11, name: "Throttle position", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x12, name: "Commanded secondary air status", len: 1, signals: &[lin("Status", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x13, name: "Oxygen sensors present", len: 1, signals: &[lin("Sensors", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x14, name: "Oxygen sensor 1 - Bank 1", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x15, name: "Oxygen sensor 2 - Bank 1", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x16, name: "Oxygen sensor 3 - Bank 1", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x17, name: "Oxygen sensor 4 - Bank 1", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x18, name: "Oxygen sensor 1 - Bank 2", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x19, name: "Oxygen sensor 2 - Bank 2", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x1A, name: "Oxygen sensor 3 - Bank 2", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x1B, name: "Oxygen sensor 4 - Bank 2", len: 2, signals: &[
+        lin("Voltage", "V", 0, 1, 0.005, 0.0),
+        lin("Short term fuel trim", "%", 1, 1, 0.78125, -100.0)
+    ] },
+    PidDefinition { pid: 0x1C, name: "OBD standard", len: 1, signals: &[lin("Standard", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x1D, name: "Oxygen sensors present (4 banks)", len: 1, signals: &[lin("Sensors", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x1E, name: "Auxiliary input status", len: 1, signals: &[bits("Power take off", 0, 0x01)] },
+    PidDefinition { pid: 0x1F, name: "Run time since engine start", len: 2, signals: &[lin("Time", "s", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x21, name: "Distance travelled with MIL on", len: 2, signals: &[lin("Distance", "km", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x22, name: "Fuel rail pressure (Relative to manifold vacuum)", len: 2, signals: &[lin("Pressure", "kPa", 0, 2, 0.079, 0.0)] },
+    PidDefinition { pid: 0x23, name: "Fuel rail gauge pressure", len: 2, signals: &[lin("Pressure", "kPa", 0, 2, 10.0, 0.0)] },
+    PidDefinition { pid: 0x24, name: "Oxygen sensor 1 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x25, name: "Oxygen sensor 2 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x26, name: "Oxygen sensor 3 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x27, name: "Oxygen sensor 4 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x28, name: "Oxygen sensor 5 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x29, name: "Oxygen sensor 6 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x2A, name: "Oxygen sensor 7 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x2B, name: "Oxygen sensor 8 (Wide range)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Voltage", "V", 2, 2, 0.00012207031, 0.0)
+    ] },
+    PidDefinition { pid: 0x2C, name: "Commanded EGR", len: 1, signals: &[lin("EGR", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x2D, name: "EGR error", len: 1, signals: &[lin("Error", "%", 0, 1, 0.78125, -100.0)] },
+    PidDefinition { pid: 0x2E, name: "Commanded evaporative purge", len: 1, signals: &[lin("Purge", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x2F, name: "Fuel tank level", len: 1, signals: &[lin("Level", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x30, name: "Warm-ups since DTCs cleared", len: 1, signals: &[lin("Warm-ups", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x31, name: "Distance travelled since DTCs cleared", len: 2, signals: &[lin("Distance", "km", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x32, name: "Evaporative system vapour pressure", len: 2, signals: &[signed("Pressure", "Pa", 0, 2, 0.25, 0.0)] },
+    PidDefinition { pid: 0x33, name: "Barometric pressure", len: 1, signals: &[lin("Pressure", "kPa", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x34, name: "Oxygen sensor 1 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x35, name: "Oxygen sensor 2 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x36, name: "Oxygen sensor 3 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x37, name: "Oxygen sensor 4 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x38, name: "Oxygen sensor 5 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x39, name: "Oxygen sensor 6 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x3A, name: "Oxygen sensor 7 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x3B, name: "Oxygen sensor 8 (Wide range current)", len: 4, signals: &[
+        lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0),
+        lin("Current", "mA", 2, 2, 0.00390625, -128.0)
+    ] },
+    PidDefinition { pid: 0x3C, name: "Catalyst temperature - Bank 1, Sensor 1", len: 2, signals: &[lin("Temperature", "°C", 0, 2, 0.1, -40.0)] },
+    PidDefinition { pid: 0x3D, name: "Catalyst temperature - Bank 2, Sensor 1", len: 2, signals: &[lin("Temperature", "°C", 0, 2, 0.1, -40.0)] },
+    PidDefinition { pid: 0x3E, name: "Catalyst temperature - Bank 1, Sensor 2", len: 2, signals: &[lin("Temperature", "°C", 0, 2, 0.1, -40.0)] },
+    PidDefinition { pid: 0x3F, name: "Catalyst temperature - Bank 2, Sensor 2", len: 2, signals: &[lin("Temperature", "°C", 0, 2, 0.1, -40.0)] },
+    PidDefinition { pid: 0x42, name: "Control module voltage", len: 2, signals: &[lin("Voltage", "V", 0, 2, 0.001, 0.0)] },
+    PidDefinition { pid: 0x43, name: "Absolute load", len: 2, signals: &[lin("Load", "%", 0, 2, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x44, name: "Commanded air-fuel equivalence ratio", len: 2, signals: &[lin("Lambda", "", 0, 2, 3.0517578e-05, 0.0)] },
+    PidDefinition { pid: 0x45, name: "Relative throttle position", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x46, name: "Ambient air temperature", len: 1, signals: &[lin("Temperature", "°C", 0, 1, 1.0, -40.0)] },
+    PidDefinition { pid: 0x47, name: "Absolute throttle position B", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x48, name: "Absolute throttle position C", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x49, name: "Accelerator pedal position D", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x4A, name: "Accelerator pedal position E", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x4B, name: "Accelerator pedal position F", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x4C, name: "Commanded throttle actuator", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x4D, name: "Time run with MIL on", len: 2, signals: &[lin("Time", "min", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x4E, name: "Time since DTCs cleared", len: 2, signals: &[lin("Time", "min", 0, 2, 1.0, 0.0)] },
+    PidDefinition { pid: 0x51, name: "Fuel type", len: 1, signals: &[lin("Fuel type", "", 0, 1, 1.0, 0.0)] },
+    PidDefinition { pid: 0x52, name: "Ethanol fuel", len: 1, signals: &[lin("Ethanol", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x59, name: "Fuel rail absolute pressure", len: 2, signals: &[lin("Pressure", "kPa", 0, 2, 10.0, 0.0)] },
+    PidDefinition { pid: 0x5A, name: "Relative accelerator pedal position", len: 1, signals: &[lin("Position", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x5B, name: "Hybrid battery pack remaining life", len: 1, signals: &[lin("Remaining life", "%", 0, 1, 0.39215687, 0.0)] },
+    PidDefinition { pid: 0x5C, name: "Engine oil temperature", len: 1, signals: &[lin("Temperature", "°C", 0, 1, 1.0, -40.0)] },
+    PidDefinition { pid: 0x5D, name: "Fuel injection timing", len: 2, signals: &[lin("Timing", "°", 0, 2, 0.0078125, -210.0)] },
+    PidDefinition { pid: 0x5E, name: "Engine fuel rate", len: 2, signals: &[lin("Fuel rate", "L/h", 0, 2, 0.05, 0.0)] },
+];
+
+/// Returns the definition of a standard PID, or None if the PID is not known
+pub fn get_pid_definition(pid: u8) -> Option<&'static PidDefinition> {
+    PIDS.iter().find(|p| p.pid == pid)
+}
+
+/// Decodes the data of a PID into its values
+///
+/// ## Returns
+/// The values of the PID, or None if the PID is not known or its data is too short
+pub fn decode_pid(pid: u8, data: &[u8]) -> Option<Vec<PidValue>> {
+    let def = get_pid_definition(pid)?;
+    if data.len() < def.len {
+        return None;
+    }
+    def.signals.iter().map(|s| s.decode(data).map(|value| PidValue { name: s.name, value, unit: s.unit })).collect()
+}
+
+/// Returns true if a PID reports which of the following 32 PIDs are supported (0x00, 0x20, 0x40...)
+pub fn is_support_pid(pid: u8) -> bool {
+    pid.is_multiple_of(0x20)
+}
+
+/// Decodes a supported PID bitmap
+///
+/// ## Arguments
+/// * base - The PID the bitmap was read from (0x00, 0x20, 0x40...)
+/// * bitmap - The 4 byte bitmap. The MSB of the first byte is PID `base + 1`
+pub fn decode_supported_pids(base: u8, bitmap: &[u8]) -> Vec<u8> {
+    bitmap.iter().take(4).enumerate()
+        .flat_map(|(byte, bits)| (0..8usize).filter(move |bit| *bits & (0x80u8 >> *bit) != 0).map(move |bit| byte * 8 + bit + 1))
+        .filter_map(|offset| base.checked_add(offset as u8))
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_pids() {
+        assert_eq!(vec![0x01, 0x03, 0x0C, 0x20], decode_supported_pids(0x00, &[0xA0, 0x10, 0x00, 0x01]));
+        assert_eq!(Some(vec![PidValue { name: "Speed", value: 1726.0, unit: "rpm" }]), decode_pid(0x0C, &[0x1A, 0xF8]));
+        assert_eq!(Some(vec![PidValue { name: "Temperature", value: 90.0, unit: "°C" }]), decode_pid(0x05, &[0x82]));
+        let status = decode_pid(0x01, &[0x83, 0x07, 0xE5, 0x00]).unwrap();
+        assert_eq!((1.0, 3.0), (status[0].value, status[1].value));
+        assert_eq!(-2.0, decode_pid(0x32, &[0xFF, 0xF8]).unwrap()[0].value);
+        assert_eq!(None, decode_pid(0x0C, &[0x1A]));
+    }
+}
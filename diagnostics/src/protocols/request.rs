@@ -0,0 +1,318 @@
+//! Request/response engine shared by the KWP2000 and UDS clients
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hardware::{AdapterHardware, HardwareError};
+use logger::Logger;
+
+use super::nrc::{get_nrc_description, NRC_BUSY_REPEAT_REQUEST, NRC_RESPONSE_PENDING};
+use super::{DiagChannel, DiagProtocol, ProtocolError, ProtocolResult};
+
+/// Service ID of a negative response
+pub const NEGATIVE_RESPONSE_SID: u8 = 0x7F;
+
+/// Offset added to the service ID of a request by the ECU in its positive response
+pub const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+
+/// Tester side response timeouts for an ECU. These include some headroom for adapter latency
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EcuTiming {
+    /// Maximum time to wait for a response (In ms)
+    pub p2_ms: u128,
+    /// Maximum time to wait for a response once the ECU has reported that its response is pending (In ms)
+    pub p2_star_ms: u128,
+    /// Number of times a request is repeated if the ECU reports that it is busy
+    pub busy_retries: u32,
+    /// Delay before repeating a request the ECU was busy for (In ms). This doubles with every repeat
+    pub busy_backoff_ms: u64
+}
+
+impl Default for EcuTiming {
+    fn default() -> Self {
+        Self {
+            p2_ms: 250,
+            p2_star_ms: 5000,
+            busy_retries: 3,
+            busy_backoff_ms: 25
+        }
+    }
+}
+
+/// Decoded response to a request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ResponseType {
+    Positive,
+    Negative(u8),
+    /// Not a response to the request, such as a late response to an earlier request
+    Unrelated
+}
+
+impl ResponseType {
+    pub(crate) fn decode(sid: u8, response: &[u8]) -> Self {
+        match response {
+            [NEGATIVE_RESPONSE_SID, s, nrc, ..] if *s == sid => ResponseType::Negative(*nrc),
+            [s, ..] if *s == sid.wrapping_add(POSITIVE_RESPONSE_OFFSET) => ResponseType::Positive,
+            _ => ResponseType::Unrelated
+        }
+    }
+}
+
+/// Checks that a positive response echoes the expected bytes of the request after its service ID
+///
+/// ## Returns
+/// The remainder of the response after the echoed bytes
+pub(crate) fn strip_echo<'a>(response: &'a [u8], echo: &[u8]) -> ProtocolResult<&'a [u8]> {
+    match response.get(1..1 + echo.len()) {
+        Some(e) if e == echo => Ok(&response[1 + echo.len()..]),
+        _ => Err(ProtocolError::InvalidResponse(response.to_vec()))
+    }
+}
+
+/// Sends requests to a single ECU over a [DiagChannel], and waits for their responses.
+///
+/// Clones of the engine share a lock, so that only one request is ever in flight to the ECU,
+/// even if the engine is used from multiple threads.
+///
+/// The engine does not change the session of the ECU by itself. Before it is handed to anything which
+/// sends non-default services (Coding, routines, actuation, memory readout, live data), the ECU must already
+/// be in the session those services require, with security access granted if needed. See
+/// [SessionManager](super::session::SessionManager) and [SecurityAccess](super::security::SecurityAccess).
+#[derive(Debug, Clone)]
+pub struct RequestEngine<A: AdapterHardware> {
+    channel: DiagChannel<A>,
+    tx_id: u32,
+    rx_id: u32,
+    protocol: DiagProtocol,
+    timing: EcuTiming,
+    in_flight: Arc<Mutex<()>>,
+    /// Time the last request was sent to the ECU
+    last_request: Arc<Mutex<Instant>>,
+    logger: Logger
+}
+
+impl<A: AdapterHardware> RequestEngine<A> {
+    /// Creates a new request engine, and registers the ECU on the channel
+    ///
+    /// ## Arguments
+    /// * channel - The channel the ECU is connected to
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * rx_id - The CAN ID the ECU responds with
+    /// * protocol - The protocol the ECU speaks. This determines how negative responses are described
+    pub fn new(channel: DiagChannel<A>, tx_id: u32, rx_id: u32, protocol: DiagProtocol) -> ProtocolResult<Self> {
+        channel.register_ecu(tx_id, rx_id)?;
+        Ok(Self {
+            channel,
+            tx_id,
+            rx_id,
+            protocol,
+            timing: EcuTiming::default(),
+            in_flight: Arc::new(Mutex::new(())),
+            last_request: Arc::new(Mutex::new(Instant::now())),
+            logger: Logger::new(&format!("ECU 0x{:04X}", tx_id))
+        })
+    }
+
+    /// Sends a request to the ECU, and waits for its response.
+    ///
+    /// If the ECU reports that its response is pending, the wait is extended to P2*. If the ECU reports
+    /// that it is busy, the request is repeated with an increasing delay, up to [EcuTiming::busy_retries] times.
+    ///
+    /// ## Arguments
+    /// * request - The request, starting with the service ID
+    ///
+    /// ## Returns
+    /// The positive response of the ECU (Including the response service ID), or [ProtocolError::ECUError]
+    /// if the ECU responded negatively
+    pub fn send_request(&self, request: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let _lock = self.in_flight.lock().unwrap();
+        self.send_request_locked(request)
+    }
+
+    /// Like [RequestEngine::send_request], but returns None without sending anything if another
+    /// request to the ECU is already in flight
+    pub fn try_send_request(&self, request: &[u8]) -> Option<ProtocolResult<Vec<u8>>> {
+        let _lock = self.in_flight.try_lock().ok()?;
+        Some(self.send_request_locked(request))
+    }
+
+    /// Waits for a message the ECU sends without being asked, such as periodic data. Returns None without
+    /// waiting if a request to the ECU is in flight, as any message received then belongs to that request
+    pub fn try_receive_unsolicited(&self, timeout_ms: u128) -> Option<ProtocolResult<Vec<u8>>> {
+        let _lock = self.in_flight.try_lock().ok()?;
+        Some(self.channel.receive(self.rx_id, timeout_ms))
+    }
+
+    fn send_request_locked(&self, request: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let sid = *request.first().ok_or_else(|| ProtocolError::ServerError("Request is empty".into()))?;
+        let mut backoff_ms = self.timing.busy_backoff_ms;
+        let mut retries = 0;
+        loop {
+            match self.exchange(sid, request) {
+                Err(ProtocolError::ECUError { code: NRC_BUSY_REPEAT_REQUEST, .. }) if retries < self.timing.busy_retries => {
+                    self.logger.log_debug(format!("ECU is busy, repeating request in {}ms", backoff_ms));
+                    std::thread::sleep(Duration::from_millis(backoff_ms));
+                    backoff_ms *= 2;
+                    retries += 1;
+                },
+                res => return res
+            }
+        }
+    }
+
+    fn exchange(&self, sid: u8, request: &[u8]) -> ProtocolResult<Vec<u8>> {
+        self.transmit(request)?;
+        let mut deadline = Instant::now() + Duration::from_millis(self.timing.p2_ms as u64);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now()).as_millis();
+            if remaining == 0 {
+                return Err(HardwareError::Timeout.into());
+            }
+            let response = self.channel.receive(self.rx_id, remaining)?;
+            self.logger.log_debug(format!("Response: {:02X?}", response));
+            match ResponseType::decode(sid, &response) {
+                ResponseType::Positive => return Ok(response),
+                ResponseType::Negative(NRC_RESPONSE_PENDING) => {
+                    deadline = Instant::now() + Duration::from_millis(self.timing.p2_star_ms as u64);
+                },
+                ResponseType::Negative(code) => return Err(ProtocolError::ECUError {
+                    code,
+                    desc: get_nrc_description(self.protocol, code).into()
+                }),
+                ResponseType::Unrelated => self.logger.log_warn(format!("Ignoring unexpected message {:02X?}", response))
+            }
+        }
+    }
+
+    /// Sends a request to the ECU without waiting for a response. This should only be used for requests
+    /// where the ECU is told not to respond
+    pub fn send_request_no_response(&self, request: &[u8]) -> ProtocolResult<()> {
+        if request.is_empty() {
+            return Err(ProtocolError::ServerError("Request is empty".into()));
+        }
+        let _lock = self.in_flight.lock().unwrap();
+        self.transmit(request)
+    }
+
+    /// Like [RequestEngine::send_request_no_response], but returns None without sending anything if
+    /// another request to the ECU is already in flight
+    pub fn try_send_request_no_response(&self, request: &[u8]) -> Option<ProtocolResult<()>> {
+        if request.is_empty() {
+            return Some(Err(ProtocolError::ServerError("Request is empty".into())));
+        }
+        let _lock = self.in_flight.try_lock().ok()?;
+        Some(self.transmit(request))
+    }
+
+    fn transmit(&self, request: &[u8]) -> ProtocolResult<()> {
+        // Discard anything left over from a previous request which timed out
+        self.channel.clear(self.rx_id)?;
+        self.logger.log_debug(format!("Request: {:02X?}", request));
+        *self.last_request.lock().unwrap() = Instant::now();
+        self.channel.send(self.tx_id, request)
+    }
+
+    /// Time since the last request was sent to the ECU
+    pub fn idle_time(&self) -> Duration {
+        self.last_request.lock().unwrap().elapsed()
+    }
+
+    /// The CAN ID requests are sent to the ECU with
+    pub fn tx_id(&self) -> u32 {
+        self.tx_id
+    }
+
+    /// The CAN ID the ECU responds with
+    pub fn rx_id(&self) -> u32 {
+        self.rx_id
+    }
+
+    /// The protocol the ECU speaks
+    pub fn protocol(&self) -> DiagProtocol {
+        self.protocol
+    }
+
+    /// The channel the ECU is connected to
+    pub fn channel(&self) -> &DiagChannel<A> {
+        &self.channel
+    }
+
+    pub fn timing(&self) -> EcuTiming {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: EcuTiming) {
+        self.timing = timing;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::protocols::test::faulty_sim_engine;
+    use hardware::fault_injection::FaultConfig;
+    use hardware::SimAdapter;
+
+    #[test]
+    pub fn test_pending_and_busy() {
+        let busy_count = Arc::new(AtomicU32::new(0));
+        let counter = busy_count.clone();
+        let mut sim = SimAdapter::new(move |_, _, req| match req {
+            [0x31, ..] => vec![(0x07E8, vec![0x7F, 0x31, 0x78]), (0x07E8, vec![0x7F, 0x31, 0x78]), (0x07E8, vec![0x71, 0x01])],
+            [0x21, ..] if counter.fetch_add(1, Ordering::Relaxed) < 2 => vec![(0x07E8, vec![0x7F, 0x21, 0x21])],
+            [0x21, lid] => vec![(0x07E8, vec![0x61, *lid])],
+            _ => vec![(0x07E8, vec![0x7F, req[0], 0x21])]
+        });
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        let engine = RequestEngine::new(channel, 0x07E0, 0x07E8, DiagProtocol::KWP2000).unwrap();
+
+        assert_eq!(vec![0x71, 0x01], engine.send_request(&[0x31, 0x01]).unwrap());
+        assert_eq!(vec![0x61, 0x10], engine.send_request(&[0x21, 0x10]).unwrap());
+        assert_eq!(3, busy_count.load(Ordering::Relaxed));
+        match engine.send_request(&[0x1A, 0x86]) {
+            Err(ProtocolError::ECUError { code, desc }) => {
+                assert_eq!(0x21, code);
+                assert_eq!("Busy, repeat request", desc);
+            },
+            other => panic!("Expected busy error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    pub fn test_requests_end_under_faults() {
+        let config = FaultConfig {
+            seed: 0xC0FFEE,
+            drop_rate: 0.1,
+            delay_rate: 0.1,
+            delay_ms: (5, 80),
+            duplicate_rate: 0.1,
+            corrupt_rate: 0.1,
+            error_rate: 0.1
+        };
+        let (adapter, mut engine) = faulty_sim_engine(DiagProtocol::KWP2000, config, |req| match req {
+            [0x21, lid] => vec![0x61, *lid, 0x12, 0x34],
+            _ => vec![0x7F, req[0], 0x11]
+        });
+        let timing = EcuTiming { p2_ms: 50, p2_star_ms: 200, busy_retries: 1, busy_backoff_ms: 10 };
+        engine.set_timing(timing);
+        // A corrupted response can at worst turn into "response pending" or "busy" once per attempt
+        let limit = Duration::from_millis(((timing.busy_retries as u128 + 1) * (timing.p2_ms + timing.p2_star_ms)) as u64 + 2 * timing.busy_backoff_ms + 100);
+
+        let mut answered = 0;
+        for lid in 0..100u8 {
+            let start = Instant::now();
+            let res = engine.send_request(&[0x21, lid]);
+            assert!(start.elapsed() < limit, "Request {} took {:?}", lid, start.elapsed());
+            if let Ok(res) = res {
+                // Only positive responses to the service are returned
+                assert_eq!(Some(&0x61), res.first());
+                answered += 1;
+            }
+        }
+        let stats = adapter.stats();
+        assert!(stats.dropped > 0 && stats.delayed > 0 && stats.duplicated > 0 && stats.corrupted > 0 && stats.errors > 0);
+        assert!(answered > 50);
+    }
+}
@@ -0,0 +1,341 @@
+//! SecurityAccess (Seed/key) workflow
+//!
+//! Seed/key algorithms are implemented with the [SeedKeyAlgorithm] trait, and registered by name in a
+//! [SeedKeyRegistry]. Each ECU variant and access level is then assigned an algorithm by name, which allows
+//! description files to reference algorithms. Algorithms can be compiled in, or loaded from seed/key DLLs
+//! using the Vector `GenerateKeyEx` interface with [LibrarySeedKey].
+//!
+//! Lockouts after too many invalid keys are kept per ECU and access level on the [DiagChannel](super::DiagChannel),
+//! so they are enforced for every [SecurityAccess] and client of the ECU.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_uint, c_uchar};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+use libloading::Library;
+
+use super::detect::EcuClient;
+use super::nrc::{NRC_EXCEEDED_NUMBER_OF_ATTEMPTS, NRC_INVALID_KEY, NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED};
+use super::request::POSITIVE_RESPONSE_OFFSET;
+use super::{ProtocolError, ProtocolResult, RequestEngine};
+
+/// Variant name which assigns an algorithm to every ECU variant without its own assignment
+pub const ANY_VARIANT: &str = "*";
+
+/// SecurityAccess service ID (Identical in KWP2000 and UDS)
+const SECURITY_ACCESS_SID: u8 = 0x27;
+
+/// Returns the sub-function the key of an access level is sent with
+///
+/// ## Arguments
+/// * level - The access level (The seed request sub-function). This must be an odd number below 0x7F
+pub(crate) fn key_level(level: u8) -> ProtocolResult<u8> {
+    match level {
+        l if l % 2 == 1 && l < 0x7F => Ok(l + 1),
+        _ => Err(ProtocolError::ServerError(format!("Invalid security access level 0x{:02X}", level)))
+    }
+}
+
+/// Largest key a seed/key library can return
+const MAX_LIBRARY_KEY_LEN: usize = 64;
+
+/// Algorithm which computes the key for a seed sent by an ECU
+pub trait SeedKeyAlgorithm: Send + Sync {
+    /// Computes a key
+    ///
+    /// ## Arguments
+    /// * variant - The variant of the ECU which sent the seed
+    /// * level - The access level the seed was requested for
+    /// * seed - The seed sent by the ECU
+    fn compute_key(&self, variant: &str, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>>;
+}
+
+impl<F: Fn(&str, u8, &[u8]) -> ProtocolResult<Vec<u8>> + Send + Sync> SeedKeyAlgorithm for F {
+    fn compute_key(&self, variant: &str, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        self(variant, level, seed)
+    }
+}
+
+type GenerateKeyExFn = unsafe extern "C" fn(
+    seed: *const c_uchar,
+    seed_len: c_uint,
+    level: c_uint,
+    variant: *const c_char,
+    key: *mut c_uchar,
+    max_key_len: c_uint,
+    key_len: *mut c_uint
+) -> c_int;
+
+/// Seed/key algorithm from a library implementing the Vector `GenerateKeyEx` interface
+pub struct LibrarySeedKey {
+    path: String,
+    _lib: Arc<Library>,
+    generate_key_fn: GenerateKeyExFn
+}
+
+impl fmt::Debug for LibrarySeedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LibrarySeedKey").field("path", &self.path).finish()
+    }
+}
+
+impl LibrarySeedKey {
+    /// Loads a seed/key library
+    pub fn load(path: &str) -> ProtocolResult<Self> {
+        let err = |e: libloading::Error| ProtocolError::ServerError(format!("Cannot load seed/key library {}: {}", path, e));
+        let lib = unsafe { Library::new(path).map_err(err)? };
+        let generate_key_fn = unsafe { *lib.get::<GenerateKeyExFn>(b"GenerateKeyEx\0").map_err(err)?.into_raw() };
+        Ok(Self {
+            path: path.to_string(),
+            _lib: Arc::new(lib),
+            generate_key_fn
+        })
+    }
+}
+
+impl SeedKeyAlgorithm for LibrarySeedKey {
+    fn compute_key(&self, variant: &str, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let variant = CString::new(variant).map_err(|_| ProtocolError::ServerError("Invalid variant name".into()))?;
+        let mut key = [0u8; MAX_LIBRARY_KEY_LEN];
+        let mut key_len: c_uint = 0;
+        let res = unsafe {
+            (self.generate_key_fn)(
+                seed.as_ptr(),
+                seed.len() as c_uint,
+                level as c_uint,
+                variant.as_ptr(),
+                key.as_mut_ptr(),
+                key.len() as c_uint,
+                &mut key_len
+            )
+        };
+        match res {
+            0 if (key_len as usize) <= key.len() => Ok(key[..key_len as usize].to_vec()),
+            _ => Err(ProtocolError::ServerError(format!("{} failed to generate a key (Error {})", self.path, res)))
+        }
+    }
+}
+
+/// Seed/key algorithms, and which ECU variants and access levels use them
+#[derive(Default, Clone)]
+pub struct SeedKeyRegistry {
+    algorithms: HashMap<String, Arc<dyn SeedKeyAlgorithm>>,
+    assignments: HashMap<(String, u8), String>
+}
+
+impl fmt::Debug for SeedKeyRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeedKeyRegistry")
+            .field("algorithms", &self.algorithms.keys().collect::<Vec<_>>())
+            .field("assignments", &self.assignments)
+            .finish()
+    }
+}
+
+impl SeedKeyRegistry {
+    /// Adds an algorithm. An existing algorithm with the same name is replaced
+    pub fn add_algorithm<S: SeedKeyAlgorithm + 'static>(&mut self, name: &str, algorithm: S) {
+        self.algorithms.insert(name.to_string(), Arc::new(algorithm));
+    }
+
+    /// Loads a seed/key library, and adds it as an algorithm
+    pub fn load_library(&mut self, name: &str, path: &str) -> ProtocolResult<()> {
+        let algorithm = LibrarySeedKey::load(path)?;
+        self.add_algorithm(name, algorithm);
+        Ok(())
+    }
+
+    /// Assigns an algorithm to an access level of an ECU variant
+    ///
+    /// ## Arguments
+    /// * variant - The ECU variant, or [ANY_VARIANT]
+    /// * level - The access level (The seed request sub-function)
+    /// * algorithm - The name of the algorithm. It does not need to be added yet
+    pub fn assign(&mut self, variant: &str, level: u8, algorithm: &str) {
+        self.assignments.insert((variant.to_string(), level), algorithm.to_string());
+    }
+
+    /// Returns the algorithm for an access level of an ECU variant
+    pub fn get(&self, variant: &str, level: u8) -> Option<Arc<dyn SeedKeyAlgorithm>> {
+        self.assignments.get(&(variant.to_string(), level))
+            .or_else(|| self.assignments.get(&(ANY_VARIANT.to_string(), level)))
+            .and_then(|name| self.algorithms.get(name))
+            .cloned()
+    }
+}
+
+/// Security access settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SecurityAccessConfig {
+    /// Number of invalid keys after which no more keys are sent until the lockout expires. This should
+    /// be below the ECU's own limit, so the ECU does not lock itself
+    pub max_attempts: u32,
+    /// Time to wait after too many invalid keys, or after the ECU reports a required time delay (In ms)
+    pub lockout_ms: u64
+}
+
+impl Default for SecurityAccessConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 2,
+            lockout_ms: 10_000
+        }
+    }
+}
+
+/// Security access attempts of an ECU's access level
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SecurityLevelState {
+    failed_attempts: u32,
+    locked_until: Option<Instant>
+}
+
+/// Unlocks security access levels of a single ECU
+#[derive(Debug, Clone)]
+pub struct SecurityAccess {
+    registry: Arc<SeedKeyRegistry>,
+    variant: String,
+    config: SecurityAccessConfig
+}
+
+impl SecurityAccess {
+    /// Creates the security access workflow for an ECU
+    ///
+    /// ## Arguments
+    /// * registry - Seed/key algorithms
+    /// * variant - The variant of the ECU, used to pick the algorithm
+    /// * config - Lockout settings
+    pub fn new(registry: Arc<SeedKeyRegistry>, variant: &str, config: SecurityAccessConfig) -> Self {
+        Self {
+            registry,
+            variant: variant.to_string(),
+            config
+        }
+    }
+
+    /// Returns the remaining lockout time of an access level of an ECU, if it is locked out
+    pub fn lockout_remaining<A: AdapterHardware>(&self, engine: &RequestEngine<A>, level: u8) -> Option<Duration> {
+        engine.channel().with_security_state(engine.tx_id(), level, |s| s.locked_until)
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Unlocks an access level: Requests a seed, computes the key, and sends it to the ECU
+    ///
+    /// ## Arguments
+    /// * client - The ECU to unlock
+    /// * level - The access level to unlock (The seed request sub-function). This is always an odd number below 0x7F
+    ///
+    /// ## Returns
+    /// [ProtocolError::SecurityLockedOut] if the level is locked out, either by the ECU or by too many invalid keys
+    pub fn unlock<A: AdapterHardware>(&self, client: &mut EcuClient<A>, level: u8) -> ProtocolResult<()> {
+        key_level(level)?;
+        if let Some(remaining) = self.lockout_remaining(client.engine(), level) {
+            return Err(ProtocolError::SecurityLockedOut(remaining));
+        }
+        let algorithm = self.registry.get(&self.variant, level).ok_or_else(|| {
+            ProtocolError::ServerError(format!("No seed/key algorithm for {} level {}", self.variant, level))
+        })?;
+
+        let res = client.security_access_request_seed(level);
+        let seed = self.check_response(client.engine(), level, res)?;
+        if seed.is_empty() {
+            return Err(ProtocolError::InvalidResponse(vec![SECURITY_ACCESS_SID + POSITIVE_RESPONSE_OFFSET, level]));
+        }
+        if seed.iter().all(|b| *b == 0) {
+            // Level is already unlocked
+            return Ok(());
+        }
+
+        let key = algorithm.compute_key(&self.variant, level, &seed)?;
+        let res = client.security_access_send_key(level, &key);
+        self.check_response(client.engine(), level, res)?;
+        client.engine().channel().with_security_state(client.engine().tx_id(), level, |s| *s = SecurityLevelState::default());
+        Ok(())
+    }
+
+    fn check_response<A: AdapterHardware, T>(&self, engine: &RequestEngine<A>, level: u8, res: ProtocolResult<T>) -> ProtocolResult<T> {
+        let code = match &res {
+            Err(ProtocolError::ECUError { code, .. }) => *code,
+            _ => return res
+        };
+        let lockout = Instant::now() + Duration::from_millis(self.config.lockout_ms);
+        engine.channel().with_security_state(engine.tx_id(), level, |state| match code {
+            NRC_INVALID_KEY => {
+                state.failed_attempts += 1;
+                if state.failed_attempts >= self.config.max_attempts {
+                    state.failed_attempts = 0;
+                    state.locked_until = Some(lockout);
+                }
+            },
+            NRC_EXCEEDED_NUMBER_OF_ATTEMPTS | NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED => {
+                state.failed_attempts = 0;
+                state.locked_until = Some(lockout);
+            },
+            _ => {}
+        });
+        res
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::{DiagChannel, DiagProtocol};
+    use hardware::SimAdapter;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    pub fn test_unlock_and_lockout() {
+        let mut registry = SeedKeyRegistry::default();
+        registry.add_algorithm("xor", |_: &str, _: u8, seed: &[u8]| Ok(vec![seed[0] ^ 0x5A, seed[1] ^ 0x5A]));
+        registry.add_algorithm("wrong", |_: &str, _: u8, _: &[u8]| Ok(vec![0x00, 0x00]));
+        registry.assign(ANY_VARIANT, 0x01, "xor");
+        registry.assign(ANY_VARIANT, 0x03, "xor");
+        registry.assign(ANY_VARIANT, 0x05, "xor");
+        registry.assign("CRD_NG", 0x01, "wrong");
+        let registry = Arc::new(registry);
+
+        // ECU with a 2 byte seed, where the key is the seed XORed with 0x5A5A
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = attempts.clone();
+        let mut sim = SimAdapter::new(move |_, _, req| {
+            let res = match req {
+                [0x27, 0x01] => vec![0x67, 0x01, 0x12, 0x34],
+                [0x27, 0x02, 0x48, 0x6E] => vec![0x67, 0x02],
+                [0x27, 0x02, ..] if counter.fetch_add(1, Ordering::Relaxed) >= 2 => vec![0x7F, 0x27, 0x36],
+                [0x27, 0x02, ..] => vec![0x7F, 0x27, 0x35],
+                // No seed, and a seed for the wrong level
+                [0x27, 0x03] => vec![0x67, 0x03],
+                [0x27, 0x05] => vec![0x67, 0x07, 0x12, 0x34],
+                _ => vec![0x7F, req[0], 0x12]
+            };
+            vec![(0x07E8, res)]
+        });
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        let client = || EcuClient::from_engine(RequestEngine::new(channel.clone(), 0x07E0, 0x07E8, DiagProtocol::UDS).unwrap());
+        let mut ecu = client();
+
+        let security = SecurityAccess::new(registry.clone(), "ME97", SecurityAccessConfig::default());
+        security.unlock(&mut ecu, 0x01).unwrap();
+        assert!(matches!(security.unlock(&mut ecu, 0x03), Err(ProtocolError::InvalidResponse(_))));
+        assert!(matches!(security.unlock(&mut ecu, 0x05), Err(ProtocolError::InvalidResponse(_))));
+        assert!(matches!(security.unlock(&mut ecu, 0x02), Err(ProtocolError::ServerError(_))));
+        assert!(matches!(security.unlock(&mut ecu, 0xFF), Err(ProtocolError::ServerError(_))));
+
+        let security = SecurityAccess::new(registry.clone(), "CRD_NG", SecurityAccessConfig::default());
+        assert!(matches!(security.unlock(&mut ecu, 0x01), Err(ProtocolError::ECUError { code: 0x35, .. })));
+        assert!(security.lockout_remaining(ecu.engine(), 0x01).is_none());
+        assert!(matches!(security.unlock(&mut ecu, 0x01), Err(ProtocolError::ECUError { code: 0x35, .. })));
+        // Too many invalid keys, so no more are sent to the ECU, even by a new client and workflow
+        let security = SecurityAccess::new(registry, "CRD_NG", SecurityAccessConfig::default());
+        assert!(security.lockout_remaining(ecu.engine(), 0x01).is_some());
+        assert!(matches!(security.unlock(&mut client(), 0x01), Err(ProtocolError::SecurityLockedOut(_))));
+        assert_eq!(2, attempts.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,452 @@
+//! Diagnostic session management
+//!
+//! ECUs fall back to their default session if they do not receive a request within the S3 timeout
+//! (Usually 5 seconds). Whilst a non-default session is active, [SessionManager] keeps it alive by sending
+//! TesterPresent in a background thread whenever the ECU has been idle for too long.
+//!
+//! Sessions also determine which services an ECU accepts, and which sessions can be entered from each other.
+//! [SessionManager::send_request] checks requests against these rules, so a request which the ECU would reject
+//! in its current session is either refused without being sent, or the required session is entered first.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use hardware::AdapterHardware;
+
+use super::kwp2000::KwpSession;
+use super::uds::{UdsSession, UdsSessionTiming};
+use super::{DiagProtocol, EcuTiming, ProtocolError, ProtocolResult, RequestEngine};
+
+/// How often the keep-alive thread checks if TesterPresent is due
+const KEEP_ALIVE_TICK_MS: u64 = 20;
+
+/// Added to the P2 and P2* values reported by a UDS ECU, to allow for adapter latency (In ms)
+const P2_HEADROOM_MS: u128 = 200;
+
+const KWP_NORMAL_SERVICES: &[u8] = &[0x10, 0x11, 0x14, 0x17, 0x18, 0x1A, 0x21, 0x23, 0x2C, 0x3E];
+const KWP_EXTENDED_SERVICES: &[u8] = &[
+    0x10, 0x11, 0x14, 0x17, 0x18, 0x1A, 0x21, 0x23, 0x27, 0x2C, 0x30, 0x31, 0x32, 0x33, 0x35, 0x36, 0x37, 0x3B, 0x3E
+];
+const KWP_REPROGRAMMING_SERVICES: &[u8] = &[0x10, 0x11, 0x1A, 0x21, 0x27, 0x31, 0x33, 0x34, 0x35, 0x36, 0x37, 0x3E];
+const KWP_STANDBY_SERVICES: &[u8] = &[0x10, 0x11, 0x1A, 0x21, 0x3E];
+const KWP_PASSIVE_SERVICES: &[u8] = &[0x10, 0x11, 0x1A, 0x3E];
+// ISO 14229-1 also allows WriteDataByIdentifier (0x2E) in the default session, but it is left
+// out on purpose: Mercedes ECUs only accept coding writes in the extended session
+const UDS_DEFAULT_SERVICES: &[u8] = &[0x10, 0x11, 0x14, 0x19, 0x22, 0x23, 0x2C, 0x31, 0x3E];
+const UDS_EXTENDED_SERVICES: &[u8] = &[
+    0x10, 0x11, 0x14, 0x19, 0x22, 0x23, 0x27, 0x28, 0x2A, 0x2C, 0x2E, 0x2F, 0x31, 0x35, 0x36, 0x37, 0x3E, 0x85
+];
+const UDS_PROGRAMMING_SERVICES: &[u8] = &[0x10, 0x11, 0x22, 0x27, 0x28, 0x2E, 0x31, 0x34, 0x35, 0x36, 0x37, 0x3E, 0x85];
+
+/// Keep-alive settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeepAliveConfig {
+    /// TesterPresent is sent once the ECU has been idle for this long (In ms). This must be below S3
+    pub interval_ms: u64,
+    /// Tell the ECU not to respond to TesterPresent. Some KWP2000 ECUs do not support this
+    pub suppress_response: bool,
+    /// Every n-th TesterPresent requires a response, to check the ECU is still in the session.
+    /// 0 never checks. This is ignored if [KeepAliveConfig::suppress_response] is false, as every
+    /// TesterPresent is checked then
+    pub check_every: u32
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 2000,
+            suppress_response: true,
+            check_every: 5
+        }
+    }
+}
+
+/// Events reported by the [SessionManager]
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// A session was started by the tester
+    SessionChanged(u8),
+    /// The ECU stopped responding to TesterPresent, so it has dropped back to its default session
+    SessionLost { session: u8, reason: ProtocolError }
+}
+
+/// Returns the ID of the default session of a protocol
+pub fn default_session_id(protocol: DiagProtocol) -> u8 {
+    match protocol {
+        DiagProtocol::KWP2000 => 0x81,
+        DiagProtocol::UDS => 0x01
+    }
+}
+
+/// Returns the TesterPresent request of a protocol
+pub(crate) fn tester_present_request(protocol: DiagProtocol, response_required: bool) -> [u8; 2] {
+    match (protocol, response_required) {
+        (DiagProtocol::KWP2000, true) => [0x3E, 0x01],
+        (DiagProtocol::KWP2000, false) => [0x3E, 0x02],
+        (DiagProtocol::UDS, true) => [0x3E, 0x00],
+        (DiagProtocol::UDS, false) => [0x3E, 0x80]
+    }
+}
+
+/// Returns the ID of the extended diagnostic session of a protocol, used for coding and actuation
+pub fn extended_session_id(protocol: DiagProtocol) -> u8 {
+    match protocol {
+        DiagProtocol::KWP2000 => KwpSession::ExtendedDiagnostics.id(),
+        DiagProtocol::UDS => UdsSession::Extended.id()
+    }
+}
+
+/// Returns the ID of the programming session of a protocol
+pub fn programming_session_id(protocol: DiagProtocol) -> u8 {
+    match protocol {
+        DiagProtocol::KWP2000 => KwpSession::Reprogramming.id(),
+        DiagProtocol::UDS => UdsSession::Programming.id()
+    }
+}
+
+/// Returns the service IDs an ECU accepts in a session, or None if the session is not a standard session,
+/// in which case the services it allows are unknown
+pub fn allowed_services(protocol: DiagProtocol, session_id: u8) -> Option<&'static [u8]> {
+    match protocol {
+        DiagProtocol::KWP2000 => match KwpSession::from_id(session_id) {
+            KwpSession::Normal => Some(KWP_NORMAL_SERVICES),
+            KwpSession::ExtendedDiagnostics => Some(KWP_EXTENDED_SERVICES),
+            KwpSession::Reprogramming => Some(KWP_REPROGRAMMING_SERVICES),
+            KwpSession::Standby => Some(KWP_STANDBY_SERVICES),
+            KwpSession::Passive => Some(KWP_PASSIVE_SERVICES),
+            KwpSession::Custom(_) => None
+        },
+        DiagProtocol::UDS => match UdsSession::from_id(session_id) {
+            UdsSession::Default => Some(UDS_DEFAULT_SERVICES),
+            UdsSession::Extended | UdsSession::SafetySystem => Some(UDS_EXTENDED_SERVICES),
+            UdsSession::Programming => Some(UDS_PROGRAMMING_SERVICES),
+            UdsSession::Custom(_) => None
+        }
+    }
+}
+
+/// Returns true if a service is allowed in a session. Every service is assumed to be allowed in non-standard sessions
+pub fn session_allows_service(protocol: DiagProtocol, session_id: u8, sid: u8) -> bool {
+    allowed_services(protocol, session_id).is_none_or(|services| services.contains(&sid))
+}
+
+/// Returns the first of the default, extended and programming sessions which allows a service
+pub fn session_for_service(protocol: DiagProtocol, sid: u8) -> Option<u8> {
+    [default_session_id(protocol), extended_session_id(protocol), programming_session_id(protocol)]
+        .iter()
+        .copied()
+        .find(|session| session_allows_service(protocol, *session, sid))
+}
+
+/// Returns true if an ECU can switch directly from one session to another. Every session can return to
+/// the default session, but the programming session and the Mercedes standby and passive sessions can
+/// only be left that way. UDS ECUs only enter programming from the extended session
+pub fn is_valid_transition(protocol: DiagProtocol, from: u8, to: u8) -> bool {
+    if from == to || to == default_session_id(protocol) {
+        return true;
+    }
+    match protocol {
+        DiagProtocol::KWP2000 => matches!(
+            (KwpSession::from_id(from), KwpSession::from_id(to)),
+            (KwpSession::Normal, _) | (KwpSession::ExtendedDiagnostics, KwpSession::Reprogramming)
+        ),
+        DiagProtocol::UDS => match (UdsSession::from_id(from), UdsSession::from_id(to)) {
+            (UdsSession::Default, UdsSession::Programming) => false,
+            (UdsSession::Default, _) => true,
+            (UdsSession::Extended, UdsSession::Programming | UdsSession::SafetySystem) => true,
+            (UdsSession::SafetySystem, UdsSession::Extended) => true,
+            _ => false
+        }
+    }
+}
+
+/// Returns the sessions to switch through to get from one session to another, or None if there is no way
+pub fn session_path(protocol: DiagProtocol, from: u8, to: u8) -> Option<Vec<u8>> {
+    let (default, extended) = (default_session_id(protocol), extended_session_id(protocol));
+    vec![vec![to], vec![default, to], vec![extended, to], vec![default, extended, to]]
+        .into_iter()
+        .find(|path| {
+            let mut current = from;
+            path.iter().all(|next| {
+                let valid = is_valid_transition(protocol, current, *next);
+                current = *next;
+                valid
+            })
+        })
+}
+
+/// What [SessionManager::send_request] does with a request which is not allowed in the current session
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SessionPolicy {
+    /// Return an error without sending the request
+    Refuse,
+    /// Switch to the first standard session which allows the request, then send it
+    AutoSwitch
+}
+
+/// Starts and ends diagnostic sessions with an ECU, and keeps non-default sessions alive
+#[derive(Debug)]
+pub struct SessionManager<A: AdapterHardware + 'static> {
+    engine: RequestEngine<A>,
+    config: KeepAliveConfig,
+    policy: SessionPolicy,
+    /// Timing reported by a UDS ECU for the current session
+    session_timing: Option<UdsSessionTiming>,
+    session: Arc<AtomicU8>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    event_tx: Sender<SessionEvent>,
+    event_rx: Receiver<SessionEvent>
+}
+
+impl<A: AdapterHardware + 'static> SessionManager<A> {
+    /// Creates a session manager. The ECU is assumed to be in its default session
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU. Requests sent through clones of it pause the keep-alive
+    /// * config - Keep-alive settings
+    pub fn new(engine: RequestEngine<A>, config: KeepAliveConfig) -> Self {
+        let (event_tx, event_rx) = channel();
+        Self {
+            session: Arc::new(AtomicU8::new(default_session_id(engine.protocol()))),
+            engine,
+            config,
+            policy: SessionPolicy::Refuse,
+            session_timing: None,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            event_tx,
+            event_rx
+        }
+    }
+
+    /// Switches the ECU to a diagnostic session. Keep-alive starts if the session is not the default session.
+    /// If the ECU is a UDS ECU, the P2 and P2* timeouts of the request engine are set to the values it reports
+    ///
+    /// ## Returns
+    /// The positive response of the ECU, or an error without sending anything if the ECU cannot switch
+    /// to the session from its current session
+    pub fn start_session(&mut self, session_id: u8) -> ProtocolResult<Vec<u8>> {
+        let protocol = self.engine.protocol();
+        let current = self.current_session();
+        if !is_valid_transition(protocol, current, session_id) {
+            return Err(ProtocolError::ServerError(format!("Cannot switch from session 0x{:02X} to 0x{:02X}", current, session_id)));
+        }
+        self.stop_keep_alive();
+        let res = self.engine.send_request(&[0x10, session_id]);
+        if let Ok(response) = &res {
+            self.session.store(session_id, Ordering::Relaxed);
+            let _ = self.event_tx.send(SessionEvent::SessionChanged(session_id));
+            if protocol == DiagProtocol::UDS {
+                self.session_timing = response.get(2..).and_then(UdsSessionTiming::decode);
+                if let Some(t) = self.session_timing {
+                    self.engine.set_timing(EcuTiming {
+                        p2_ms: t.p2_max_ms as u128 + P2_HEADROOM_MS,
+                        p2_star_ms: t.p2_star_max_ms as u128 + P2_HEADROOM_MS,
+                        ..self.engine.timing()
+                    });
+                }
+            }
+        }
+        if self.session.load(Ordering::Relaxed) != self.default_session() {
+            self.start_keep_alive();
+        }
+        res
+    }
+
+    /// Sends a request to the ECU after checking that its current session allows the service.
+    /// If it does not, the request is refused or the session is switched, depending on the [SessionPolicy].
+    /// DiagnosticSessionControl requests are handled by [SessionManager::start_session]
+    ///
+    /// ## Returns
+    /// The positive response of the ECU
+    pub fn send_request(&mut self, request: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let protocol = self.engine.protocol();
+        let sid = *request.first().ok_or_else(|| ProtocolError::ServerError("Request is empty".into()))?;
+        if let [0x10, session_id] = request {
+            return self.start_session(*session_id);
+        }
+        let current = self.current_session();
+        if !session_allows_service(protocol, current, sid) {
+            let not_allowed = || ProtocolError::ServerError(format!("Service 0x{:02X} is not allowed in session 0x{:02X}", sid, current));
+            if self.policy == SessionPolicy::Refuse {
+                return Err(not_allowed());
+            }
+            let path = session_for_service(protocol, sid)
+                .and_then(|target| session_path(protocol, current, target))
+                .ok_or_else(not_allowed)?;
+            for session_id in path {
+                self.start_session(session_id)?;
+            }
+        }
+        self.engine.send_request(request)
+    }
+
+    /// Sets what [SessionManager::send_request] does with requests the current session does not allow
+    pub fn set_policy(&mut self, policy: SessionPolicy) {
+        self.policy = policy;
+    }
+
+    /// The timing parameters a UDS ECU reported for its current session
+    pub fn session_timing(&self) -> Option<UdsSessionTiming> {
+        self.session_timing
+    }
+
+    /// Stops keep-alive and returns the ECU to its default session
+    pub fn end_session(&mut self) -> ProtocolResult<()> {
+        self.start_session(self.default_session()).map(|_| ())
+    }
+
+    /// The session the ECU is currently in
+    pub fn current_session(&self) -> u8 {
+        self.session.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if a non-default session is active
+    pub fn in_non_default_session(&self) -> bool {
+        self.current_session() != self.default_session()
+    }
+
+    /// Returns the next event which has not been read yet, without blocking
+    pub fn poll_event(&self) -> Option<SessionEvent> {
+        self.event_rx.try_recv().ok()
+    }
+
+    /// The request engine of the ECU. Its timing follows the session, unlike clones made before the session was started
+    pub fn engine(&self) -> &RequestEngine<A> {
+        &self.engine
+    }
+
+    fn default_session(&self) -> u8 {
+        default_session_id(self.engine.protocol())
+    }
+
+    fn start_keep_alive(&mut self) {
+        self.running.store(true, Ordering::Relaxed);
+        let engine = self.engine.clone();
+        let config = self.config;
+        let session = self.session.clone();
+        let running = self.running.clone();
+        let events = self.event_tx.clone();
+        self.thread = Some(std::thread::spawn(move || {
+            let protocol = engine.protocol();
+            let interval = Duration::from_millis(config.interval_ms);
+            let mut sent = 0u32;
+            while running.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(KEEP_ALIVE_TICK_MS));
+                if engine.idle_time() < interval {
+                    continue;
+                }
+                let check = !config.suppress_response || (config.check_every != 0 && sent.wrapping_add(1).is_multiple_of(config.check_every));
+                let request = tester_present_request(protocol, check);
+                let res = if check {
+                    engine.try_send_request(&request).map(|r| r.map(|_| ()))
+                } else {
+                    engine.try_send_request_no_response(&request)
+                };
+                match res {
+                    // A request is in flight, which keeps the session alive by itself
+                    None => continue,
+                    Some(Ok(())) => sent = sent.wrapping_add(1),
+                    Some(Err(reason)) => {
+                        if check {
+                            let lost = session.swap(default_session_id(protocol), Ordering::Relaxed);
+                            let _ = events.send(SessionEvent::SessionLost { session: lost, reason });
+                            running.store(false, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        }));
+    }
+
+    fn stop_keep_alive(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl<A: AdapterHardware + 'static> Drop for SessionManager<A> {
+    fn drop(&mut self) {
+        self.stop_keep_alive();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_engine;
+    use std::sync::atomic::AtomicU32;
+
+    #[test]
+    pub fn test_keep_alive() {
+        let tester_present = Arc::new(AtomicU32::new(0));
+        let powered = Arc::new(AtomicBool::new(true));
+        let (tp, power) = (tester_present.clone(), powered.clone());
+        let (_, engine) = sim_engine(DiagProtocol::KWP2000, move |req| {
+            if !power.load(Ordering::Relaxed) {
+                return Vec::new();
+            }
+            match req {
+                [0x10, s] => vec![0x50, *s],
+                [0x3E, sub] => {
+                    tp.fetch_add(1, Ordering::Relaxed);
+                    if *sub == 0x01 { vec![0x7E] } else { Vec::new() }
+                },
+                _ => Vec::new()
+            }
+        });
+        let mut manager = SessionManager::new(engine, KeepAliveConfig { interval_ms: 50, suppress_response: true, check_every: 2 });
+
+        manager.start_session(0x92).unwrap();
+        assert!(matches!(manager.poll_event(), Some(SessionEvent::SessionChanged(0x92))));
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(tester_present.load(Ordering::Relaxed) >= 3);
+        assert!(manager.in_non_default_session());
+
+        // ECU is switched off, so the next checked TesterPresent goes unanswered
+        powered.store(false, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(matches!(manager.poll_event(), Some(SessionEvent::SessionLost { session: 0x92, .. })));
+        assert_eq!(0x81, manager.current_session());
+    }
+
+    #[test]
+    pub fn test_session_rules() {
+        let sessions = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let ecu_sessions = sessions.clone();
+        let (_, engine) = sim_engine(DiagProtocol::UDS, move |req| {
+            match req {
+                [0x10, s] => {
+                    ecu_sessions.lock().unwrap().push(*s);
+                    vec![0x50, *s, 0x00, 0x32, 0x01, 0xF4]
+                },
+                [0x2E, a, b, ..] => vec![0x6E, *a, *b],
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+        let mut manager = SessionManager::new(engine, KeepAliveConfig::default());
+
+        assert!(!is_valid_transition(DiagProtocol::UDS, 0x01, 0x02));
+        assert_eq!(Some(vec![0x03, 0x02]), session_path(DiagProtocol::UDS, 0x01, 0x02));
+        assert_eq!(Some(vec![0x81, 0x92]), session_path(DiagProtocol::KWP2000, 0x89, 0x92));
+        assert!(manager.start_session(0x02).is_err());
+        assert!(sessions.lock().unwrap().is_empty());
+
+        // Neither WriteDataByIdentifier nor ReadDataByPeriodicIdentifier are allowed in the default session
+        assert!(manager.send_request(&[0x2E, 0x01, 0x00, 0xAA]).is_err());
+        assert!(manager.send_request(&[0x2A, 0x01, 0xF0]).is_err());
+        assert!(sessions.lock().unwrap().is_empty());
+        manager.set_policy(SessionPolicy::AutoSwitch);
+        assert_eq!(vec![0x6E, 0x01, 0x00], manager.send_request(&[0x2E, 0x01, 0x00, 0xAA]).unwrap());
+        assert_eq!(vec![0x03], *sessions.lock().unwrap());
+        assert_eq!(Some(UdsSessionTiming { p2_max_ms: 50, p2_star_max_ms: 5000 }), manager.session_timing());
+        assert_eq!(250, manager.engine().timing().p2_ms);
+        manager.end_session().unwrap();
+    }
+}
@@ -0,0 +1,813 @@
+//! UDS (ISO 14229) diagnostic client, as used by all Mercedes ECUs from the Xentry era
+
+use hardware::AdapterHardware;
+
+use super::dtc::{format_dtc_with_failure_type, DtcEnvironment, EnvironmentLayout, ExtendedDataRecord, FreezeFrame};
+use super::request::strip_echo;
+use super::security::key_level;
+use super::{DiagChannel, DiagProtocol, DTCState, DynamicIdentifierItem, GenericProtocolServer, MemoryAddressFormat, PeriodicRate, ProtocolError, ProtocolResult, RequestEngine, DTC};
+
+/// Bit set in a sub-function to tell the ECU not to send a positive response
+pub const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+/// UDS service IDs
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsService {
+    DiagnosticSessionControl = 0x10,
+    ECUReset = 0x11,
+    ClearDiagnosticInformation = 0x14,
+    ReadDTCInformation = 0x19,
+    ReadDataByIdentifier = 0x22,
+    ReadMemoryByAddress = 0x23,
+    SecurityAccess = 0x27,
+    CommunicationControl = 0x28,
+    ReadDataByPeriodicIdentifier = 0x2A,
+    DynamicallyDefineDataIdentifier = 0x2C,
+    WriteDataByIdentifier = 0x2E,
+    InputOutputControlByIdentifier = 0x2F,
+    RoutineControl = 0x31,
+    RequestUpload = 0x35,
+    TransferData = 0x36,
+    RequestTransferExit = 0x37,
+    TesterPresent = 0x3E,
+    ControlDTCSetting = 0x85
+}
+
+/// UDS diagnostic sessions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsSession {
+    /// Default session
+    Default,
+    /// ECU programming (Flashing) session
+    Programming,
+    /// Extended diagnostic session, required for coding and actuation
+    Extended,
+    /// Safety system diagnostic session, used for airbag and similar ECUs
+    SafetySystem,
+    /// Any other session ID
+    Custom(u8)
+}
+
+impl UdsSession {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsSession::Default => 0x01,
+            UdsSession::Programming => 0x02,
+            UdsSession::Extended => 0x03,
+            UdsSession::SafetySystem => 0x04,
+            UdsSession::Custom(id) => *id
+        }
+    }
+
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0x01 => UdsSession::Default,
+            0x02 => UdsSession::Programming,
+            0x03 => UdsSession::Extended,
+            0x04 => UdsSession::SafetySystem,
+            _ => UdsSession::Custom(id)
+        }
+    }
+}
+
+/// Timing parameters the ECU reports when entering a session
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdsSessionTiming {
+    /// Maximum time the ECU takes to respond (In ms)
+    pub p2_max_ms: u16,
+    /// Maximum time the ECU takes to respond after sending response pending (In ms)
+    pub p2_star_max_ms: u32
+}
+
+impl UdsSessionTiming {
+    /// Decodes the session parameter record of a DiagnosticSessionControl response
+    pub fn decode(record: &[u8]) -> Option<Self> {
+        match record {
+            [p2_hi, p2_lo, p2s_hi, p2s_lo, ..] => Some(Self {
+                p2_max_ms: u16::from_be_bytes([*p2_hi, *p2_lo]),
+                // P2* is reported with a resolution of 10ms
+                p2_star_max_ms: u16::from_be_bytes([*p2s_hi, *p2s_lo]) as u32 * 10
+            }),
+            _ => None
+        }
+    }
+}
+
+/// ECU reset types
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsResetType {
+    /// Simulates a power cycle of the ECU
+    HardReset,
+    /// Simulates the ignition being switched off and on again
+    KeyOffOnReset,
+    /// Restarts the ECU's application
+    SoftReset,
+    /// Any other reset type
+    Custom(u8)
+}
+
+impl UdsResetType {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsResetType::HardReset => 0x01,
+            UdsResetType::KeyOffOnReset => 0x02,
+            UdsResetType::SoftReset => 0x03,
+            UdsResetType::Custom(id) => *id
+        }
+    }
+}
+
+/// Routine control types
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsRoutineControlType {
+    Start,
+    Stop,
+    RequestResults
+}
+
+impl UdsRoutineControlType {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsRoutineControlType::Start => 0x01,
+            UdsRoutineControlType::Stop => 0x02,
+            UdsRoutineControlType::RequestResults => 0x03
+        }
+    }
+}
+
+/// Input/Output control parameters
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsIoControlParameter {
+    /// Gives control of the output back to the ECU
+    ReturnControlToEcu,
+    /// Resets the output to its default state
+    ResetToDefault,
+    /// Freezes the output in its current state
+    FreezeCurrentState,
+    /// Temporarily sets the output to the supplied state
+    ShortTermAdjustment
+}
+
+impl UdsIoControlParameter {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsIoControlParameter::ReturnControlToEcu => 0x00,
+            UdsIoControlParameter::ResetToDefault => 0x01,
+            UdsIoControlParameter::FreezeCurrentState => 0x02,
+            UdsIoControlParameter::ShortTermAdjustment => 0x03
+        }
+    }
+}
+
+/// Communication control types
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsCommunicationControl {
+    EnableRxAndTx,
+    EnableRxDisableTx,
+    DisableRxEnableTx,
+    DisableRxAndTx
+}
+
+impl UdsCommunicationControl {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsCommunicationControl::EnableRxAndTx => 0x00,
+            UdsCommunicationControl::EnableRxDisableTx => 0x01,
+            UdsCommunicationControl::DisableRxEnableTx => 0x02,
+            UdsCommunicationControl::DisableRxAndTx => 0x03
+        }
+    }
+}
+
+/// Types of messages affected by communication control
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdsCommunicationType {
+    /// Application messages
+    Normal,
+    /// Network management messages
+    NetworkManagement,
+    /// Application and network management messages
+    All
+}
+
+impl UdsCommunicationType {
+    pub fn id(&self) -> u8 {
+        match self {
+            UdsCommunicationType::Normal => 0x01,
+            UdsCommunicationType::NetworkManagement => 0x02,
+            UdsCommunicationType::All => 0x03
+        }
+    }
+}
+
+/// A DTC as reported by ReadDTCInformation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdsDtcRecord {
+    /// Raw 3 byte DTC
+    pub code: u32,
+    /// Status byte of the DTC
+    pub status: u8
+}
+
+impl UdsDtcRecord {
+    /// Returns true if the ECU requests the warning indicator to be on for this DTC
+    pub fn warning_indicator(&self) -> bool {
+        self.status & 0x80 != 0
+    }
+
+    /// Converts the status byte into a [DTCState]
+    pub fn state(&self) -> DTCState {
+        if self.status & 0x01 != 0 {
+            DTCState::Active
+        } else if self.status & 0x08 != 0 {
+            DTCState::Stored
+        } else if self.status & 0x04 != 0 {
+            DTCState::Pending
+        } else {
+            DTCState::None
+        }
+    }
+}
+
+impl From<UdsDtcRecord> for DTC {
+    fn from(r: UdsDtcRecord) -> Self {
+        DTC {
+            code: format_dtc_with_failure_type(r.code),
+            state: r.state(),
+            mil_on: r.warning_indicator(),
+            environment: None
+        }
+    }
+}
+
+/// Number of DTCs matching a status mask
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdsDtcCount {
+    /// Status bits supported by the ECU
+    pub availability_mask: u8,
+    /// Format of the DTCs stored in the ECU
+    pub format: u8,
+    pub count: u16
+}
+
+/// DTCs matching a status mask
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdsDtcList {
+    /// Status bits supported by the ECU
+    pub availability_mask: u8,
+    pub records: Vec<UdsDtcRecord>
+}
+
+/// Checks that a DTC specific ReadDTCInformation response is for the expected DTC
+///
+/// ## Returns
+/// The remainder of the response after the DTC and its status
+fn strip_dtc(data: &[u8], code: u32) -> ProtocolResult<&[u8]> {
+    match data {
+        [a, b, c, _status, rest @ ..] if u32::from_be_bytes([0, *a, *b, *c]) == code => Ok(rest),
+        _ => Err(ProtocolError::InvalidResponse(data.to_vec()))
+    }
+}
+
+/// Typed UDS services. These are implemented on top of [GenericProtocolServer::send_command_with_response]
+pub trait UdsServer: GenericProtocolServer {
+    /// Switches the ECU to another diagnostic session
+    ///
+    /// ## Returns
+    /// The timing parameters the ECU reports for the session
+    fn diagnostic_session_control(&mut self, session: UdsSession) -> ProtocolResult<UdsSessionTiming> {
+        let res = self.send_command_with_response(&[UdsService::DiagnosticSessionControl as u8, session.id()])?;
+        UdsSessionTiming::decode(strip_echo(&res, &[session.id()])?).ok_or(ProtocolError::InvalidResponse(res))
+    }
+
+    /// Resets the ECU
+    fn ecu_reset(&mut self, reset: UdsResetType) -> ProtocolResult<()> {
+        let res = self.send_command_with_response(&[UdsService::ECUReset as u8, reset.id()])?;
+        strip_echo(&res, &[reset.id()]).map(|_| ())
+    }
+
+    /// Reads a single data identifier from the ECU
+    fn read_data_by_identifier(&mut self, did: u16) -> ProtocolResult<Vec<u8>> {
+        let res = self.send_command_with_response(&[UdsService::ReadDataByIdentifier as u8, (did >> 8) as u8, did as u8])?;
+        strip_echo(&res, &did.to_be_bytes()).map(|d| d.to_vec())
+    }
+
+    /// Reads multiple data identifiers from the ECU in one request.
+    ///
+    /// ## Arguments
+    /// * dids - The identifiers to read, with the length of their data. As the response does not contain
+    ///   the length of each record, it must be known up front. The length of the last identifier may be
+    ///   None, in which case it takes the remainder of the response.
+    ///
+    /// ## Returns
+    /// The data of each identifier, in the order requested
+    fn read_data_by_identifiers(&mut self, dids: &[(u16, Option<usize>)]) -> ProtocolResult<Vec<(u16, Vec<u8>)>> {
+        let mut req = vec![UdsService::ReadDataByIdentifier as u8];
+        dids.iter().for_each(|(did, _)| req.extend_from_slice(&did.to_be_bytes()));
+        let res = self.send_command_with_response(&req)?;
+        let mut data = &res[1..];
+        let mut records = Vec::with_capacity(dids.len());
+        for (did, len) in dids {
+            data = match data {
+                [hi, lo, rest @ ..] if u16::from_be_bytes([*hi, *lo]) == *did => rest,
+                _ => return Err(ProtocolError::InvalidResponse(res))
+            };
+            let len = len.unwrap_or(data.len());
+            if len > data.len() {
+                return Err(ProtocolError::InvalidResponse(res));
+            }
+            records.push((*did, data[..len].to_vec()));
+            data = &data[len..];
+        }
+        Ok(records)
+    }
+
+    /// Asks the ECU to transmit periodic data identifiers (0xF2xx) until they are stopped with
+    /// [UdsServer::stop_periodic_identifiers]. The data is received as unsolicited messages, starting with
+    /// the low byte of the identifier
+    ///
+    /// ## Arguments
+    /// * rate - The transmission rate
+    /// * ids - The low bytes of the periodic data identifiers
+    fn read_data_by_periodic_identifier(&mut self, rate: PeriodicRate, ids: &[u8]) -> ProtocolResult<()> {
+        let mode = match rate {
+            PeriodicRate::Slow => 0x01,
+            PeriodicRate::Medium => 0x02,
+            PeriodicRate::Fast => 0x03
+        };
+        let mut req = vec![UdsService::ReadDataByPeriodicIdentifier as u8, mode];
+        req.extend_from_slice(ids);
+        self.send_command_with_response(&req).map(|_| ())
+    }
+
+    /// Stops the periodic transmission of data identifiers
+    ///
+    /// ## Arguments
+    /// * ids - The low bytes of the periodic data identifiers. If empty, all transmissions are stopped
+    fn stop_periodic_identifiers(&mut self, ids: &[u8]) -> ProtocolResult<()> {
+        let mut req = vec![UdsService::ReadDataByPeriodicIdentifier as u8, 0x04];
+        req.extend_from_slice(ids);
+        self.send_command_with_response(&req).map(|_| ())
+    }
+
+    /// Defines a data identifier whose record is made up of parts of other records
+    ///
+    /// ## Arguments
+    /// * did - The data identifier to define. It must be within 0xF200 - 0xF2FF to be read periodically
+    /// * items - The parts of other records to copy, in order
+    fn dynamically_define_data_identifier(&mut self, did: u16, items: &[DynamicIdentifierItem]) -> ProtocolResult<()> {
+        let mut req = vec![UdsService::DynamicallyDefineDataIdentifier as u8, 0x01, (did >> 8) as u8, did as u8];
+        for item in items {
+            req.extend_from_slice(&item.source_id.to_be_bytes());
+            req.extend_from_slice(&[item.position, item.size]);
+        }
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &req[1..4]).map(|_| ())
+    }
+
+    /// Clears the definition of a dynamically defined data identifier
+    fn clear_dynamically_defined_data_identifier(&mut self, did: u16) -> ProtocolResult<()> {
+        let req = [UdsService::DynamicallyDefineDataIdentifier as u8, 0x03, (did >> 8) as u8, did as u8];
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &req[1..4]).map(|_| ())
+    }
+
+    /// Writes a data identifier to the ECU
+    fn write_data_by_identifier(&mut self, did: u16, data: &[u8]) -> ProtocolResult<()> {
+        let mut req = vec![UdsService::WriteDataByIdentifier as u8, (did >> 8) as u8, did as u8];
+        req.extend_from_slice(data);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &did.to_be_bytes()).map(|_| ())
+    }
+
+    /// Reads an area of the ECU's memory
+    ///
+    /// ## Arguments
+    /// * format - How the address and size are encoded
+    /// * address - The start address of the area
+    /// * size - The number of bytes to read
+    fn read_memory_by_address(&mut self, format: MemoryAddressFormat, address: u32, size: u32) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![UdsService::ReadMemoryByAddress as u8, format.format_identifier()];
+        req.extend_from_slice(&format.encode(address, size)?);
+        let res = self.send_command_with_response(&req)?;
+        Ok(res[1..].to_vec())
+    }
+
+    /// Asks the ECU to upload an area of its memory to the tester. The data is then read with
+    /// [UdsServer::transfer_data], and the upload is ended with [UdsServer::request_transfer_exit]
+    ///
+    /// ## Arguments
+    /// * format - How the address and size are encoded
+    /// * data_format - The data format identifier. 0x00 requests the data unencrypted and uncompressed
+    /// * address - The start address of the area
+    /// * size - The number of bytes to upload
+    ///
+    /// ## Returns
+    /// The maximum length of a TransferData response, including the service ID and block sequence counter
+    fn request_upload(&mut self, format: MemoryAddressFormat, data_format: u8, address: u32, size: u32) -> ProtocolResult<u32> {
+        let mut req = vec![UdsService::RequestUpload as u8, data_format, format.format_identifier()];
+        req.extend_from_slice(&format.encode(address, size)?);
+        let res = self.send_command_with_response(&req)?;
+        let len_bytes = (*res.get(1).ok_or_else(|| ProtocolError::InvalidResponse(res.clone()))? >> 4) as usize;
+        match res.get(2..2 + len_bytes) {
+            Some(len) if (1..=4).contains(&len_bytes) => Ok(len.iter().fold(0, |acc, b| (acc << 8) | *b as u32)),
+            _ => Err(ProtocolError::InvalidResponse(res))
+        }
+    }
+
+    /// Transfers the next block of an upload or download
+    ///
+    /// ## Arguments
+    /// * sequence - The block sequence counter. It starts at 1 and wraps around to 0
+    /// * params - Transfer request parameters. Empty for uploads
+    ///
+    /// ## Returns
+    /// The data in the response, excluding the echoed block sequence counter
+    fn transfer_data(&mut self, sequence: u8, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![UdsService::TransferData as u8, sequence];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[sequence]).map(|d| d.to_vec())
+    }
+
+    /// Ends an upload or download
+    ///
+    /// ## Returns
+    /// Transfer response parameters returned by the ECU, if any
+    fn request_transfer_exit(&mut self) -> ProtocolResult<Vec<u8>> {
+        let res = self.send_command_with_response(&[UdsService::RequestTransferExit as u8])?;
+        Ok(res[1..].to_vec())
+    }
+
+    /// Sends a ReadDTCInformation request
+    ///
+    /// ## Arguments
+    /// * report_type - The sub-function, which determines what is reported
+    /// * params - Parameters of the sub-function
+    ///
+    /// ## Returns
+    /// The response, excluding the echoed sub-function
+    fn read_dtc_information(&mut self, report_type: u8, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![UdsService::ReadDTCInformation as u8, report_type];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[report_type]).map(|d| d.to_vec())
+    }
+
+    /// Counts the DTCs which match a status mask (Sub-function 0x01)
+    fn report_number_of_dtc_by_status_mask(&mut self, status_mask: u8) -> ProtocolResult<UdsDtcCount> {
+        match self.read_dtc_information(0x01, &[status_mask])?.as_slice() {
+            [availability_mask, format, hi, lo] => Ok(UdsDtcCount {
+                availability_mask: *availability_mask,
+                format: *format,
+                count: u16::from_be_bytes([*hi, *lo])
+            }),
+            other => Err(ProtocolError::InvalidResponse(other.to_vec()))
+        }
+    }
+
+    /// Reads the DTCs which match a status mask (Sub-function 0x02)
+    fn report_dtc_by_status_mask(&mut self, status_mask: u8) -> ProtocolResult<UdsDtcList> {
+        let res = self.read_dtc_information(0x02, &[status_mask])?;
+        match res.split_first() {
+            Some((availability_mask, records)) if records.len() % 4 == 0 => Ok(UdsDtcList {
+                availability_mask: *availability_mask,
+                records: records.chunks(4).map(|r| UdsDtcRecord {
+                    code: u32::from_be_bytes([0, r[0], r[1], r[2]]),
+                    status: r[3]
+                }).collect()
+            }),
+            _ => Err(ProtocolError::InvalidResponse(res))
+        }
+    }
+
+    /// Lists the freeze frames stored by the ECU (Sub-function 0x03)
+    ///
+    /// ## Returns
+    /// Each DTC with a freeze frame, and the record number of the freeze frame
+    fn report_dtc_snapshot_identification(&mut self) -> ProtocolResult<Vec<(u32, u8)>> {
+        let res = self.read_dtc_information(0x03, &[])?;
+        if res.len() % 4 != 0 {
+            return Err(ProtocolError::InvalidResponse(res));
+        }
+        Ok(res.chunks(4).map(|r| (u32::from_be_bytes([0, r[0], r[1], r[2]]), r[3])).collect())
+    }
+
+    /// Reads a freeze frame of a DTC (Sub-function 0x04)
+    ///
+    /// ## Returns
+    /// The freeze frame, or None if the ECU has no such freeze frame stored
+    fn report_dtc_snapshot_record(&mut self, code: u32, record_number: u8) -> ProtocolResult<Option<FreezeFrame>> {
+        let [_, a, b, c] = code.to_be_bytes();
+        let res = self.read_dtc_information(0x04, &[a, b, c, record_number])?;
+        Ok(match strip_dtc(&res, code)? {
+            [record_number, identifier_count, data @ ..] => Some(FreezeFrame {
+                record_number: *record_number,
+                identifier_count: *identifier_count,
+                data: data.to_vec()
+            }),
+            _ => None
+        })
+    }
+
+    /// Reads an extended data record of a DTC (Sub-function 0x06)
+    ///
+    /// ## Arguments
+    /// * code - The DTC
+    /// * record_number - The record to read, or 0xFF to read all records at once
+    ///
+    /// ## Returns
+    /// The record, or None if the ECU has no such record stored
+    fn report_dtc_extended_data(&mut self, code: u32, record_number: u8) -> ProtocolResult<Option<ExtendedDataRecord>> {
+        let [_, a, b, c] = code.to_be_bytes();
+        let res = self.read_dtc_information(0x06, &[a, b, c, record_number])?;
+        Ok(match strip_dtc(&res, code)? {
+            [] => None,
+            data if record_number == 0xFF => Some(ExtendedDataRecord { record_number, data: data.to_vec() }),
+            [number, data @ ..] => Some(ExtendedDataRecord { record_number: *number, data: data.to_vec() })
+        })
+    }
+
+    /// Reads the freeze frames and extended data records of a DTC. Records the ECU does not support are skipped
+    ///
+    /// ## Arguments
+    /// * code - The DTC to read the environment data of
+    /// * layout - The layout of the ECU's environment data, if known. This also selects the extended data records to read
+    fn read_dtc_environment(&mut self, code: u32, layout: Option<&EnvironmentLayout>) -> ProtocolResult<DtcEnvironment> {
+        let snapshots = self.read_snapshot_identification()?;
+        self.read_dtc_environment_with_snapshots(code, &snapshots, layout)
+    }
+
+    /// Like [UdsServer::read_dtc_environment], with the freeze frames listed by
+    /// [UdsServer::report_dtc_snapshot_identification] already known, so they are not listed again for every DTC
+    ///
+    /// ## Arguments
+    /// * code - The DTC to read the environment data of
+    /// * snapshots - Each DTC with a freeze frame, and the record number of the freeze frame
+    /// * layout - The layout of the ECU's environment data, if known. This also selects the extended data records to read
+    fn read_dtc_environment_with_snapshots(&mut self, code: u32, snapshots: &[(u32, u8)], layout: Option<&EnvironmentLayout>) -> ProtocolResult<DtcEnvironment> {
+        let mut env = DtcEnvironment::default();
+        for (_, number) in snapshots.iter().filter(|(c, _)| *c == code) {
+            if let Some(frame) = self.report_dtc_snapshot_record(code, *number)? {
+                env.freeze_frames.push(frame);
+            }
+        }
+        let records = match layout {
+            Some(l) if !l.extended_data_records.is_empty() => l.extended_data_records.clone(),
+            _ => vec![0xFF]
+        };
+        for number in records {
+            match self.report_dtc_extended_data(code, number) {
+                Ok(Some(record)) => env.extended_data.push(record),
+                Ok(None) | Err(ProtocolError::ECUError { .. }) => {},
+                Err(e) => return Err(e)
+            }
+        }
+        if let Some(layout) = layout {
+            env.apply_layout(layout);
+        }
+        Ok(env)
+    }
+
+    /// Lists the freeze frames stored by the ECU, or nothing if the ECU does not support freeze frames
+    fn read_snapshot_identification(&mut self) -> ProtocolResult<Vec<(u32, u8)>> {
+        match self.report_dtc_snapshot_identification() {
+            Ok(s) => Ok(s),
+            Err(ProtocolError::ECUError { .. }) => Ok(Vec::new()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Reads all DTCs, and the environment data of each
+    ///
+    /// ## Arguments
+    /// * layout - The layout of the ECU's environment data, if known
+    fn read_dtcs_with_environment(&mut self, layout: Option<&EnvironmentLayout>) -> ProtocolResult<Vec<DTC>> {
+        let records = self.report_dtc_by_status_mask(0xFF)?.records;
+        let snapshots = self.read_snapshot_identification()?;
+        let mut dtcs = Vec::new();
+        for record in records {
+            let mut dtc = DTC::from(record);
+            dtc.environment = Some(self.read_dtc_environment_with_snapshots(record.code, &snapshots, layout)?);
+            dtcs.push(dtc);
+        }
+        Ok(dtcs)
+    }
+
+    /// Clears DTCs stored in the ECU
+    ///
+    /// ## Arguments
+    /// * group - The group of DTCs to clear. 0xFFFFFF clears all DTCs
+    fn clear_diagnostic_information(&mut self, group: u32) -> ProtocolResult<()> {
+        let [_, a, b, c] = group.to_be_bytes();
+        self.send_command_with_response(&[UdsService::ClearDiagnosticInformation as u8, a, b, c]).map(|_| ())
+    }
+
+    /// Requests a security access seed from the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level to request the seed for. This is always an odd number below 0x7F
+    ///
+    /// ## Returns
+    /// The seed. A seed of all zeros means the level is already unlocked
+    fn security_access_request_seed(&mut self, level: u8) -> ProtocolResult<Vec<u8>> {
+        key_level(level)?;
+        let res = self.send_command_with_response(&[UdsService::SecurityAccess as u8, level])?;
+        strip_echo(&res, &[level]).map(|d| d.to_vec())
+    }
+
+    /// Sends the key computed from a seed to the ECU
+    ///
+    /// ## Arguments
+    /// * level - The access level the seed was requested for. The key is sent with level + 1
+    /// * key - The key
+    fn security_access_send_key(&mut self, level: u8, key: &[u8]) -> ProtocolResult<()> {
+        let key_level = key_level(level)?;
+        let mut req = vec![UdsService::SecurityAccess as u8, key_level];
+        req.extend_from_slice(key);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &[key_level]).map(|_| ())
+    }
+
+    /// Enables or disables the transmission and reception of messages by the ECU
+    fn communication_control(&mut self, control: UdsCommunicationControl, comm_type: UdsCommunicationType) -> ProtocolResult<()> {
+        let res = self.send_command_with_response(&[UdsService::CommunicationControl as u8, control.id(), comm_type.id()])?;
+        strip_echo(&res, &[control.id()]).map(|_| ())
+    }
+
+    /// Enables or disables the storing of new DTCs by the ECU
+    fn control_dtc_setting(&mut self, enabled: bool) -> ProtocolResult<()> {
+        let setting = if enabled { 0x01 } else { 0x02 };
+        let res = self.send_command_with_response(&[UdsService::ControlDTCSetting as u8, setting])?;
+        strip_echo(&res, &[setting]).map(|_| ())
+    }
+
+    /// Starts, stops or requests the results of a routine in the ECU
+    ///
+    /// ## Returns
+    /// The routine status record returned by the ECU
+    fn routine_control(&mut self, control: UdsRoutineControlType, routine_id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![UdsService::RoutineControl as u8, control.id(), (routine_id >> 8) as u8, routine_id as u8];
+        req.extend_from_slice(params);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &req[1..4]).map(|d| d.to_vec())
+    }
+
+    /// Controls an input or output of the ECU
+    ///
+    /// ## Arguments
+    /// * did - The data identifier of the input or output
+    /// * param - How to control the input or output
+    /// * state - The state to set, followed by the control enable mask if the ECU requires one.
+    ///   This is only used for adjustments
+    ///
+    /// ## Returns
+    /// The current state of the input or output, as reported by the ECU
+    fn io_control_by_identifier(&mut self, did: u16, param: UdsIoControlParameter, state: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut req = vec![UdsService::InputOutputControlByIdentifier as u8, (did >> 8) as u8, did as u8, param.id()];
+        req.extend_from_slice(state);
+        let res = self.send_command_with_response(&req)?;
+        strip_echo(&res, &req[1..4]).map(|d| d.to_vec())
+    }
+
+    /// Tells the ECU that the tester is still connected, so it stays in its current session
+    ///
+    /// ## Arguments
+    /// * response_required - If false, the ECU is told not to respond
+    fn tester_present(&mut self, response_required: bool) -> ProtocolResult<()> {
+        if response_required {
+            self.send_command_with_response(&[UdsService::TesterPresent as u8, 0x00]).map(|_| ())
+        } else {
+            self.send_command(&[UdsService::TesterPresent as u8, SUPPRESS_POSITIVE_RESPONSE])
+        }
+    }
+}
+
+/// UDS client for a single ECU
+#[derive(Debug, Clone)]
+pub struct UdsClient<A: AdapterHardware> {
+    engine: RequestEngine<A>
+}
+
+impl<A: AdapterHardware> UdsClient<A> {
+    /// Creates a new UDS client
+    ///
+    /// ## Arguments
+    /// * channel - The channel the ECU is connected to
+    /// * tx_id - The CAN ID requests are sent to the ECU with
+    /// * rx_id - The CAN ID the ECU responds with
+    pub fn new(channel: DiagChannel<A>, tx_id: u32, rx_id: u32) -> ProtocolResult<Self> {
+        Ok(Self { engine: RequestEngine::new(channel, tx_id, rx_id, DiagProtocol::UDS)? })
+    }
+
+    /// Creates a UDS client from an existing request engine. Clones of the engine share the ECU
+    pub fn from_engine(engine: RequestEngine<A>) -> Self {
+        Self { engine }
+    }
+
+    pub fn engine(&self) -> &RequestEngine<A> {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut RequestEngine<A> {
+        &mut self.engine
+    }
+}
+
+impl<A: AdapterHardware> GenericProtocolServer for UdsClient<A> {
+    fn send_command_with_response(&mut self, send: &[u8]) -> ProtocolResult<Vec<u8>> {
+        self.engine.send_request(send)
+    }
+
+    fn send_command(&mut self, send: &[u8]) -> ProtocolResult<()> {
+        self.engine.send_request_no_response(send)
+    }
+
+    fn read_dtcs(&mut self) -> ProtocolResult<Vec<DTC>> {
+        Ok(self.report_dtc_by_status_mask(0xFF)?.records.into_iter().map(DTC::from).collect())
+    }
+}
+
+impl<A: AdapterHardware> UdsServer for UdsClient<A> {}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::dtc::EnvironmentField;
+    use hardware::{AdapterChannel, SimAdapter};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Number of times the freeze frames were listed
+    static SNAPSHOT_LISTS: AtomicU32 = AtomicU32::new(0);
+
+    fn sim_ecu(_: AdapterChannel, id: u32, req: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        if id != 0x0744 {
+            return Vec::new();
+        }
+        let res = match req {
+            [0x10, 0x03] => vec![0x50, 0x03, 0x00, 0x32, 0x01, 0xF4],
+            [0x22, 0xF1, 0x90, 0xF1, 0x8C] => [&[0x62, 0xF1, 0x90][..], b"WDD2210001A123456", &[0xF1, 0x8C], b"1234"].concat(),
+            [0x19, 0x02, mask] => vec![0x59, 0x02, *mask, 0x90, 0x12, 0x34, 0x09, 0xC1, 0x00, 0x01, 0x04],
+            [0x19, 0x03] => {
+                SNAPSHOT_LISTS.fetch_add(1, Ordering::Relaxed);
+                vec![0x59, 0x03, 0x90, 0x12, 0x34, 0x01]
+            },
+            [0x19, 0x04, 0x90, 0x12, 0x34, 0x01] => vec![0x59, 0x04, 0x90, 0x12, 0x34, 0x09, 0x01, 0x01, 0xF4, 0x0D, 0x32],
+            [0x19, 0x06, a, b, c, 0x01] => vec![0x59, 0x06, *a, *b, *c, 0x09, 0x01, 0x07, 0x00, 0x30, 0x39],
+            [0x19, 0x06, ..] => vec![0x7F, 0x19, 0x31],
+            [0x31, 0x01, hi, lo] => vec![0x71, 0x01, *hi, *lo, 0x01],
+            [0x28, ctrl, _] => vec![0x68, *ctrl],
+            [0x3E, 0x80] => return Vec::new(),
+            _ => vec![0x7F, req[0], 0x31]
+        };
+        vec![(0x04C4, res)]
+    }
+
+    fn client() -> UdsClient<SimAdapter> {
+        let mut sim = SimAdapter::new(sim_ecu);
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        UdsClient::new(channel, 0x0744, 0x04C4).unwrap()
+    }
+
+    #[test]
+    pub fn test_uds_services() {
+        let mut uds = client();
+        let timing = uds.diagnostic_session_control(UdsSession::Extended).unwrap();
+        assert_eq!(UdsSessionTiming { p2_max_ms: 50, p2_star_max_ms: 5000 }, timing);
+        let dids = uds.read_data_by_identifiers(&[(0xF190, Some(17)), (0xF18C, None)]).unwrap();
+        assert_eq!(b"WDD2210001A123456".to_vec(), dids[0].1);
+        assert_eq!((0xF18C, b"1234".to_vec()), dids[1]);
+        assert_eq!(vec![0x01], uds.routine_control(UdsRoutineControlType::Start, 0x0203, &[]).unwrap());
+        uds.communication_control(UdsCommunicationControl::DisableRxAndTx, UdsCommunicationType::All).unwrap();
+        uds.tester_present(false).unwrap();
+        assert!(matches!(uds.read_data_by_identifier(0x0100), Err(ProtocolError::ECUError { code: 0x31, .. })));
+    }
+
+    #[test]
+    pub fn test_uds_dtcs() {
+        let mut uds = client();
+        let list = uds.report_dtc_by_status_mask(0x09).unwrap();
+        assert_eq!(0x09, list.availability_mask);
+        assert_eq!(vec![UdsDtcRecord { code: 0x901234, status: 0x09 }, UdsDtcRecord { code: 0xC10001, status: 0x04 }], list.records);
+        assert!(matches!(list.records[1].state(), DTCState::Pending));
+        let dtcs = uds.read_dtcs().unwrap();
+        assert_eq!("B101234", dtcs[0].code);
+        assert!(matches!(dtcs[0].state, DTCState::Active));
+        assert_eq!("U010001", dtcs[1].code);
+
+        let layout = EnvironmentLayout {
+            frequency_counter: Some(EnvironmentField { record_number: Some(0x01), offset: 0, len: 1 }),
+            extended_data_records: vec![0x01, 0x02],
+            ..Default::default()
+        };
+        let dtcs = uds.read_dtcs_with_environment(Some(&layout)).unwrap();
+        assert_eq!(1, SNAPSHOT_LISTS.load(Ordering::Relaxed));
+        let env = dtcs[0].environment.as_ref().unwrap();
+        assert_eq!(vec![FreezeFrame { record_number: 0x01, identifier_count: 0x01, data: vec![0xF4, 0x0D, 0x32] }], env.freeze_frames);
+        assert_eq!(1, env.extended_data.len());
+        assert_eq!(Some(7), env.frequency_counter);
+        assert!(dtcs[1].environment.as_ref().unwrap().freeze_frames.is_empty());
+    }
+}
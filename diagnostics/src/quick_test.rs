@@ -0,0 +1,300 @@
+//! Vehicle wide ECU scan (Quick test)
+//!
+//! Every ECU the vehicle might have is probed with a short response timeout, so absent ECUs only cost
+//! a fraction of a second. ECUs are probed in parallel over the shared [DiagChannel], and the variants of
+//! an ECU (Such as the KWP2000 and UDS generations of the same module) are tried in order, stopping at the
+//! first variant which responds, rather than trying every known variant like Xentry does.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hardware::AdapterHardware;
+use serde::{Deserialize, Serialize};
+
+use crate::identification::EcuIdentification;
+use crate::protocols::detect::detect_protocol;
+use crate::protocols::kwp2000::{Kwp2000Client, Kwp2000Server};
+use crate::protocols::uds::{UdsClient, UdsServer};
+use crate::protocols::{DiagChannel, DiagProtocol, EcuTiming, ProtocolResult};
+
+/// DTC group which selects all DTCs of a KWP2000 ECU
+const KWP_ALL_DTCS: u16 = 0xFF00;
+
+/// One possible variant of an ECU, and how to address it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariantCandidate {
+    pub name: String,
+    /// CAN ID requests are sent to
+    pub tx_id: u32,
+    /// CAN ID the ECU responds with
+    pub rx_id: u32,
+    /// Protocol of the variant. If None, the protocol is detected when the variant is probed
+    #[serde(default)]
+    pub protocol: Option<DiagProtocol>
+}
+
+/// An ECU which might be fitted to the vehicle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuCandidate {
+    /// Short name of the ECU, such as `ME` or `EZS`
+    pub name: String,
+    /// Variants of the ECU, in the order they are probed. Put the most common variant first
+    pub variants: Vec<VariantCandidate>
+}
+
+/// Quick test settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QuickTestConfig {
+    /// Time to wait for an ECU to respond to the TesterPresent probe (In ms)
+    pub probe_timeout_ms: u128,
+    /// Maximum number of ECUs probed at once. Adapters only support a limited number of ISO-TP filters
+    pub max_parallel: usize,
+    /// Status mask used to count the DTCs of UDS ECUs. The default counts DTCs which are failed or confirmed
+    pub uds_dtc_status_mask: u8
+}
+
+impl Default for QuickTestConfig {
+    fn default() -> Self {
+        Self {
+            probe_timeout_ms: 100,
+            max_parallel: 8,
+            uds_dtc_status_mask: 0x09
+        }
+    }
+}
+
+/// Result of an ECU which responded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuReport {
+    /// Name of the ECU
+    pub name: String,
+    /// The variant which responded, with its protocol filled in if it was detected
+    pub variant: VariantCandidate,
+    /// Identification of the ECU, or None if it could not be read
+    pub identification: Option<EcuIdentification>,
+    /// Number of DTCs stored in the ECU, or None if they could not be read
+    pub dtc_count: Option<u32>,
+    /// Errors which occurred whilst reading the ECU
+    pub errors: Vec<String>
+}
+
+/// Result of a quick test
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct VehicleReport {
+    /// ECUs which responded, in the order they were given
+    pub ecus: Vec<EcuReport>,
+    /// Names of the ECUs where no variant responded
+    pub not_responding: Vec<String>,
+    /// Time the scan took (In ms)
+    pub scan_time_ms: u128
+}
+
+impl VehicleReport {
+    /// Total number of DTCs stored across all ECUs
+    pub fn total_dtcs(&self) -> u32 {
+        self.ecus.iter().filter_map(|e| e.dtc_count).sum()
+    }
+}
+
+/// Scans the vehicle for ECUs
+///
+/// ## Arguments
+/// * channel - The diagnostic channel. ECUs are registered on it whilst probed, and unregistered afterwards
+///   unless they were already registered before the scan
+/// * candidates - ECUs which might be fitted to the vehicle
+/// * config - Quick test settings
+pub fn run_quick_test<A: AdapterHardware + 'static>(channel: &DiagChannel<A>, candidates: &[EcuCandidate], config: QuickTestConfig) -> VehicleReport {
+    let start = Instant::now();
+    let groups = group_by_response_id(candidates);
+    let worker_count = config.max_parallel.clamp(1, groups.len().max(1));
+    let queue = Arc::new(Mutex::new(groups.into_iter().collect::<VecDeque<_>>()));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(candidates.len())));
+
+    let workers = (0..worker_count).map(|_| {
+        let (channel, queue, results) = (channel.clone(), queue.clone(), results.clone());
+        std::thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop_front();
+            let group = match next {
+                Some(g) => g,
+                None => break
+            };
+            for (idx, candidate) in group {
+                let report = probe_ecu(&channel, &candidate, &config);
+                results.lock().unwrap().push((idx, candidate.name, report));
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        let _ = w.join();
+    }
+
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.sort_by_key(|(idx, _, _)| *idx);
+    let mut report = VehicleReport::default();
+    for (_, name, ecu) in results {
+        match ecu {
+            Some(ecu) => report.ecus.push(ecu),
+            None => report.not_responding.push(name)
+        }
+    }
+    report.scan_time_ms = start.elapsed().as_millis();
+    report
+}
+
+/// Groups candidates which share a response ID, so that each group can be probed by a single worker.
+/// Candidates keep their index, so the report can be put back in order
+fn group_by_response_id(candidates: &[EcuCandidate]) -> Vec<Vec<(usize, EcuCandidate)>> {
+    let mut groups: Vec<Vec<(usize, EcuCandidate)>> = Vec::new();
+    for (idx, candidate) in candidates.iter().cloned().enumerate() {
+        let shares_id = |(_, other): &(usize, EcuCandidate)| other.variants.iter().any(|o| candidate.variants.iter().any(|v| v.rx_id == o.rx_id));
+        // A candidate can join groups which did not share an ID before
+        let (sharing, mut rest): (Vec<_>, Vec<_>) = groups.into_iter().partition(|g| g.iter().any(shares_id));
+        let mut group = sharing.into_iter().flatten().collect::<Vec<_>>();
+        group.push((idx, candidate));
+        rest.push(group);
+        groups = rest;
+    }
+    groups
+}
+
+/// Tries each variant of an ECU until one responds
+fn probe_ecu<A: AdapterHardware>(channel: &DiagChannel<A>, candidate: &EcuCandidate, config: &QuickTestConfig) -> Option<EcuReport> {
+    candidate.variants.iter().find_map(|variant| {
+        // ECUs which a client already has open must stay registered
+        let added = channel.register_ecus(&[(variant.tx_id, variant.rx_id)]).ok()?;
+        let res = probe_variant(channel, variant, config);
+        for rx_id in added {
+            let _ = channel.unregister_ecu(rx_id);
+        }
+        match res {
+            Ok(Some((protocol, identification, dtc_count, errors))) => Some(EcuReport {
+                name: candidate.name.clone(),
+                variant: VariantCandidate { protocol: Some(protocol), ..variant.clone() },
+                identification,
+                dtc_count,
+                errors
+            }),
+            // The variant did not respond, or it could not be registered on the channel
+            _ => None
+        }
+    })
+}
+
+type VariantResult = (DiagProtocol, Option<EcuIdentification>, Option<u32>, Vec<String>);
+
+/// Probes an ECU variant with TesterPresent (Or protocol detection, if its protocol is not known),
+/// then reads its identification and DTC count
+///
+/// ## Returns
+/// None if the ECU did not respond within the probe timeout
+fn probe_variant<A: AdapterHardware>(channel: &DiagChannel<A>, variant: &VariantCandidate, config: &QuickTestConfig) -> ProtocolResult<Option<VariantResult>> {
+    let probe_timing = EcuTiming { p2_ms: config.probe_timeout_ms, busy_retries: 0, ..Default::default() };
+    let (protocol, probe) = match variant.protocol {
+        Some(protocol) => (protocol, true),
+        None => match detect_protocol(channel, variant.tx_id, variant.rx_id, config.probe_timeout_ms) {
+            Ok(protocol) => (protocol, false),
+            Err(e) if e.is_timeout() => return Ok(None),
+            Err(e) => return Err(e)
+        }
+    };
+    let (ident, dtcs) = match protocol {
+        DiagProtocol::KWP2000 => {
+            let mut client = Kwp2000Client::new(channel.clone(), variant.tx_id, variant.rx_id)?;
+            client.engine_mut().set_timing(probe_timing);
+            if probe && is_absent(client.tester_present(true)) {
+                return Ok(None);
+            }
+            client.engine_mut().set_timing(EcuTiming::default());
+            let ident = EcuIdentification::read_kwp(&mut client);
+            (ident, client.read_dtc_by_status(KWP_ALL_DTCS).map(|d| d.len() as u32))
+        },
+        DiagProtocol::UDS => {
+            let mut client = UdsClient::new(channel.clone(), variant.tx_id, variant.rx_id)?;
+            client.engine_mut().set_timing(probe_timing);
+            if probe && is_absent(client.tester_present(true)) {
+                return Ok(None);
+            }
+            client.engine_mut().set_timing(EcuTiming::default());
+            let ident = EcuIdentification::read_uds(&mut client);
+            (ident, client.report_number_of_dtc_by_status_mask(config.uds_dtc_status_mask).map(|c| c.count as u32))
+        }
+    };
+    let errors = [ident.as_ref().err(), dtcs.as_ref().err()].iter().flatten().map(|e| format!("{:?}", e)).collect();
+    Ok(Some((protocol, ident.ok(), dtcs.ok(), errors)))
+}
+
+/// Returns true if the ECU did not respond to the probe at all. A negative response still means the ECU is fitted
+fn is_absent(res: ProtocolResult<()>) -> bool {
+    matches!(res, Err(e) if e.is_timeout())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hardware::SimAdapter;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn variant(name: &str, tx_id: u32, rx_id: u32, protocol: Option<DiagProtocol>) -> VariantCandidate {
+        VariantCandidate { name: name.into(), tx_id, rx_id, protocol }
+    }
+
+    #[test]
+    pub fn test_quick_test() {
+        let skipped_variant = Arc::new(AtomicU32::new(0));
+        let skipped = skipped_variant.clone();
+        let mut sim = SimAdapter::new(move |_, id, req| {
+            match (id, req) {
+                // ME (KWP2000)
+                (0x07E0, [0x3E, 0x01]) => vec![(0x07E8, vec![0x7E])],
+                (0x07E0, [0x1A, 0x86]) => vec![(0x07E8, [0x5A, 0x86, 0x02, 0x71, 0x53, 0x01, 0x01, 0x03, 0x48, 0x04, 0x12, 0x00, 0x01, 0x00, 0x12, 0x05, 0x20, 0x05].to_vec())],
+                (0x07E0, [0x1A, _]) => vec![(0x07E8, vec![0x7F, 0x1A, 0x12])],
+                (0x07E0, [0x18, 0x02, 0xFF, 0x00]) => vec![(0x07E8, vec![0x58, 0x02, 0x90, 0x01, 0xE0, 0x01, 0x00, 0x60])],
+                // EZS (UDS) on its second variant
+                (0x0745, [0x3E, 0x00]) => vec![(0x04C5, vec![0x7E, 0x00])],
+                (0x0745, [0x22, 0xF1, 0x11]) => vec![(0x04C5, vec![0x62, 0xF1, 0x11, 0x41, 0x31, 0x36, 0x39])],
+                (0x0745, [0x22, ..]) => vec![(0x04C5, vec![0x7F, 0x22, 0x31])],
+                (0x0745, [0x19, 0x01, 0x09]) => vec![(0x04C5, vec![0x59, 0x01, 0xFF, 0x01, 0x00, 0x03])],
+                // AGW (UDS), whose protocol is detected
+                (0x074A, [0x1A, _]) => vec![(0x04CA, vec![0x7F, 0x1A, 0x11])],
+                (0x074A, _) => vec![(0x04CA, vec![0x7F, req[0], 0x31])],
+                (0x07E1, _) => {
+                    skipped.fetch_add(1, Ordering::Relaxed);
+                    Vec::new()
+                },
+                _ => Vec::new()
+            }
+        });
+        sim.open_device().unwrap();
+        let channel = DiagChannel::open(sim, 500_000, &[]).unwrap();
+        // ME is already open in another client
+        channel.register_ecu(0x07E0, 0x07E8).unwrap();
+        let candidates = vec![
+            EcuCandidate { name: "ME".into(), variants: vec![variant("ME97", 0x07E0, 0x07E8, Some(DiagProtocol::KWP2000)), variant("ME97_UDS", 0x07E1, 0x07E9, Some(DiagProtocol::UDS))] },
+            // Shares its response ID with ME, so is probed after it
+            EcuCandidate { name: "CDI".into(), variants: vec![variant("CDI3", 0x07E0, 0x07E8, Some(DiagProtocol::KWP2000))] },
+            EcuCandidate { name: "SAM".into(), variants: vec![variant("SAM_F", 0x0740, 0x04C0, Some(DiagProtocol::KWP2000))] },
+            EcuCandidate { name: "EZS".into(), variants: vec![variant("EZS_KWP", 0x0744, 0x04C4, Some(DiagProtocol::KWP2000)), variant("EZS", 0x0745, 0x04C5, Some(DiagProtocol::UDS))] },
+            EcuCandidate { name: "AGW".into(), variants: vec![variant("AGW", 0x074A, 0x04CA, None)] },
+        ];
+        let report = run_quick_test(&channel, &candidates, QuickTestConfig { probe_timeout_ms: 50, ..Default::default() });
+
+        assert_eq!(vec!["SAM".to_string()], report.not_responding);
+        assert_eq!(4, report.ecus.len());
+        assert_eq!("ME97", report.ecus[0].variant.name);
+        assert_eq!(Some(2), report.ecus[0].dtc_count);
+        assert!(report.ecus[0].identification.is_some());
+        assert_eq!(report.ecus[0].identification, report.ecus[1].identification);
+        assert_eq!("EZS", report.ecus[2].variant.name);
+        assert_eq!(Some(DiagProtocol::UDS), report.ecus[2].variant.protocol);
+        assert_eq!(Some(3), report.ecus[2].dtc_count);
+        assert_eq!(Some(DiagProtocol::UDS), report.ecus[3].variant.protocol);
+        assert!(report.ecus[3].dtc_count.is_none());
+        assert_eq!(7, report.total_dtcs());
+        assert!(channel.is_registered(0x07E8));
+        assert!(!channel.is_registered(0x04C5));
+        assert_eq!(4, group_by_response_id(&candidates).len());
+        // ME was identified by its first variant, so its second variant is never probed
+        assert_eq!(0, skipped_variant.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,232 @@
+//! Routines (Adaptation resets, DPF regeneration, steering angle calibration...)
+//!
+//! A routine is started, and its results are then polled until the ECU reports that it has finished.
+//! How an ECU reports that a routine is still running differs between ECUs. KWP2000 ECUs usually respond
+//! with [NRC_ROUTINE_NOT_COMPLETE], whilst UDS ECUs usually report the state in the routine status record,
+//! which can be decoded by supplying a [RoutineStatusDecoder].
+
+use std::time::{Duration, Instant};
+
+use hardware::AdapterHardware;
+use logger::Logger;
+
+use crate::protocols::detect::EcuClient;
+use crate::protocols::nrc::{NRC_BUSY_REPEAT_REQUEST, NRC_ROUTINE_NOT_COMPLETE};
+use crate::protocols::{ProtocolError, ProtocolResult, RequestEngine};
+
+/// State of a routine, decoded from its status record
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoutineStatus {
+    /// The routine is still running. Progress is in percent, if the ECU reports it
+    Running { progress: Option<u8> },
+    /// The routine finished successfully
+    Completed,
+    /// The routine finished, but failed
+    Failed
+}
+
+/// Decodes the status record returned by RequestRoutineResults
+pub type RoutineStatusDecoder = dyn Fn(&[u8]) -> RoutineStatus + Send + Sync;
+
+/// Routine polling settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RoutineConfig {
+    /// Time between requests for the results of the routine (In ms)
+    pub poll_interval_ms: u64,
+    /// Maximum time the routine may take (In ms)
+    pub timeout_ms: u64,
+    /// Stop the routine if it does not finish in time
+    pub stop_on_timeout: bool
+}
+
+impl Default for RoutineConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 250,
+            timeout_ms: 30_000,
+            stop_on_timeout: true
+        }
+    }
+}
+
+/// Progress of a running routine, reported whilst polling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineProgress {
+    /// Time since the routine was started
+    pub elapsed: Duration,
+    /// Progress in percent, if the ECU reports it
+    pub progress: Option<u8>,
+    /// The last status record returned by the ECU. Empty if the ECU responded that the routine is not complete
+    pub status_record: Vec<u8>
+}
+
+/// Result of a routine which finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineResult {
+    /// [RoutineStatus::Completed] or [RoutineStatus::Failed]
+    pub status: RoutineStatus,
+    /// The status record the ECU returned when the routine was started
+    pub start_record: Vec<u8>,
+    /// The final status record of the routine
+    pub result_record: Vec<u8>,
+    /// Time the routine took
+    pub duration: Duration
+}
+
+/// Runs routines on an ECU
+pub struct RoutineRunner<A: AdapterHardware> {
+    engine: RequestEngine<A>,
+    config: RoutineConfig,
+    decoder: Box<RoutineStatusDecoder>,
+    logger: Logger
+}
+
+impl<A: AdapterHardware> std::fmt::Debug for RoutineRunner<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoutineRunner")
+            .field("engine", &self.engine)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<A: AdapterHardware> RoutineRunner<A> {
+    /// Creates a routine runner. By default, any status record returned by the ECU means the routine is complete
+    ///
+    /// ## Arguments
+    /// * engine - The request engine of the ECU
+    /// * config - Polling settings
+    pub fn new(engine: RequestEngine<A>, config: RoutineConfig) -> Self {
+        Self {
+            engine,
+            config,
+            decoder: Box::new(|_| RoutineStatus::Completed),
+            logger: Logger::new("Routine")
+        }
+    }
+
+    /// Sets how status records of the routine are decoded
+    pub fn with_decoder<F: Fn(&[u8]) -> RoutineStatus + Send + Sync + 'static>(mut self, decoder: F) -> Self {
+        self.decoder = Box::new(decoder);
+        self
+    }
+
+    /// Starts a routine, and polls its results until it finishes
+    ///
+    /// ## Arguments
+    /// * routine_id - The local identifier (KWP2000) or routine identifier (UDS) of the routine
+    /// * params - Parameters the routine is started with
+    /// * on_progress - Called after every poll whilst the routine is running
+    ///
+    /// ## Returns
+    /// The result of the routine. If the routine does not finish in time, an error is returned
+    pub fn run<F: FnMut(&RoutineProgress)>(&self, routine_id: u16, params: &[u8], mut on_progress: F) -> ProtocolResult<RoutineResult> {
+        let start = Instant::now();
+        let start_record = self.start(routine_id, params)?;
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        loop {
+            std::thread::sleep(Duration::from_millis(self.config.poll_interval_ms));
+            let (status, record) = match self.request_results(routine_id) {
+                Ok(record) => ((self.decoder)(&record), record),
+                Err(ProtocolError::ECUError { code: NRC_ROUTINE_NOT_COMPLETE | NRC_BUSY_REPEAT_REQUEST, .. }) => {
+                    (RoutineStatus::Running { progress: None }, Vec::new())
+                },
+                Err(e) => return Err(e)
+            };
+            match status {
+                RoutineStatus::Running { progress } => on_progress(&RoutineProgress { elapsed: start.elapsed(), progress, status_record: record }),
+                status => return Ok(RoutineResult { status, start_record, result_record: record, duration: start.elapsed() })
+            }
+            if start.elapsed() >= timeout {
+                if self.config.stop_on_timeout {
+                    if let Err(e) = self.stop(routine_id, &[]) {
+                        self.logger.log_warn(format!("Could not stop routine 0x{:04X}: {:?}", routine_id, e));
+                    }
+                }
+                return Err(ProtocolError::ServerError(format!("Routine 0x{:04X} did not finish within {}ms", routine_id, self.config.timeout_ms)));
+            }
+        }
+    }
+
+    /// Starts a routine without waiting for it to finish
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn start(&self, routine_id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        EcuClient::from_engine(self.engine.clone()).start_routine(routine_id, params)
+    }
+
+    /// Stops a running routine
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn stop(&self, routine_id: u16, params: &[u8]) -> ProtocolResult<Vec<u8>> {
+        EcuClient::from_engine(self.engine.clone()).stop_routine(routine_id, params)
+    }
+
+    /// Requests the results of a routine
+    ///
+    /// ## Returns
+    /// The status record returned by the ECU
+    pub fn request_results(&self, routine_id: u16) -> ProtocolResult<Vec<u8>> {
+        EcuClient::from_engine(self.engine.clone()).request_routine_results(routine_id)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocols::test::sim_engine;
+    use crate::protocols::DiagProtocol;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    pub fn test_kwp_routine() {
+        let polls = Arc::new(AtomicU8::new(0));
+        let p = polls.clone();
+        let (_, engine) = sim_engine(DiagProtocol::KWP2000, move |req| {
+            match req {
+                [0x31, 0x05] => vec![0x71, 0x05],
+                [0x33, 0x05] => if p.fetch_add(1, Ordering::Relaxed) < 2 { vec![0x7F, 0x33, 0x23] } else { vec![0x73, 0x05, 0x00] },
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+        let runner = RoutineRunner::new(engine, RoutineConfig { poll_interval_ms: 5, ..Default::default() });
+
+        let mut progress = 0;
+        let res = runner.run(0x05, &[], |_| progress += 1).unwrap();
+        assert_eq!(2, progress);
+        assert_eq!(RoutineStatus::Completed, res.status);
+        assert_eq!(vec![0x00], res.result_record);
+    }
+
+    #[test]
+    pub fn test_uds_routine_timeout() {
+        let stopped = Arc::new(AtomicU8::new(0));
+        let s = stopped.clone();
+        let (_, engine) = sim_engine(DiagProtocol::UDS, move |req| {
+            match req {
+                [0x31, 0x01, 0x02, 0x03] => vec![0x71, 0x01, 0x02, 0x03],
+                // Routine info byte: 0x01 running, followed by the progress in percent
+                [0x31, 0x03, 0x02, 0x03] => vec![0x71, 0x03, 0x02, 0x03, 0x01, 0x32],
+                [0x31, 0x02, 0x02, 0x03] => {
+                    s.fetch_add(1, Ordering::Relaxed);
+                    vec![0x71, 0x02, 0x02, 0x03]
+                },
+                _ => vec![0x7F, req[0], 0x11]
+            }
+        });
+        let runner = RoutineRunner::new(engine, RoutineConfig { poll_interval_ms: 5, timeout_ms: 50, stop_on_timeout: true })
+            .with_decoder(|record| match record {
+                [0x01, progress, ..] => RoutineStatus::Running { progress: Some(*progress) },
+                [0x02, ..] => RoutineStatus::Completed,
+                _ => RoutineStatus::Failed
+            });
+
+        let mut last = None;
+        assert!(runner.run(0x0203, &[], |p| last = p.progress).is_err());
+        assert_eq!(Some(50), last);
+        assert_eq!(1, stopped.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,121 @@
+//! Automatic CAN bitrate detection
+//!
+//! Mercedes vehicles put diagnostics on different buses running at different speeds
+//! (83.3kbps interior CAN on older vehicles, 125kbps and 500kbps). The detection listens on the bus at
+//! each candidate bitrate without transmitting anything, and picks the bitrate that
+//! received valid traffic without any errors.
+//!
+//! Listening at the wrong bitrate with an active CAN controller would acknowledge frames and send error
+//! frames onto the vehicle bus, so detection is only supported by adapters which can put their controller
+//! into a listen-only mode. Currently that is only SocketCAN. SAE J2534 has no listen-only mode, so
+//! Passthru adapters refuse detection, and the bitrate has to be picked by the user.
+
+use serde::{Deserialize, Serialize};
+
+use crate::HardwareError;
+
+/// Bitrates found on Mercedes vehicles, in the order they should be tried
+pub const MERCEDES_CAN_BITRATES: [u32; 3] = [500_000, 125_000, 83_333];
+
+/// Default time (In ms) to listen on the bus at each bitrate
+pub const DEFAULT_LISTEN_MS: u128 = 500;
+
+/// Result of listening on the bus at a single bitrate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BitrateProbe {
+    /// Bitrate of the bus (In bps)
+    pub bitrate: u32,
+    /// Number of valid frames received
+    pub frames: usize,
+    /// Number of unique CAN IDs received
+    pub unique_ids: usize,
+    /// Number of CAN error frames or adapter failures seen whilst listening
+    pub errors: usize
+}
+
+impl BitrateProbe {
+    /// Returns true if the probe saw traffic, and no errors
+    pub fn is_valid(&self) -> bool {
+        self.frames > 0 && self.errors == 0
+    }
+}
+
+/// Result of [AdapterHardware::detect_can_bitrate]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BitrateDetection {
+    /// The detected bitrate of the bus. None implies no bitrate received valid traffic
+    pub bitrate: Option<u32>,
+    /// Results of every bitrate that was tried
+    pub probes: Vec<BitrateProbe>
+}
+
+impl BitrateDetection {
+    /// Picks the best bitrate from a list of probes. The valid probe with the most traffic wins
+    pub fn from_probes(probes: Vec<BitrateProbe>) -> Self {
+        let bitrate = probes.iter()
+            .filter(|p| p.is_valid())
+            .max_by_key(|p| (p.unique_ids, p.frames))
+            .map(|p| p.bitrate);
+        Self { bitrate, probes }
+    }
+}
+
+/// Counts valid frames received at a bitrate, as well as the unique IDs seen
+#[derive(Debug, Default)]
+pub(crate) struct ProbeCounter {
+    frames: usize,
+    ids: Vec<u32>,
+    errors: usize
+}
+
+impl ProbeCounter {
+    pub(crate) fn add_frame(&mut self, id: u32) {
+        self.frames += 1;
+        if !self.ids.contains(&id) {
+            self.ids.push(id)
+        }
+    }
+
+    pub(crate) fn add_error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub(crate) fn finish(self, bitrate: u32) -> BitrateProbe {
+        BitrateProbe {
+            bitrate,
+            frames: self.frames,
+            unique_ids: self.ids.len(),
+            errors: self.errors
+        }
+    }
+}
+
+/// Error returned by adapters which cannot listen to the bus without taking part in it.
+/// This is the default implementation of [AdapterHardware::detect_can_bitrate](crate::AdapterHardware::detect_can_bitrate).
+pub fn passive_probing_not_supported() -> HardwareError {
+    HardwareError::Other("Passive bitrate probing is not supported by this adapter".into())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_pick_bitrate() {
+        let probes = vec![
+            BitrateProbe { bitrate: 500_000, frames: 0, unique_ids: 0, errors: 0 },
+            BitrateProbe { bitrate: 125_000, frames: 40, unique_ids: 3, errors: 12 },
+            BitrateProbe { bitrate: 83_333, frames: 350, unique_ids: 21, errors: 0 },
+        ];
+        assert_eq!(Some(83_333), BitrateDetection::from_probes(probes).bitrate);
+    }
+
+    #[test]
+    pub fn test_no_traffic() {
+        let probes = vec![
+            BitrateProbe { bitrate: 500_000, frames: 0, unique_ids: 0, errors: 0 },
+            BitrateProbe { bitrate: 125_000, frames: 0, unique_ids: 0, errors: 4 },
+        ];
+        assert_eq!(None, BitrateDetection::from_probes(probes).bitrate);
+    }
+}
@@ -1 +1,4 @@
-pub mod passthru;
\ No newline at end of file
+pub mod passthru;
+pub mod sim;
+#[cfg(target_os = "linux")]
+pub mod socketcan;
\ No newline at end of file
@@ -2,12 +2,20 @@ use j2534_rust::FilterType::FLOW_CONTROL_FILTER;
 use j2534_rust::*;
 use lazy_static::lazy_static;
 use libloading::Library;
+use logger::Logger;
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
 use std::{ffi::*, fmt};
 
+use crate::bitrate::BitrateDetection;
+use crate::data_structures::HwDataFrame;
+use crate::{AdapterBuffer, AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError, HardwareResult, IoctlIdentifier, LinInitType};
+
 lazy_static! {
-    pub static ref DRIVER: Arc<RwLock<Option<PassthruDrv>>> = Arc::new(RwLock::new(None));
+    /// Passthru libraries which are currently loaded, keyed by their path. Devices which share a library
+    /// also share its loaded instance, and the library is unloaded once no device is using it
+    static ref LOADED_LIBRARIES: Mutex<HashMap<String, Weak<PassthruDrv>>> = Mutex::new(HashMap::new());
 }
 
 #[cfg(windows)]
@@ -89,8 +97,6 @@ pub struct DrvVersion {
 pub struct PassthruDrv {
     /// Loaded library to interface with the device
     lib: Arc<libloading::Library>,
-    /// Is the device currently connected?
-    is_connected: bool,
     /// Open device connection
     open_fn: PassThruOpenFn,
     /// Close device connection
@@ -124,7 +130,6 @@ pub struct PassthruDrv {
 impl fmt::Debug for PassthruDrv {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PassthruDrv")
-            .field("is_connected", &self.is_connected)
             .field("library", &self.lib)
             .finish()
     }
@@ -182,7 +187,6 @@ impl PassthruDrv {
 
             Ok(PassthruDrv {
                 lib: Arc::new(lib),
-                is_connected: false,
                 open_fn,
                 close_fn,
                 connect_fn,
@@ -201,28 +205,30 @@ impl PassthruDrv {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn is_connected(&self) -> bool {
-        self.is_connected
+    /// Returns the already loaded instance of a library if another device is using it,
+    /// otherwise the library is loaded
+    pub fn load_shared(path: &str) -> std::result::Result<Arc<PassthruDrv>, libloading::Error> {
+        let mut libs = LOADED_LIBRARIES.lock().unwrap();
+        if let Some(drv) = libs.get(path).and_then(|d| d.upgrade()) {
+            return Ok(drv);
+        }
+        let drv = Arc::new(PassthruDrv::load_lib(path.to_string())?);
+        libs.retain(|_, d| d.strong_count() > 0);
+        libs.insert(path.to_string(), Arc::downgrade(&drv));
+        Ok(drv)
     }
 
     //type PassThruOpenFn = unsafe extern "stdcall" fn(name: *const libc::c_void, device_id: *mut u32) -> i32;
-    pub fn open(&mut self) -> Result<u32> {
+    pub fn open(&self) -> Result<u32> {
         let mut id: u32 = 0;
         let res =
             unsafe { (&self.open_fn)(std::ptr::null() as *const libc::c_void, &mut id as *mut u32) };
-        if res == 0x00 {
-            self.is_connected = true;
-        }
         ret_res(res, id)
     }
 
     //type PassThruCloseFn = unsafe extern "stdcall" fn(device_id: u32) -> i32;
-    pub fn close(&mut self, dev_id: u32) -> Result<()> {
+    pub fn close(&self, dev_id: u32) -> Result<()> {
         let res = unsafe { (&self.close_fn)(dev_id) };
-        if res == 0x00 {
-            self.is_connected = false;
-        }
         ret_res(res, ())
     }
 
@@ -665,4 +671,434 @@ impl PassthruDevice {
             //drv: driver
         })
     }
-}
\ No newline at end of file
+}
+
+// Connect flags (SAE J2534-1)
+const CONNECT_ISO15765_ADDR_TYPE: u32 = 0x0000_0080;
+const CONNECT_CAN_29BIT_ID: u32 = 0x0000_0100;
+const CONNECT_ISO9141_NO_CHECKSUM: u32 = 0x0000_0200;
+
+// Transmit flags (SAE J2534-1)
+const TX_ISO15765_FRAME_PAD: u32 = 0x0000_0040;
+const TX_CAN_29BIT_ID: u32 = 0x0000_0100;
+
+// Receive status flags (SAE J2534-1). Messages with any of these set are indications, not vehicle data
+const RX_TX_MSG_TYPE: u32 = 0x0000_0001;
+const RX_START_OF_MESSAGE: u32 = 0x0000_0002;
+const RX_TX_DONE: u32 = 0x0000_0008;
+
+/// SBYTE_ARRAY structure used by the FIVE_BAUD_INIT IOCTL
+#[repr(C)]
+struct SByteArray {
+    num_of_bytes: u32,
+    byte_ptr: *mut u8
+}
+
+/// A logical channel opened on a [PassthruAdapter]
+#[derive(Debug)]
+struct PassthruChannel {
+    channel_type: AdapterChannel,
+    /// Passthru channel handle. The channel is only connected once its first filter is added,
+    /// as that is when the bus speed is known
+    handle: Option<u32>,
+    baud: u32,
+    flags: u32
+}
+
+#[derive(Debug, Default)]
+struct PassthruState {
+    /// Loaded library of the device. Only present whilst the device is open
+    drv: Option<Arc<PassthruDrv>>,
+    dev_id: Option<u32>,
+    channels: HashMap<u32, PassthruChannel>,
+    next_channel_id: u32
+}
+
+/// [AdapterHardware] implementation for Passthru (SAE J2534) devices
+///
+/// Each adapter owns its own device handle, so multiple Passthru devices can be open at once,
+/// even if they use different libraries. Clones of an adapter share the same device.
+#[derive(Debug, Clone)]
+pub struct PassthruAdapter {
+    device: PassthruDevice,
+    state: Arc<Mutex<PassthruState>>,
+    logger: Logger
+}
+
+/// Converts a Passthru error into a [HardwareError]. In the event of `ERR_FAILED`, the driver's
+/// description of the error is retrieved with `PassThruGetLastError()`
+fn to_hw_err(drv: &PassthruDrv, e: PassthruError) -> HardwareError {
+    if let PassthruError::ERR_FAILED = e {
+        if let Ok(desc) = drv.get_last_error() {
+            return HardwareError::HwApiError { code: e as u32, desc: desc.trim_matches(char::from(0)).to_string() }
+        }
+    }
+    e.into()
+}
+
+fn to_protocol(channel_type: AdapterChannel) -> Protocol {
+    match channel_type {
+        AdapterChannel::Can => Protocol::CAN,
+        AdapterChannel::IsoTp => Protocol::ISO15765,
+        AdapterChannel::Kwp => Protocol::ISO14230,
+        AdapterChannel::Obd => Protocol::ISO9141
+    }
+}
+
+fn blank_msg(protocol: Protocol) -> PASSTHRU_MSG {
+    PASSTHRU_MSG {
+        protocol_id: protocol as u32,
+        rx_status: 0,
+        tx_flags: 0,
+        timestamp: 0,
+        data_size: 0,
+        extra_data_size: 0,
+        data: [0; 4128]
+    }
+}
+
+fn has_can_id(channel_type: AdapterChannel) -> bool {
+    matches!(channel_type, AdapterChannel::Can | AdapterChannel::IsoTp)
+}
+
+/// Converts a frame into a Passthru message. CAN based messages have their ID stored
+/// in the first 4 bytes of the message data
+fn to_passthru_msg<T: HwDataFrame>(frame: &T, channel_type: AdapterChannel) -> PASSTHRU_MSG {
+    let mut msg = blank_msg(to_protocol(channel_type));
+    let mut data = Vec::with_capacity(frame.get_data().len() + 4);
+    if has_can_id(channel_type) {
+        data.extend_from_slice(&frame.get_id().to_be_bytes());
+        if frame.get_id() > 0x7FF {
+            msg.tx_flags |= TX_CAN_29BIT_ID;
+        }
+    }
+    if channel_type == AdapterChannel::IsoTp {
+        msg.tx_flags |= TX_ISO15765_FRAME_PAD;
+    }
+    data.extend_from_slice(frame.get_data());
+    let size = std::cmp::min(data.len(), msg.data.len());
+    msg.data[0..size].copy_from_slice(&data[0..size]);
+    msg.data_size = size as u32;
+    msg
+}
+
+fn from_passthru_msg<T: HwDataFrame>(msg: &PASSTHRU_MSG, channel_type: AdapterChannel) -> T {
+    let mut frame = T::default();
+    // The driver reports the size, so it is never trusted to fit the message
+    let data = &msg.data[0..(msg.data_size as usize).min(msg.data.len())];
+    if has_can_id(channel_type) && data.len() >= 4 {
+        frame.set_id(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+        frame.set_data(&data[4..]);
+    } else {
+        frame.set_data(data);
+    }
+    frame
+}
+
+/// Converts an IOCTL identifier into its Passthru configuration parameter ID and value
+fn to_sconfig(param: &IoctlIdentifier) -> SConfig {
+    let (parameter, value) = match *param {
+        IoctlIdentifier::ISO15765_STMIN(v) => (0x1F, v),
+        IoctlIdentifier::ISO15765_BS(v) => (0x1E, v),
+        IoctlIdentifier::P1_MIN(v) => (0x06, v),
+        IoctlIdentifier::P1_MAX(v) => (0x07, v),
+        IoctlIdentifier::P2_MIN(v) => (0x08, v),
+        IoctlIdentifier::P2_MAX(v) => (0x09, v),
+        IoctlIdentifier::P3_MIN(v) => (0x0A, v),
+        IoctlIdentifier::P3_MAX(v) => (0x0B, v),
+        IoctlIdentifier::P4_MIN(v) => (0x0C, v),
+        IoctlIdentifier::P4_MAX(v) => (0x0D, v),
+        IoctlIdentifier::W1(v) => (0x0E, v),
+        IoctlIdentifier::W2(v) => (0x0F, v),
+        IoctlIdentifier::W3(v) => (0x10, v),
+        IoctlIdentifier::W4(v) => (0x11, v),
+        IoctlIdentifier::W5(v) => (0x12, v),
+        IoctlIdentifier::TIDLE(v) => (0x13, v),
+        IoctlIdentifier::TINL(v) => (0x14, v),
+        IoctlIdentifier::TWUP(v) => (0x15, v),
+        IoctlIdentifier::PARITY(v) => (0x16, v as u32)
+    };
+    SConfig { parameter, value }
+}
+
+/// Writes a value read from the Passthru device back into an IOCTL identifier
+fn from_sconfig(param: &mut IoctlIdentifier, value: u32) {
+    *param = match *param {
+        IoctlIdentifier::ISO15765_STMIN(_) => IoctlIdentifier::ISO15765_STMIN(value),
+        IoctlIdentifier::ISO15765_BS(_) => IoctlIdentifier::ISO15765_BS(value),
+        IoctlIdentifier::P1_MIN(_) => IoctlIdentifier::P1_MIN(value),
+        IoctlIdentifier::P1_MAX(_) => IoctlIdentifier::P1_MAX(value),
+        IoctlIdentifier::P2_MIN(_) => IoctlIdentifier::P2_MIN(value),
+        IoctlIdentifier::P2_MAX(_) => IoctlIdentifier::P2_MAX(value),
+        IoctlIdentifier::P3_MIN(_) => IoctlIdentifier::P3_MIN(value),
+        IoctlIdentifier::P3_MAX(_) => IoctlIdentifier::P3_MAX(value),
+        IoctlIdentifier::P4_MIN(_) => IoctlIdentifier::P4_MIN(value),
+        IoctlIdentifier::P4_MAX(_) => IoctlIdentifier::P4_MAX(value),
+        IoctlIdentifier::W1(_) => IoctlIdentifier::W1(value),
+        IoctlIdentifier::W2(_) => IoctlIdentifier::W2(value),
+        IoctlIdentifier::W3(_) => IoctlIdentifier::W3(value),
+        IoctlIdentifier::W4(_) => IoctlIdentifier::W4(value),
+        IoctlIdentifier::W5(_) => IoctlIdentifier::W5(value),
+        IoctlIdentifier::TIDLE(_) => IoctlIdentifier::TIDLE(value),
+        IoctlIdentifier::TINL(_) => IoctlIdentifier::TINL(value),
+        IoctlIdentifier::TWUP(_) => IoctlIdentifier::TWUP(value),
+        IoctlIdentifier::PARITY(_) => IoctlIdentifier::PARITY(value as u8)
+    }
+}
+
+impl PassthruAdapter {
+    pub fn new(device: PassthruDevice) -> Self {
+        Self {
+            device,
+            state: Arc::new(Mutex::new(PassthruState::default())),
+            logger: Logger::new("Passthru")
+        }
+    }
+
+    /// Looks up a Passthru device by its name
+    pub fn from_name(name: &str) -> Option<Self> {
+        PassthruDevice::find_all().ok()?
+            .into_iter()
+            .find(|d| d.name == name)
+            .map(Self::new)
+    }
+
+    /// Runs a function on the device's Passthru library
+    fn with_driver<T, F: FnOnce(&PassthruDrv) -> Result<T>>(&self, f: F) -> HardwareResult<T> {
+        let drv = self.state.lock().unwrap().drv.clone();
+        match drv {
+            Some(drv) => f(&drv).map_err(|e| to_hw_err(&drv, e)),
+            None => Err(HardwareError::Other("Passthru device is not open".into()))
+        }
+    }
+
+    fn dev_id(&self) -> HardwareResult<u32> {
+        self.state.lock().unwrap().dev_id.ok_or_else(|| HardwareError::Other("Passthru device is not open".into()))
+    }
+
+    /// Returns the Passthru channel handle and channel type of a connected channel
+    fn channel_handle(&self, channel_id: u32) -> HardwareResult<(u32, AdapterChannel)> {
+        let state = self.state.lock().unwrap();
+        match state.channels.get(&channel_id) {
+            Some(PassthruChannel { handle: Some(h), channel_type, .. }) => Ok((*h, *channel_type)),
+            Some(_) => Err(HardwareError::Other(format!("Channel {} has no filters, so is not connected yet", channel_id))),
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID.into())
+        }
+    }
+
+    /// Returns the Passthru channel handle of the connected channel carrying a frame type
+    fn handle_for_type(&self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        let state = self.state.lock().unwrap();
+        state.channels.values()
+            .find(|c| c.channel_type == channel_type && c.handle.is_some())
+            .and_then(|c| c.handle)
+            .ok_or_else(|| HardwareError::Other(format!("No {:?} channel is connected", channel_type)))
+    }
+}
+
+impl AdapterHardware for PassthruAdapter {
+    fn open_device(&mut self) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.dev_id.is_some() {
+            return Ok(());
+        }
+        self.logger.log_debug(format!("Loading Passthru library {}", self.device.drv_path));
+        let drv = PassthruDrv::load_shared(&self.device.drv_path).map_err(|e| HardwareError::Other(e.to_string()))?;
+        let dev_id = drv.open().map_err(|e| to_hw_err(&drv, e))?;
+        state.drv = Some(drv);
+        state.dev_id = Some(dev_id);
+        Ok(())
+    }
+
+    fn close_device(&mut self) -> HardwareResult<()> {
+        let ids: Vec<u32> = self.state.lock().unwrap().channels.keys().copied().collect();
+        for id in ids {
+            if let Err(e) = self.close_channel(id) {
+                self.logger.log_warn(format!("Could not close channel {}: {:?}", id, e));
+            }
+        }
+        let dev_id = self.dev_id()?;
+        self.with_driver(|drv| drv.close(dev_id))?;
+        let mut state = self.state.lock().unwrap();
+        state.dev_id = None;
+        // Unloads the library if no other device is using it
+        state.drv = None;
+        Ok(())
+    }
+
+    fn read_voltage(&mut self) -> HardwareResult<f32> {
+        let dev_id = self.dev_id()?;
+        let mut output: u32 = 0;
+        self.with_driver(|drv| drv.ioctl(dev_id, IoctlID::READ_VBATT, std::ptr::null_mut(), &mut output as *mut u32 as *mut c_void))?;
+        Ok(output as f32 / 1000.0)
+    }
+
+    fn open_channel(&mut self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        if state.dev_id.is_none() {
+            return Err(HardwareError::Other("Passthru device is not open".into()));
+        }
+        state.next_channel_id += 1;
+        let id = state.next_channel_id;
+        state.channels.insert(id, PassthruChannel { channel_type, handle: None, baud: 0, flags: 0 });
+        Ok(id)
+    }
+
+    fn close_channel(&mut self, id: u32) -> HardwareResult<()> {
+        let channel = self.state.lock().unwrap().channels.remove(&id);
+        match channel {
+            Some(PassthruChannel { handle: Some(h), .. }) => self.with_driver(|drv| drv.disconnect(h)),
+            Some(_) => Ok(()),
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID.into())
+        }
+    }
+
+    fn add_channel_filter(&mut self, channel_id: u32, filter: AdapterFilter, baud: u32, flags: &[ChannelFlags]) -> HardwareResult<u32> {
+        let dev_id = self.dev_id()?;
+        let (handle, channel_type, pt_flags) = {
+            let mut state = self.state.lock().unwrap();
+            let PassthruState { drv, channels, .. } = &mut *state;
+            let channel = channels.get_mut(&channel_id).ok_or_else(|| -> HardwareError { PassthruError::ERR_INVALID_CHANNEL_ID.into() })?;
+            let pt_flags = flags.iter().fold(0, |f, flag| f | match flag {
+                ChannelFlags::CAN_USE_29BIT_ADDR => CONNECT_CAN_29BIT_ID,
+                ChannelFlags::ISOTP_USE_EXT_ADDR => CONNECT_ISO15765_ADDR_TYPE,
+                ChannelFlags::ISO9141_NO_CHECKSUM => CONNECT_ISO9141_NO_CHECKSUM
+            });
+            let handle = match channel.handle {
+                Some(h) if channel.baud == baud && channel.flags == pt_flags => h,
+                Some(_) => return Err(HardwareError::Other(format!(
+                    "Channel {} is already connected at {}bps with different flags", channel_id, channel.baud
+                ))),
+                None => {
+                    let protocol = to_protocol(channel.channel_type);
+                    let drv = drv.as_ref().ok_or_else(|| HardwareError::Other("Passthru device is not open".into()))?;
+                    let h = drv.connect(dev_id, protocol, pt_flags, baud).map_err(|e| to_hw_err(drv, e))?;
+                    channel.handle = Some(h);
+                    channel.baud = baud;
+                    channel.flags = pt_flags;
+                    h
+                }
+            };
+            (handle, channel.channel_type, pt_flags)
+        };
+
+        let mut mask_msg = blank_msg(to_protocol(channel_type));
+        let mut pattern_msg = blank_msg(to_protocol(channel_type));
+        let (filter_type, mask, pattern, fc) = match filter {
+            AdapterFilter::Pass { mask, id } => (FilterType::PASS_FILTER, mask, id, None),
+            AdapterFilter::Block { mask, id } => (FilterType::BLOCK_FILTER, mask, id, None),
+            AdapterFilter::IsoTP { mask, id, fc } => (FilterType::FLOW_CONTROL_FILTER, mask, id, Some(fc))
+        };
+        for (msg, value) in [(&mut mask_msg, mask), (&mut pattern_msg, pattern)] {
+            msg.data[0..4].copy_from_slice(&value.to_be_bytes());
+            msg.data_size = 4;
+            msg.tx_flags = pt_flags & CONNECT_CAN_29BIT_ID;
+        }
+        let fc_msg = fc.map(|fc| {
+            let mut msg = blank_msg(to_protocol(channel_type));
+            msg.data[0..4].copy_from_slice(&fc.to_be_bytes());
+            msg.data_size = 4;
+            msg.tx_flags = pt_flags & CONNECT_CAN_29BIT_ID;
+            msg
+        });
+        self.with_driver(|drv| drv.start_msg_filter(handle, filter_type, &mask_msg, &pattern_msg, fc_msg))
+    }
+
+    fn del_channel_filter(&mut self, channel_id: u32, filter_id: u32) -> HardwareResult<u32> {
+        let (handle, _) = self.channel_handle(channel_id)?;
+        self.with_driver(|drv| drv.stop_msg_filter(handle, filter_id))?;
+        Ok(filter_id)
+    }
+
+    fn clear_channel_buffer(&mut self, channel_id: u32, buffer: AdapterBuffer) -> HardwareResult<()> {
+        let (handle, _) = self.channel_handle(channel_id)?;
+        let ioctls: &[IoctlID] = match buffer {
+            AdapterBuffer::Input => &[IoctlID::CLEAR_RX_BUFFER],
+            AdapterBuffer::Output => &[IoctlID::CLEAR_TX_BUFFER],
+            AdapterBuffer::Both => &[IoctlID::CLEAR_RX_BUFFER, IoctlID::CLEAR_TX_BUFFER]
+        };
+        for ioctl in ioctls {
+            self.with_driver(|drv| drv.ioctl(handle, *ioctl, std::ptr::null_mut(), std::ptr::null_mut()))?;
+        }
+        Ok(())
+    }
+
+    fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>> {
+        let channel_type = T::channel_type();
+        let handle = self.handle_for_type(channel_type)?;
+        let msgs = match self.with_driver(|drv| drv.read_messages(handle, max_read as u32, timeout_ms as u32)) {
+            Ok(m) => m,
+            Err(HardwareError::HwApiError { code, .. }) if code == PassthruError::ERR_BUFFER_EMPTY as u32 || code == PassthruError::ERR_TIMEOUT as u32 => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        Ok(msgs.iter()
+            .filter(|m| m.rx_status & (RX_TX_MSG_TYPE | RX_START_OF_MESSAGE | RX_TX_DONE) == 0)
+            .map(|m| from_passthru_msg(m, channel_type))
+            .collect())
+    }
+
+    fn write_data<T: HwDataFrame>(&mut self, input: &[T], timeout_ms: u128) -> HardwareResult<()> {
+        let channel_type = T::channel_type();
+        let handle = self.handle_for_type(channel_type)?;
+        let mut msgs: Vec<PASSTHRU_MSG> = input.iter().map(|f| to_passthru_msg(f, channel_type)).collect();
+        self.with_driver(|drv| drv.write_messages(handle, &mut msgs, timeout_ms as u32))?;
+        Ok(())
+    }
+
+    fn channel_set_ioctl(&mut self, channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()> {
+        let (handle, _) = self.channel_handle(channel_id)?;
+        let mut cfg = to_sconfig(&param);
+        let mut list = SConfigList { num_of_params: 1, config_ptr: &mut cfg as *mut SConfig };
+        self.with_driver(|drv| drv.ioctl(handle, IoctlID::SET_CONFIG, &mut list as *mut SConfigList as *mut c_void, std::ptr::null_mut()))
+    }
+
+    fn channel_get_ioctl(&mut self, channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()> {
+        let (handle, _) = self.channel_handle(channel_id)?;
+        let mut cfg = to_sconfig(param);
+        let mut list = SConfigList { num_of_params: 1, config_ptr: &mut cfg as *mut SConfig };
+        self.with_driver(|drv| drv.ioctl(handle, IoctlID::GET_CONFIG, &mut list as *mut SConfigList as *mut c_void, std::ptr::null_mut()))?;
+        from_sconfig(param, cfg.value);
+        Ok(())
+    }
+
+    fn channel_lin_init(&mut self, channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()> {
+        let (handle, channel_type) = self.channel_handle(channel_id)?;
+        match init_type {
+            LinInitType::FastInit { id: _, data } => {
+                let mut input = blank_msg(to_protocol(channel_type));
+                if data.len() > input.data.len() {
+                    return Err(HardwareError::Other(format!("FastInit message of {} bytes is longer than the {} bytes a Passthru message holds", data.len(), input.data.len())));
+                }
+                input.data[0..data.len()].copy_from_slice(data);
+                input.data_size = data.len() as u32;
+                let mut output = blank_msg(to_protocol(channel_type));
+                self.with_driver(|drv| drv.ioctl(
+                    handle,
+                    IoctlID::FAST_INIT,
+                    &mut input as *mut PASSTHRU_MSG as *mut c_void,
+                    &mut output as *mut PASSTHRU_MSG as *mut c_void
+                ))?;
+                *data = output.data[0..(output.data_size as usize).min(output.data.len())].to_vec();
+            },
+            LinInitType::FiveBaudInit(data) => {
+                let mut key_bytes = [0u8; 2];
+                let mut input = SByteArray { num_of_bytes: data.len() as u32, byte_ptr: data.as_mut_ptr() };
+                let mut output = SByteArray { num_of_bytes: key_bytes.len() as u32, byte_ptr: key_bytes.as_mut_ptr() };
+                self.with_driver(|drv| drv.ioctl(
+                    handle,
+                    IoctlID::FIVE_BAUD_INIT,
+                    &mut input as *mut SByteArray as *mut c_void,
+                    &mut output as *mut SByteArray as *mut c_void
+                ))?;
+                *data = key_bytes[0..(output.num_of_bytes as usize).min(key_bytes.len())].to_vec();
+            }
+        }
+        Ok(())
+    }
+
+    /// SAE J2534 does not define a listen-only mode, and connecting a CAN channel at the wrong bitrate
+    /// would make the device's controller disturb the bus. Detection is therefore always refused
+    fn detect_can_bitrate(&mut self, _candidates: &[u32], _listen_ms: u128) -> HardwareResult<BitrateDetection> {
+        Err(HardwareError::Other(format!("{} cannot detect the CAN bitrate, as J2534 devices have no listen-only mode", self.device.name)))
+    }
+}
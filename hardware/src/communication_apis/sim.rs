@@ -0,0 +1,216 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::data_structures::HwDataFrame;
+use crate::{AdapterBuffer, AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError, HardwareResult, IoctlIdentifier, LinInitType};
+
+/// Function which simulates the vehicle. It is called with every frame written to the adapter,
+/// and returns the frames (ID and data) the vehicle responds with on the same channel type.
+pub type SimHandler = dyn Fn(AdapterChannel, u32, &[u8]) -> Vec<(u32, Vec<u8>)> + Send + Sync;
+
+#[derive(Debug)]
+struct SimChannel {
+    channel_type: AdapterChannel,
+    filters: HashMap<u32, AdapterFilter>,
+    next_filter_id: u32,
+    ioctls: Vec<IoctlIdentifier>
+}
+
+impl SimChannel {
+    fn accepts(&self, id: u32) -> bool {
+        let pass = self.filters.values().any(|f| match f {
+            AdapterFilter::Pass { mask, id: p } | AdapterFilter::IsoTP { mask, id: p, .. } => id & mask == *p,
+            AdapterFilter::Block { .. } => false
+        });
+        let block = self.filters.values().any(|f| matches!(f, AdapterFilter::Block { mask, id: b } if id & mask == *b));
+        pass && !block
+    }
+}
+
+#[derive(Debug, Default)]
+struct SimState {
+    open: bool,
+    channels: HashMap<u32, SimChannel>,
+    next_channel_id: u32,
+    /// Frames waiting to be read, with the channel type they were sent on
+    rx_queue: VecDeque<(AdapterChannel, u32, Vec<u8>)>
+}
+
+/// [AdapterHardware] implementation which simulates a vehicle in software.
+///
+/// Frames written to the adapter are passed to a [SimHandler], and its responses are
+/// received through the adapter's channel filters, just like a real vehicle.
+#[derive(Clone)]
+pub struct SimAdapter {
+    handler: Arc<SimHandler>,
+    state: Arc<Mutex<SimState>>,
+    voltage: f32
+}
+
+impl fmt::Debug for SimAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimAdapter")
+            .field("state", &self.state)
+            .field("voltage", &self.voltage)
+            .finish()
+    }
+}
+
+impl SimAdapter {
+    pub fn new<F: Fn(AdapterChannel, u32, &[u8]) -> Vec<(u32, Vec<u8>)> + Send + Sync + 'static>(handler: F) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            state: Arc::new(Mutex::new(SimState::default())),
+            voltage: 12.6
+        }
+    }
+
+    /// Queues a frame as if the vehicle sent it unprompted
+    pub fn inject_frame(&self, channel_type: AdapterChannel, id: u32, data: &[u8]) {
+        self.state.lock().unwrap().rx_queue.push_back((channel_type, id, data.to_vec()));
+    }
+
+    fn not_open(&self, channel_id: u32) -> HardwareError {
+        HardwareError::Other(format!("Channel {} is not open", channel_id))
+    }
+}
+
+impl AdapterHardware for SimAdapter {
+    fn open_device(&mut self) -> HardwareResult<()> {
+        self.state.lock().unwrap().open = true;
+        Ok(())
+    }
+
+    fn close_device(&mut self) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.open = false;
+        state.channels.clear();
+        state.rx_queue.clear();
+        Ok(())
+    }
+
+    fn read_voltage(&mut self) -> HardwareResult<f32> {
+        Ok(self.voltage)
+    }
+
+    fn open_channel(&mut self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        if !state.open {
+            return Err(HardwareError::Other("Simulation device is not open".into()));
+        }
+        state.next_channel_id += 1;
+        let id = state.next_channel_id;
+        state.channels.insert(id, SimChannel { channel_type, filters: HashMap::new(), next_filter_id: 0, ioctls: Vec::new() });
+        Ok(id)
+    }
+
+    fn close_channel(&mut self, id: u32) -> HardwareResult<()> {
+        self.state.lock().unwrap().channels.remove(&id).map(|_| ()).ok_or_else(|| self.not_open(id))
+    }
+
+    fn add_channel_filter(&mut self, channel_id: u32, filter: AdapterFilter, _baud: u32, _flags: &[ChannelFlags]) -> HardwareResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let channel = state.channels.get_mut(&channel_id).ok_or_else(|| self.not_open(channel_id))?;
+        channel.next_filter_id += 1;
+        channel.filters.insert(channel.next_filter_id, filter);
+        Ok(channel.next_filter_id)
+    }
+
+    fn del_channel_filter(&mut self, channel_id: u32, filter_id: u32) -> HardwareResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let channel = state.channels.get_mut(&channel_id).ok_or_else(|| self.not_open(channel_id))?;
+        channel.filters.remove(&filter_id)
+            .map(|_| filter_id)
+            .ok_or_else(|| HardwareError::Other(format!("Filter {} does not exist on channel {}", filter_id, channel_id)))
+    }
+
+    fn clear_channel_buffer(&mut self, channel_id: u32, buffer: AdapterBuffer) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let channel_type = state.channels.get(&channel_id).ok_or_else(|| self.not_open(channel_id))?.channel_type;
+        if let AdapterBuffer::Input | AdapterBuffer::Both = buffer {
+            state.rx_queue.retain(|(c, _, _)| *c != channel_type);
+        }
+        Ok(())
+    }
+
+    fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        loop {
+            let mut res = Vec::new();
+            {
+                let mut state = self.state.lock().unwrap();
+                let SimState { channels, rx_queue, .. } = &mut *state;
+                let channel = channels.values()
+                    .find(|c| c.channel_type == T::channel_type())
+                    .ok_or_else(|| HardwareError::Other(format!("No {:?} channel is open", T::channel_type())))?;
+                let mut remaining = VecDeque::with_capacity(rx_queue.len());
+                while let Some((channel_type, id, data)) = rx_queue.pop_front() {
+                    if channel_type != channel.channel_type || res.len() >= max_read {
+                        remaining.push_back((channel_type, id, data));
+                    } else if channel.accepts(id) {
+                        let mut frame = T::default();
+                        frame.set_id(id);
+                        frame.set_data(&data);
+                        res.push(frame);
+                    }
+                    // Otherwise the frame is discarded by the channel's filters
+                }
+                *rx_queue = remaining;
+            }
+            if !res.is_empty() || Instant::now() >= deadline {
+                return Ok(res);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn write_data<T: HwDataFrame>(&mut self, input: &[T], _timeout_ms: u128) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.channels.values().any(|c| c.channel_type == T::channel_type()) {
+            return Err(HardwareError::Other(format!("No {:?} channel is open", T::channel_type())));
+        }
+        for frame in input {
+            for (id, data) in (self.handler)(T::channel_type(), frame.get_id(), frame.get_data()) {
+                state.rx_queue.push_back((T::channel_type(), id, data));
+            }
+        }
+        Ok(())
+    }
+
+    fn channel_set_ioctl(&mut self, channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let channel = state.channels.get_mut(&channel_id).ok_or_else(|| self.not_open(channel_id))?;
+        channel.ioctls.retain(|p| std::mem::discriminant(p) != std::mem::discriminant(&param));
+        channel.ioctls.push(param);
+        Ok(())
+    }
+
+    fn channel_get_ioctl(&mut self, channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()> {
+        let state = self.state.lock().unwrap();
+        let channel = state.channels.get(&channel_id).ok_or_else(|| self.not_open(channel_id))?;
+        match channel.ioctls.iter().find(|p| std::mem::discriminant(*p) == std::mem::discriminant(param)) {
+            Some(p) => {
+                *param = *p;
+                Ok(())
+            },
+            None => Err(HardwareError::Other(format!("IOCTL {:?} has not been set", param)))
+        }
+    }
+
+    fn channel_lin_init(&mut self, channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()> {
+        let channel_type = self.state.lock().unwrap().channels.get(&channel_id).ok_or_else(|| self.not_open(channel_id))?.channel_type;
+        match init_type {
+            LinInitType::FastInit { id, data } => {
+                let response = (self.handler)(channel_type, *id, data);
+                *data = response.into_iter().next().map(|(_, d)| d).unwrap_or_default();
+            },
+            LinInitType::FiveBaudInit(data) => {
+                // KWP2000 key bytes
+                *data = vec![0x8F, 0xE9];
+            }
+        }
+        Ok(())
+    }
+}
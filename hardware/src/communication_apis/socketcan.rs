@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use logger::Logger;
+use socketcan::{CANFilter, CANFrame, CANSocket};
+
+use crate::bitrate::{BitrateDetection, ProbeCounter};
+use crate::data_structures::HwDataFrame;
+use crate::{AdapterBuffer, AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError, HardwareResult, IoctlIdentifier, LinInitType};
+
+/// A CAN channel opened on a [SocketCanAdapter]
+struct SocketCanChannel {
+    socket: CANSocket,
+    /// Filters on the channel. Pass filters are applied by the kernel, block filters are applied in software
+    /// as SocketCAN does not support combining them
+    filters: HashMap<u32, AdapterFilter>,
+    next_filter_id: u32
+}
+
+impl fmt::Debug for SocketCanChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocketCanChannel")
+            .field("filters", &self.filters)
+            .finish()
+    }
+}
+
+impl SocketCanChannel {
+    /// Applies the channel's pass filters to the socket
+    fn apply_filters(&self) -> HardwareResult<()> {
+        let pass: Vec<CANFilter> = self.filters.values()
+            .filter_map(|f| match f {
+                AdapterFilter::Pass { mask, id } => CANFilter::new(*id, *mask).ok(),
+                _ => None
+            })
+            .collect();
+        match pass.is_empty() {
+            true => self.socket.filter_drop_all(),
+            false => self.socket.set_filter(&pass)
+        }.map_err(HardwareError::IoError)
+    }
+
+    fn is_blocked(&self, id: u32) -> bool {
+        self.filters.values().any(|f| matches!(f, AdapterFilter::Block { mask, id: b } if id & mask == *b))
+    }
+}
+
+#[derive(Debug, Default)]
+struct SocketCanState {
+    open: bool,
+    /// Bitrate the interface was last configured with. None if it was configured outside of OpenStar
+    bitrate: Option<u32>,
+    channels: HashMap<u32, SocketCanChannel>,
+    next_channel_id: u32
+}
+
+/// [AdapterHardware] implementation for SocketCAN interfaces (Linux only)
+///
+/// Only [AdapterChannel::Can] channels are supported. Changing the bitrate of the interface
+/// requires permission to run `ip link`. If that fails, the interface's existing bitrate is used.
+#[derive(Debug, Clone)]
+pub struct SocketCanAdapter {
+    iface: String,
+    state: Arc<Mutex<SocketCanState>>,
+    logger: Logger
+}
+
+/// Takes the interface out of listen-only mode when bitrate detection ends, including when it fails
+struct ListenOnlyGuard<'a> {
+    adapter: &'a SocketCanAdapter,
+    /// Bitrate to configure the interface with. None if there is nothing to restore
+    restore: Option<u32>
+}
+
+impl ListenOnlyGuard<'_> {
+    /// Configures the interface with a bitrate and listen-only mode disabled
+    fn finish(mut self, bitrate: u32) -> HardwareResult<()> {
+        self.restore = None;
+        self.adapter.configure_interface(bitrate, false)
+    }
+}
+
+impl Drop for ListenOnlyGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(bitrate) = self.restore {
+            if let Err(e) = self.adapter.configure_interface(bitrate, false) {
+                self.adapter.logger.log_err(format!("Could not restore {} to {}bps: {:?}", self.adapter.iface, bitrate, e));
+            }
+        }
+    }
+}
+
+/// Reads a frame from a socket, giving up once the deadline has passed
+fn read_frame_before(socket: &CANSocket, deadline: Instant) -> HardwareResult<Option<CANFrame>> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.as_millis() == 0 {
+        socket.set_nonblocking(true).map_err(HardwareError::IoError)?;
+    } else {
+        socket.set_nonblocking(false).map_err(HardwareError::IoError)?;
+        socket.set_read_timeout(remaining).map_err(HardwareError::IoError)?;
+    }
+    match socket.read_frame() {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(HardwareError::IoError(e))
+    }
+}
+
+impl SocketCanAdapter {
+    pub fn new(iface: &str) -> Self {
+        Self {
+            iface: iface.to_string(),
+            state: Arc::new(Mutex::new(SocketCanState::default())),
+            logger: Logger::new("SocketCAN")
+        }
+    }
+
+    /// Lists all CAN network interfaces present on the system
+    pub fn find_all() -> Vec<String> {
+        let mut res: Vec<String> = match std::fs::read_dir("/sys/class/net") {
+            Ok(list) => list
+                .filter_map(|e| e.ok())
+                // ARPHRD_CAN
+                .filter(|e| std::fs::read_to_string(e.path().join("type")).map(|t| t.trim() == "280").unwrap_or(false))
+                .filter_map(|e| e.file_name().into_string().ok())
+                .collect(),
+            Err(_) => Vec::new()
+        };
+        res.sort();
+        res
+    }
+
+    /// Reconfigures the interface's bitrate using `ip link`. The interface is brought down
+    /// and back up in the process.
+    ///
+    /// ## Arguments
+    /// * bitrate - The bitrate of the bus (In bps)
+    /// * listen_only - If true, the interface will not acknowledge or transmit any frames
+    fn configure_interface(&self, bitrate: u32, listen_only: bool) -> HardwareResult<()> {
+        let listen_only = if listen_only { "on" } else { "off" };
+        let bitrate = bitrate.to_string();
+        let commands: [&[&str]; 3] = [
+            &["link", "set", "dev", &self.iface, "down"],
+            &["link", "set", "dev", &self.iface, "type", "can", "bitrate", &bitrate, "listen-only", listen_only],
+            &["link", "set", "dev", &self.iface, "up"],
+        ];
+        for args in commands.iter() {
+            let output = Command::new("ip").args(args.iter()).output().map_err(HardwareError::IoError)?;
+            if !output.status.success() {
+                return Err(HardwareError::Other(format!(
+                    "ip {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the bitrate the interface is currently configured with, using `ip -details link show`
+    fn current_bitrate(&self) -> Option<u32> {
+        let output = Command::new("ip").args(["-details", "link", "show", "dev", &self.iface].iter()).output().ok()?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut words = output.split_whitespace();
+        words.find(|w| *w == "bitrate")?;
+        words.next()?.parse().ok()
+    }
+
+    fn not_supported(&self, what: &str) -> HardwareError {
+        HardwareError::Other(format!("{} is not supported on SocketCAN", what))
+    }
+}
+
+impl AdapterHardware for SocketCanAdapter {
+    fn open_device(&mut self) -> HardwareResult<()> {
+        if !Path::new("/sys/class/net").join(&self.iface).exists() {
+            return Err(HardwareError::Other(format!("Network interface {} does not exist", self.iface)));
+        }
+        self.state.lock().unwrap().open = true;
+        Ok(())
+    }
+
+    fn close_device(&mut self) -> HardwareResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.channels.clear();
+        state.open = false;
+        Ok(())
+    }
+
+    fn read_voltage(&mut self) -> HardwareResult<f32> {
+        Err(self.not_supported("Reading battery voltage"))
+    }
+
+    fn open_channel(&mut self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        if channel_type != AdapterChannel::Can {
+            return Err(self.not_supported(&format!("{:?} channel", channel_type)));
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.open {
+            return Err(HardwareError::Other("SocketCAN device is not open".into()));
+        }
+        if !state.channels.is_empty() {
+            return Err(HardwareError::Other("A CAN channel is already open on this interface".into()));
+        }
+        let socket = CANSocket::open(&self.iface).map_err(|e| HardwareError::Other(e.to_string()))?;
+        socket.filter_drop_all().map_err(HardwareError::IoError)?;
+        state.next_channel_id += 1;
+        let id = state.next_channel_id;
+        state.channels.insert(id, SocketCanChannel { socket, filters: HashMap::new(), next_filter_id: 0 });
+        Ok(id)
+    }
+
+    fn close_channel(&mut self, id: u32) -> HardwareResult<()> {
+        match self.state.lock().unwrap().channels.remove(&id) {
+            Some(_) => Ok(()),
+            None => Err(HardwareError::Other(format!("Channel {} is not open", id)))
+        }
+    }
+
+    fn add_channel_filter(&mut self, channel_id: u32, filter: AdapterFilter, baud: u32, _flags: &[ChannelFlags]) -> HardwareResult<u32> {
+        if let AdapterFilter::IsoTP { .. } = filter {
+            return Err(self.not_supported("ISO-TP flow control filter"));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.bitrate != Some(baud) {
+            // Bringing the interface down resets every socket open on it
+            if state.channels.keys().any(|id| *id != channel_id) {
+                return Err(HardwareError::Other(format!("Cannot change the bitrate of {} whilst other channels are open", self.iface)));
+            }
+            match self.configure_interface(baud, false) {
+                Ok(()) => state.bitrate = Some(baud),
+                Err(e) => self.logger.log_warn(format!("Could not set {} to {}bps, using its existing bitrate: {:?}", self.iface, baud, e))
+            }
+        }
+        let channel = state.channels.get_mut(&channel_id).ok_or_else(|| HardwareError::Other(format!("Channel {} is not open", channel_id)))?;
+        channel.next_filter_id += 1;
+        let filter_id = channel.next_filter_id;
+        channel.filters.insert(filter_id, filter);
+        channel.apply_filters()?;
+        Ok(filter_id)
+    }
+
+    fn del_channel_filter(&mut self, channel_id: u32, filter_id: u32) -> HardwareResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let channel = state.channels.get_mut(&channel_id).ok_or_else(|| HardwareError::Other(format!("Channel {} is not open", channel_id)))?;
+        if channel.filters.remove(&filter_id).is_none() {
+            return Err(HardwareError::Other(format!("Filter {} does not exist on channel {}", filter_id, channel_id)));
+        }
+        channel.apply_filters()?;
+        Ok(filter_id)
+    }
+
+    fn clear_channel_buffer(&mut self, channel_id: u32, buffer: AdapterBuffer) -> HardwareResult<()> {
+        let state = self.state.lock().unwrap();
+        let channel = state.channels.get(&channel_id).ok_or_else(|| HardwareError::Other(format!("Channel {} is not open", channel_id)))?;
+        // The kernel owns the transmit queue, so only the receive queue can be drained
+        if let AdapterBuffer::Input | AdapterBuffer::Both = buffer {
+            while read_frame_before(&channel.socket, Instant::now())?.is_some() {}
+        }
+        Ok(())
+    }
+
+    fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>> {
+        if T::channel_type() != AdapterChannel::Can {
+            return Err(self.not_supported(&format!("{:?} channel", T::channel_type())));
+        }
+        let state = self.state.lock().unwrap();
+        let channel = state.channels.values().next().ok_or_else(|| HardwareError::Other("No CAN channel is open".into()))?;
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        let mut res = Vec::new();
+        while res.len() < max_read {
+            match read_frame_before(&channel.socket, deadline)? {
+                Some(f) if f.is_error() || channel.is_blocked(f.id()) => continue,
+                Some(f) => {
+                    let mut frame = T::default();
+                    frame.set_id(f.id());
+                    frame.set_data(f.data());
+                    res.push(frame);
+                },
+                None => break
+            }
+        }
+        Ok(res)
+    }
+
+    fn write_data<T: HwDataFrame>(&mut self, input: &[T], timeout_ms: u128) -> HardwareResult<()> {
+        if T::channel_type() != AdapterChannel::Can {
+            return Err(self.not_supported(&format!("{:?} channel", T::channel_type())));
+        }
+        let state = self.state.lock().unwrap();
+        let channel = state.channels.values().next().ok_or_else(|| HardwareError::Other("No CAN channel is open".into()))?;
+        channel.socket.set_nonblocking(timeout_ms == 0).map_err(HardwareError::IoError)?;
+        if timeout_ms != 0 {
+            channel.socket.set_write_timeout(Duration::from_millis(timeout_ms as u64)).map_err(HardwareError::IoError)?;
+        }
+        for frame in input {
+            let f = CANFrame::new(frame.get_id(), frame.get_data(), false, false)
+                .map_err(|e| HardwareError::Other(format!("Invalid CAN frame: {}", e)))?;
+            channel.socket.write_frame(&f).map_err(HardwareError::IoError)?;
+        }
+        Ok(())
+    }
+
+    fn channel_set_ioctl(&mut self, _channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()> {
+        Err(self.not_supported(&format!("IOCTL {:?}", param)))
+    }
+
+    fn channel_get_ioctl(&mut self, _channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()> {
+        Err(self.not_supported(&format!("IOCTL {:?}", param)))
+    }
+
+    fn channel_lin_init(&mut self, _channel_id: u32, _init_type: &mut LinInitType) -> HardwareResult<()> {
+        Err(self.not_supported("LIN initialization"))
+    }
+
+    /// SocketCAN puts the interface into listen-only mode for each candidate bitrate, so the
+    /// bus is never disturbed, and counts CAN error frames as errors.
+    /// The interface is left configured at the detected bitrate with listen-only mode disabled. If detection
+    /// fails or finds nothing, the bitrate the interface had before is restored.
+    fn detect_can_bitrate(&mut self, candidates: &[u32], listen_ms: u128) -> HardwareResult<BitrateDetection> {
+        if !self.state.lock().unwrap().channels.is_empty() {
+            return Err(HardwareError::Other("Cannot detect bitrate whilst a CAN channel is open".into()));
+        }
+        let previous = self.state.lock().unwrap().bitrate.or_else(|| self.current_bitrate());
+        let mut guard = ListenOnlyGuard { adapter: self, restore: None };
+        let mut probes = Vec::with_capacity(candidates.len());
+        for bitrate in candidates {
+            // Listen-only mode must be turned off again even if probing fails half way
+            guard.restore = previous.or(Some(*bitrate));
+            self.configure_interface(*bitrate, true)?;
+            let socket = CANSocket::open(&self.iface).map_err(|e| HardwareError::Other(e.to_string()))?;
+            socket.filter_accept_all().map_err(HardwareError::IoError)?;
+            socket.error_filter_accept_all().map_err(HardwareError::IoError)?;
+            let mut counter = ProbeCounter::default();
+            let deadline = Instant::now() + Duration::from_millis(listen_ms as u64);
+            while Instant::now() < deadline {
+                match read_frame_before(&socket, deadline) {
+                    Ok(Some(f)) if f.is_error() => counter.add_error(),
+                    Ok(Some(f)) => counter.add_frame(f.id()),
+                    Ok(None) => break,
+                    Err(_) => counter.add_error()
+                }
+            }
+            probes.push(counter.finish(*bitrate));
+        }
+        let detection = BitrateDetection::from_probes(probes);
+        // If nothing was detected and the previous bitrate is unknown, the last candidate is kept
+        if let Some(bitrate) = detection.bitrate.or(guard.restore) {
+            guard.finish(bitrate)?;
+            self.state.lock().unwrap().bitrate = Some(bitrate);
+        }
+        self.logger.log_debug(format!("Detected bitrate of {}: {:?}", self.iface, detection.bitrate));
+        Ok(detection)
+    }
+}
@@ -1,10 +1,14 @@
 use std::{cmp::min, fmt::Debug};
 
+use crate::AdapterChannel;
+
 pub trait HwDataFrame: Debug + Sync + Send + Sized + Clone + Default {
     fn set_data(&mut self, data: &[u8]);
     fn get_data(&self) -> &[u8];
     fn get_id(&self) -> u32;
     fn set_id(&mut self, id: u32);
+    /// The logical channel type this frame is sent and received on
+    fn channel_type() -> AdapterChannel;
 }
 
 #[derive(Debug, Clone, Default)]
@@ -43,6 +47,10 @@ impl HwDataFrame for HWCanFrame {
         self.id = id;
         self.can_ext_addr = self.id > 0x7FF;
     }
+
+    fn channel_type() -> AdapterChannel {
+        AdapterChannel::Can
+    }
 }
 
 impl logger::Loggable for HWCanFrame {
@@ -87,6 +95,10 @@ impl HwDataFrame for HwIsoTpFrame {
         self.id = id;
         self.can_ext_addr = self.id > 0x7FF;
     }
+
+    fn channel_type() -> AdapterChannel {
+        AdapterChannel::IsoTp
+    }
 }
 
 impl logger::Loggable for HwIsoTpFrame {
@@ -95,12 +107,94 @@ impl logger::Loggable for HwIsoTpFrame {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Message on a KWP2000 K-Line channel (ISO14230). The data contains the header bytes (Format, target and source),
+/// but not the checksum, which is added by the adapter. There is no message ID on K-Line, so the ID is unused.
+#[derive(Debug, Clone, Default)]
 pub struct HwKwpFrame {
     id: u32,
     data: Vec<u8>
 }
 
+impl HwKwpFrame {
+    pub fn new(data: &[u8]) -> Self {
+        let mut c = Self::default();
+        c.set_data(data);
+        c
+    }
+}
+
+impl HwDataFrame for HwKwpFrame {
+    fn set_data(&mut self, data: &[u8]) {
+        self.data = data.to_vec()
+    }
+
+    fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn channel_type() -> AdapterChannel {
+        AdapterChannel::Kwp
+    }
+}
+
+impl logger::Loggable for HwKwpFrame {
+    fn to_log_string(&self) -> String {
+        format!("KwpFrame - Data: {:02X?}", self.data)
+    }
+}
+
+/// Message on an OBD K-Line channel (ISO9141). The data contains the header bytes, but not the checksum,
+/// which is added by the adapter. There is no message ID on K-Line, so the ID is unused.
+#[derive(Debug, Clone, Default)]
+pub struct HwObdFrame {
+    id: u32,
+    data: Vec<u8>
+}
+
+impl HwObdFrame {
+    pub fn new(data: &[u8]) -> Self {
+        let mut c = Self::default();
+        c.set_data(data);
+        c
+    }
+}
+
+impl HwDataFrame for HwObdFrame {
+    fn set_data(&mut self, data: &[u8]) {
+        self.data = data.to_vec()
+    }
+
+    fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn channel_type() -> AdapterChannel {
+        AdapterChannel::Obd
+    }
+}
+
+impl logger::Loggable for HwObdFrame {
+    fn to_log_string(&self) -> String {
+        format!("ObdFrame - Data: {:02X?}", self.data)
+    }
+}
+
 
 #[cfg(test)]
 pub mod test {
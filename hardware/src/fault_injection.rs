@@ -0,0 +1,379 @@
+//! Fault injection for resilience testing
+//!
+//! [FaultInjector] wraps any [AdapterHardware] and randomly injects the faults seen with
+//! flaky OBD connectors and marginal adapters: dropped, delayed, duplicated and corrupted frames,
+//! as well as spurious [HardwareError]s. Faults are driven by a seeded random number generator,
+//! so a failing test run can be reproduced exactly.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::data_structures::HwDataFrame;
+use crate::{AdapterBuffer, AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError, HardwareResult, IoctlIdentifier, LinInitType};
+
+/// Configuration of the faults to inject. Each rate is a probability between 0.0 (Never) and 1.0 (Always).
+/// The default configuration injects no faults.
+#[derive(Debug, Copy, Clone)]
+pub struct FaultConfig {
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Probability that a frame (Read or written) is silently dropped
+    pub drop_rate: f32,
+    /// Probability that a received frame is delayed
+    pub delay_rate: f32,
+    /// Minimum and maximum delay of a delayed frame (In ms)
+    pub delay_ms: (u64, u64),
+    /// Probability that a frame is duplicated. On [AdapterChannel::Can] channels only ISO-TP consecutive frames
+    /// are duplicated, on every other channel type the whole message is duplicated.
+    pub duplicate_rate: f32,
+    /// Probability that a single bit of a frame's data (Read or written) is flipped
+    pub corrupt_rate: f32,
+    /// Probability that an adapter call fails with a spurious [HardwareError]
+    pub error_rate: f32
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0x5EED,
+            drop_rate: 0.0,
+            delay_rate: 0.0,
+            delay_ms: (0, 0),
+            duplicate_rate: 0.0,
+            corrupt_rate: 0.0,
+            error_rate: 0.0
+        }
+    }
+}
+
+/// Counters of every fault that has been injected
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FaultStats {
+    pub dropped: usize,
+    pub delayed: usize,
+    pub duplicated: usize,
+    pub corrupted: usize,
+    pub errors: usize
+}
+
+#[derive(Debug)]
+struct FaultState {
+    config: FaultConfig,
+    rng: u64,
+    stats: FaultStats,
+    /// Received frames which are being held back, and the time they are released
+    delayed: Vec<(Instant, AdapterChannel, u32, Vec<u8>)>
+}
+
+impl FaultState {
+    /// xorshift64*
+    fn next_f32(&mut self) -> f32 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545F4914F6CDD1D) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn roll(&mut self, rate: f32) -> bool {
+        rate > 0.0 && self.next_f32() < rate
+    }
+
+    fn corrupt(&mut self, data: &mut [u8]) {
+        if !data.is_empty() {
+            let bit = (self.next_f32() * (data.len() * 8) as f32) as usize % (data.len() * 8);
+            data[bit / 8] ^= 1 << (bit % 8);
+            self.stats.corrupted += 1;
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let (min, max) = self.config.delay_ms;
+        let range = max.saturating_sub(min) as f32;
+        Duration::from_millis(min + (self.next_f32() * range) as u64)
+    }
+
+    fn spurious_error(&mut self, what: &str) -> HardwareResult<()> {
+        if self.roll(self.config.error_rate) {
+            self.stats.errors += 1;
+            return Err(HardwareError::Other(format!("Injected fault during {}", what)));
+        }
+        Ok(())
+    }
+}
+
+fn is_duplicate_candidate(channel_type: AdapterChannel, data: &[u8]) -> bool {
+    match channel_type {
+        AdapterChannel::Can => data.first().map(|pci| pci & 0xF0 == 0x20).unwrap_or(false),
+        _ => true
+    }
+}
+
+/// [AdapterHardware] wrapper which injects faults into an adapter. See [FaultConfig]
+#[derive(Debug, Clone)]
+pub struct FaultInjector<A: AdapterHardware> {
+    inner: A,
+    state: Arc<Mutex<FaultState>>
+}
+
+impl<A: AdapterHardware> FaultInjector<A> {
+    pub fn new(inner: A, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(FaultState {
+                config,
+                // xorshift must never be seeded with 0
+                rng: config.seed.max(1),
+                stats: FaultStats::default(),
+                delayed: Vec::new()
+            }))
+        }
+    }
+
+    /// Replaces the fault configuration. The random number generator is only reseeded if the seed changes,
+    /// so replacing the rates alone continues the same sequence
+    pub fn set_config(&self, config: FaultConfig) {
+        let mut state = self.state.lock().unwrap();
+        if config.seed != state.config.seed {
+            state.rng = config.seed.max(1);
+        }
+        state.config = config;
+    }
+
+    /// Returns counters of every fault injected so far
+    pub fn stats(&self) -> FaultStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Returns the wrapped adapter
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    fn spurious_error(&self, what: &str) -> HardwareResult<()> {
+        self.state.lock().unwrap().spurious_error(what)
+    }
+
+    /// Releases held back frames of a channel type which are due
+    fn take_delayed<T: HwDataFrame>(state: &mut FaultState, now: Instant, max: usize) -> Vec<T> {
+        let mut res = Vec::new();
+        let mut i = 0;
+        while i < state.delayed.len() && res.len() < max {
+            let (release, channel_type, _, _) = &state.delayed[i];
+            if *channel_type == T::channel_type() && *release <= now {
+                let (_, _, id, data) = state.delayed.remove(i);
+                let mut frame = T::default();
+                frame.set_id(id);
+                frame.set_data(&data);
+                res.push(frame);
+            } else {
+                i += 1;
+            }
+        }
+        res
+    }
+}
+
+impl<A: AdapterHardware> AdapterHardware for FaultInjector<A> {
+    fn open_device(&mut self) -> HardwareResult<()> {
+        self.spurious_error("open_device")?;
+        self.inner.open_device()
+    }
+
+    fn close_device(&mut self) -> HardwareResult<()> {
+        self.state.lock().unwrap().delayed.clear();
+        self.inner.close_device()
+    }
+
+    fn read_voltage(&mut self) -> HardwareResult<f32> {
+        self.spurious_error("read_voltage")?;
+        self.inner.read_voltage()
+    }
+
+    fn open_channel(&mut self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        self.spurious_error("open_channel")?;
+        self.inner.open_channel(channel_type)
+    }
+
+    fn close_channel(&mut self, id: u32) -> HardwareResult<()> {
+        self.inner.close_channel(id)
+    }
+
+    fn add_channel_filter(&mut self, channel_id: u32, filter: AdapterFilter, baud: u32, flags: &[ChannelFlags]) -> HardwareResult<u32> {
+        self.spurious_error("add_channel_filter")?;
+        self.inner.add_channel_filter(channel_id, filter, baud, flags)
+    }
+
+    fn del_channel_filter(&mut self, channel_id: u32, filter_id: u32) -> HardwareResult<u32> {
+        self.inner.del_channel_filter(channel_id, filter_id)
+    }
+
+    fn clear_channel_buffer(&mut self, channel_id: u32, buffer: AdapterBuffer) -> HardwareResult<()> {
+        self.inner.clear_channel_buffer(channel_id, buffer)
+    }
+
+    fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>> {
+        self.spurious_error("read_data")?;
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        let read: Vec<T> = self.inner.read_data(max_read, timeout_ms)?;
+
+        let mut state = self.state.lock().unwrap();
+        let config = state.config;
+        let mut res: Vec<T> = Self::take_delayed(&mut state, Instant::now(), max_read);
+        for mut frame in read {
+            if state.roll(config.drop_rate) {
+                state.stats.dropped += 1;
+                continue;
+            }
+            if state.roll(config.corrupt_rate) {
+                let mut data = frame.get_data().to_vec();
+                state.corrupt(&mut data);
+                frame.set_data(&data);
+            }
+            if state.roll(config.delay_rate) {
+                let release = Instant::now() + state.delay();
+                state.stats.delayed += 1;
+                state.delayed.push((release, T::channel_type(), frame.get_id(), frame.get_data().to_vec()));
+                continue;
+            }
+            if is_duplicate_candidate(T::channel_type(), frame.get_data()) && state.roll(config.duplicate_rate) {
+                state.stats.duplicated += 1;
+                res.push(frame.clone());
+            }
+            res.push(frame);
+        }
+
+        // Nothing to return yet, but a delayed frame may be released before the read times out
+        if res.is_empty() {
+            let next_release = state.delayed.iter()
+                .filter(|(release, channel_type, _, _)| *channel_type == T::channel_type() && *release <= deadline)
+                .map(|(release, _, _, _)| *release)
+                .min();
+            if let Some(release) = next_release {
+                drop(state);
+                std::thread::sleep(release.saturating_duration_since(Instant::now()));
+                state = self.state.lock().unwrap();
+                res = Self::take_delayed(&mut state, Instant::now(), max_read);
+            }
+        }
+
+        // Keep anything past max_read for the next read
+        let now = Instant::now();
+        for frame in res.drain(max_read.min(res.len())..).rev() {
+            state.delayed.insert(0, (now, T::channel_type(), frame.get_id(), frame.get_data().to_vec()));
+        }
+        Ok(res)
+    }
+
+    fn write_data<T: HwDataFrame>(&mut self, input: &[T], timeout_ms: u128) -> HardwareResult<()> {
+        self.spurious_error("write_data")?;
+        let mut to_write = Vec::with_capacity(input.len());
+        {
+            let mut state = self.state.lock().unwrap();
+            let config = state.config;
+            for frame in input {
+                if state.roll(config.drop_rate) {
+                    state.stats.dropped += 1;
+                    continue;
+                }
+                let mut frame = frame.clone();
+                if state.roll(config.corrupt_rate) {
+                    let mut data = frame.get_data().to_vec();
+                    state.corrupt(&mut data);
+                    frame.set_data(&data);
+                }
+                if is_duplicate_candidate(T::channel_type(), frame.get_data()) && state.roll(config.duplicate_rate) {
+                    state.stats.duplicated += 1;
+                    to_write.push(frame.clone());
+                }
+                to_write.push(frame);
+            }
+        }
+        self.inner.write_data(&to_write, timeout_ms)
+    }
+
+    fn channel_set_ioctl(&mut self, channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()> {
+        self.spurious_error("channel_set_ioctl")?;
+        self.inner.channel_set_ioctl(channel_id, param)
+    }
+
+    fn channel_get_ioctl(&mut self, channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()> {
+        self.spurious_error("channel_get_ioctl")?;
+        self.inner.channel_get_ioctl(channel_id, param)
+    }
+
+    fn channel_lin_init(&mut self, channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()> {
+        self.spurious_error("channel_lin_init")?;
+        self.inner.channel_lin_init(channel_id, init_type)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::SimAdapter;
+    use crate::data_structures::HWCanFrame;
+
+    /// Opens a CAN channel on a simulated ECU which echoes every frame back on ID 0x7E8. Faults are only
+    /// injected once the channel is open
+    fn echo_adapter(config: FaultConfig) -> FaultInjector<SimAdapter> {
+        let sim = SimAdapter::new(|_, _, data| vec![(0x7E8, data.to_vec())]);
+        let mut adapter = FaultInjector::new(sim, FaultConfig::default());
+        adapter.open_device().unwrap();
+        let channel = adapter.open_channel(AdapterChannel::Can).unwrap();
+        adapter.add_channel_filter(channel, AdapterFilter::Pass { mask: 0x7FF, id: 0x7E8 }, 500_000, &[]).unwrap();
+        adapter.set_config(config);
+        adapter
+    }
+
+    #[test]
+    pub fn test_no_faults() {
+        let mut adapter = echo_adapter(FaultConfig::default());
+        let res = adapter.read_and_write(HWCanFrame::new(0x7E0, &[0x02, 0x10, 0x92]), 0, 10).unwrap();
+        assert_eq!(&[0x02, 0x10, 0x92], res.get_data());
+    }
+
+    #[test]
+    pub fn test_drop() {
+        let mut adapter = echo_adapter(FaultConfig { drop_rate: 1.0, ..Default::default() });
+        assert!(matches!(adapter.read_and_write(HWCanFrame::new(0x7E0, &[0x01, 0x3E]), 0, 10), Err(HardwareError::Timeout)));
+        assert_eq!(1, adapter.stats().dropped);
+    }
+
+    #[test]
+    pub fn test_delay_within_timeout() {
+        let mut adapter = echo_adapter(FaultConfig { delay_rate: 1.0, delay_ms: (20, 20), ..Default::default() });
+        let start = Instant::now();
+        let res = adapter.read_and_write(HWCanFrame::new(0x7E0, &[0x01, 0x3E]), 0, 100).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(&[0x01, 0x3E], res.get_data());
+    }
+
+    #[test]
+    pub fn test_duplicate_consecutive_frames_only() {
+        let mut adapter = echo_adapter(FaultConfig { duplicate_rate: 1.0, ..Default::default() });
+        adapter.write_data(&[HWCanFrame::new(0x7E0, &[0x10, 0x09, 0x3B]), HWCanFrame::new(0x7E0, &[0x21, 0x00])], 0).unwrap();
+        let read: Vec<HWCanFrame> = adapter.read_data(10, 10).unwrap();
+        let pcis: Vec<u8> = read.iter().map(|f| f.get_data()[0]).collect();
+        // Consecutive frame is duplicated on the way out, and both copies again on the way back
+        assert_eq!(vec![0x10, 0x21, 0x21, 0x21, 0x21], pcis);
+    }
+
+    #[test]
+    pub fn test_corrupt_and_errors_reproducible() {
+        let config = FaultConfig { seed: 1234, corrupt_rate: 0.5, error_rate: 0.2, ..Default::default() };
+        let run = |config: FaultConfig| {
+            let mut adapter = echo_adapter(config);
+            (0..50).map(|_| adapter.read_and_write(HWCanFrame::new(0x7E0, &[0x02, 0x1A, 0x86]), 0, 5)
+                .map(|f| f.get_data().to_vec())
+                .ok()
+            ).collect::<Vec<Option<Vec<u8>>>>()
+        };
+        let first = run(config);
+        assert_eq!(first, run(config));
+        // The configured seed is used, rather than the one the adapter was created with
+        assert_ne!(first, run(FaultConfig { seed: 4321, ..config }));
+        assert_ne!(first, run(FaultConfig { seed: FaultConfig::default().seed, ..config }));
+        assert!(first.iter().any(|r| r.is_none()));
+        assert!(first.iter().any(|r| matches!(r, Some(d) if d != &[0x02, 0x1A, 0x86])));
+    }
+}
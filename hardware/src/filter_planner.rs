@@ -0,0 +1,386 @@
+//! Filter planner for adapter channels.
+//!
+//! Adapters only offer a handful of mask/ID filter slots (10 on most Passthru devices), and each slot
+//! can only express a single `mask & id == ID` pattern. This module takes high level rules such as
+//! ID ranges or lists of IDs and compiles them into the smallest set of [AdapterFilter]s that fit
+//! in the available slots. If the rules cannot be expressed exactly, the plan over-admits in hardware
+//! and the remaining frames must be discarded in software with [FilterPlan::filter_frames].
+
+use crate::{AdapterFilter, AdapterHardware, ChannelFlags, HardwareResult, data_structures::HwDataFrame};
+
+/// Number of filter slots available on most Passthru devices
+pub const DEFAULT_MAX_FILTERS: usize = 10;
+
+/// A high level rule describing a set of IDs
+#[derive(Debug, Clone)]
+pub enum FilterRule {
+    /// Every ID on the bus
+    All,
+    /// A single ID
+    Id(u32),
+    /// An inclusive range of IDs
+    Range { start: u32, end: u32 },
+    /// A list of individual IDs
+    List(Vec<u32>)
+}
+
+impl FilterRule {
+    fn to_ranges(&self, max_id: u32) -> Vec<(u32, u32)> {
+        match self {
+            FilterRule::All => vec![(0, max_id)],
+            FilterRule::Id(id) => vec![(*id, *id)],
+            FilterRule::Range { start, end } => vec![(*start.min(end), *start.max(end))],
+            FilterRule::List(ids) => ids.iter().map(|id| (*id, *id)).collect()
+        }
+    }
+}
+
+/// Builder which compiles a set of pass and block rules into a [FilterPlan]
+///
+/// An ID is wanted if it matches any pass rule and no block rule. If no pass rules are added,
+/// nothing will be wanted, just like a freshly opened channel.
+#[derive(Debug, Clone)]
+pub struct FilterPlanner {
+    max_filters: usize,
+    max_id: u32,
+    pass: Vec<FilterRule>,
+    block: Vec<FilterRule>
+}
+
+impl FilterPlanner {
+    /// Creates a new planner
+    ///
+    /// ## Arguments
+    /// * max_filters - The number of filter slots available on the channel
+    /// * extended_ids - True if the channel uses 29bit CAN IDs, false for 11bit IDs
+    pub fn new(max_filters: usize, extended_ids: bool) -> Self {
+        Self {
+            max_filters,
+            max_id: if extended_ids { 0x1FFFFFFF } else { 0x7FF },
+            pass: Vec::new(),
+            block: Vec::new()
+        }
+    }
+
+    /// Adds a rule of IDs that should be received
+    pub fn pass(mut self, rule: FilterRule) -> Self {
+        self.pass.push(rule);
+        self
+    }
+
+    /// Adds a rule of IDs that should never be received, even if they match a pass rule
+    pub fn block(mut self, rule: FilterRule) -> Self {
+        self.block.push(rule);
+        self
+    }
+
+    /// Compiles the rules into a filter plan.
+    ///
+    /// The planner tries two exact strategies and picks whichever needs fewer slots:
+    /// 1. Pass filters covering only the wanted IDs
+    /// 2. Pass filters covering the pass rules, plus block filters covering the blocked IDs
+    ///
+    /// If neither fits in the available slots, the pass filters from strategy 1 are merged until they fit,
+    /// and the plan will require software filtering. If the channel has no filter slots at all, the plan
+    /// has no hardware filters and every frame is filtered in software.
+    pub fn compile(&self) -> FilterPlan {
+        let pass = normalize(self.pass.iter().flat_map(|r| r.to_ranges(self.max_id)).collect(), self.max_id);
+        let block = normalize(self.block.iter().flat_map(|r| r.to_ranges(self.max_id)).collect(), self.max_id);
+        let block = intersect(&block, &pass);
+        let wanted = subtract(&pass, &block);
+
+        let wanted_blocks = decompose(&wanted, self.max_id);
+        let split_pass = decompose(&pass, self.max_id);
+        let split_block = decompose(&block, self.max_id);
+
+        let mut plan = FilterPlan {
+            filters: Vec::new(),
+            exact: true,
+            wanted: wanted.clone()
+        };
+
+        let split_len = split_pass.len() + split_block.len();
+        if wanted_blocks.len() <= self.max_filters && wanted_blocks.len() <= split_len {
+            plan.filters = wanted_blocks.iter().map(|b| b.to_pass()).collect();
+        } else if split_len <= self.max_filters {
+            plan.filters = split_pass.iter().map(|b| b.to_pass())
+                .chain(split_block.iter().map(|b| b.to_block()))
+                .collect();
+        } else if self.max_filters == 0 {
+            plan.exact = false;
+        } else {
+            let merged = merge_to_fit(wanted_blocks, self.max_filters, self.max_id);
+            plan.exact = merged.iter().all(|b| b.exact);
+            plan.filters = merged.iter().map(|b| b.to_pass()).collect();
+        }
+        plan
+    }
+}
+
+/// The result of compiling filter rules with [FilterPlanner]
+#[derive(Debug, Clone)]
+pub struct FilterPlan {
+    filters: Vec<AdapterFilter>,
+    exact: bool,
+    wanted: Vec<(u32, u32)>
+}
+
+impl FilterPlan {
+    /// Returns the hardware filters that should be configured on the channel
+    pub fn filters(&self) -> &[AdapterFilter] {
+        &self.filters
+    }
+
+    /// Returns true if the hardware filters match the rules exactly. If this is false,
+    /// received frames must be passed through [FilterPlan::filter_frames]
+    pub fn is_exact(&self) -> bool {
+        self.exact
+    }
+
+    /// Returns true if an ID is wanted by the rules the plan was compiled from
+    pub fn matches(&self, id: u32) -> bool {
+        self.wanted.iter().any(|(start, end)| id >= *start && id <= *end)
+    }
+
+    /// Software post-filter. Removes any frames that were let through by the hardware filters
+    /// but are not wanted by the rules.
+    pub fn filter_frames<T: HwDataFrame>(&self, frames: Vec<T>) -> Vec<T> {
+        if self.exact {
+            return frames;
+        }
+        frames.into_iter().filter(|f| self.matches(f.get_id())).collect()
+    }
+
+    /// Configures the plan's filters on an adapter channel
+    ///
+    /// ## Arguments
+    /// * adapter - The adapter to configure
+    /// * channel_id - The ID of the channel to create the filters on
+    /// * baud - The bus speed of the channel (In bps)
+    /// * flags - A list of flags to be applied to the channel
+    ///
+    /// ## Returns
+    /// The IDs of every filter created. If creating any filter fails, the filters already
+    /// created by this function are deleted again.
+    pub fn apply<A: AdapterHardware>(&self, adapter: &mut A, channel_id: u32, baud: u32, flags: &[ChannelFlags]) -> HardwareResult<Vec<u32>> {
+        let mut ids = Vec::with_capacity(self.filters.len());
+        for f in &self.filters {
+            match adapter.add_channel_filter(channel_id, *f, baud, flags) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    for id in ids {
+                        let _ = adapter.del_channel_filter(channel_id, id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+/// A single `mask & id == ID` pattern
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct MaskBlock {
+    mask: u32,
+    id: u32,
+    /// True if every ID matched by this block is wanted
+    exact: bool
+}
+
+impl MaskBlock {
+    fn size(&self, max_id: u32) -> u64 {
+        1u64 << (max_id & !self.mask).count_ones()
+    }
+
+    fn to_pass(self) -> AdapterFilter {
+        AdapterFilter::Pass { mask: self.mask, id: self.id }
+    }
+
+    fn to_block(self) -> AdapterFilter {
+        AdapterFilter::Block { mask: self.mask, id: self.id }
+    }
+
+    /// Returns true if every ID matched by other is also matched by self
+    fn contains(&self, other: &MaskBlock) -> bool {
+        self.mask & other.mask == self.mask && other.id & self.mask == self.id
+    }
+
+    /// Smallest block matching every ID of both self and other
+    fn merge(&self, other: &MaskBlock, max_id: u32) -> MaskBlock {
+        let mask = self.mask & other.mask & !(self.id ^ other.id) & max_id;
+        let mut merged = MaskBlock { mask, id: self.id & mask, exact: false };
+        // Two disjoint exact blocks whose union is exactly the merged block (Such as 0x100 and 0x102)
+        merged.exact = self.exact && other.exact
+            && !self.contains(other) && !other.contains(self)
+            && merged.size(max_id) == self.size(max_id) + other.size(max_id);
+        merged
+    }
+}
+
+/// Sorts and merges overlapping or adjacent ranges, clamping them to the ID space
+fn normalize(mut ranges: Vec<(u32, u32)>, max_id: u32) -> Vec<(u32, u32)> {
+    ranges.retain(|(start, _)| *start <= max_id);
+    ranges.iter_mut().for_each(|r| r.1 = r.1.min(max_id));
+    ranges.sort_unstable();
+    let mut res: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match res.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => res.push((start, end))
+        }
+    }
+    res
+}
+
+fn intersect(a: &[(u32, u32)], b: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut res = Vec::new();
+    for (a_start, a_end) in a {
+        for (b_start, b_end) in b {
+            let start = *a_start.max(b_start);
+            let end = *a_end.min(b_end);
+            if start <= end {
+                res.push((start, end));
+            }
+        }
+    }
+    res
+}
+
+fn subtract(a: &[(u32, u32)], b: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut res = Vec::new();
+    for (start, end) in a {
+        let mut cursor = *start as u64;
+        for (b_start, b_end) in b {
+            if (*b_end as u64) < cursor || b_start > end {
+                continue;
+            }
+            if (*b_start as u64) > cursor {
+                res.push((cursor as u32, b_start - 1));
+            }
+            cursor = *b_end as u64 + 1;
+        }
+        if cursor <= *end as u64 {
+            res.push((cursor as u32, *end));
+        }
+    }
+    res
+}
+
+/// Splits ranges into the fewest aligned power of two blocks, each of which maps to exactly one mask/ID pair
+fn decompose(ranges: &[(u32, u32)], max_id: u32) -> Vec<MaskBlock> {
+    let mut res = Vec::new();
+    for (start, end) in ranges {
+        let mut cursor = *start as u64;
+        let end = *end as u64;
+        while cursor <= end {
+            // Largest block aligned to cursor, which does not go past the end of the range
+            let mut bits = if cursor == 0 { 32 } else { cursor.trailing_zeros() };
+            while bits > 0 && cursor + (1u64 << bits) - 1 > end {
+                bits -= 1;
+            }
+            let mask = !((1u64 << bits) - 1) as u32 & max_id;
+            res.push(MaskBlock { mask, id: cursor as u32 & mask, exact: true });
+            cursor += 1u64 << bits;
+        }
+    }
+    res
+}
+
+/// Greedily merges the pair of blocks that results in the smallest block, until the blocks fit in max_filters.
+/// max_filters must not be 0
+fn merge_to_fit(mut blocks: Vec<MaskBlock>, max_filters: usize, max_id: u32) -> Vec<MaskBlock> {
+    while blocks.len() > max_filters {
+        let mut best: Option<(usize, usize, MaskBlock)> = None;
+        for i in 0..blocks.len() {
+            for j in i + 1..blocks.len() {
+                let merged = blocks[i].merge(&blocks[j], max_id);
+                let better = match &best {
+                    Some((_, _, b)) => merged.size(max_id) < b.size(max_id),
+                    None => true
+                };
+                if better {
+                    best = Some((i, j, merged));
+                }
+            }
+        }
+        let (i, j, merged) = best.unwrap();
+        blocks.remove(j);
+        blocks.remove(i);
+        // Any block now covered by the merged block is redundant
+        let before = blocks.len();
+        blocks.retain(|b| !merged.contains(b));
+        let merged = MaskBlock { exact: merged.exact && before == blocks.len(), ..merged };
+        blocks.push(merged);
+    }
+    blocks
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::data_structures::HWCanFrame;
+
+    fn passes(plan: &FilterPlan, id: u32) -> bool {
+        let pass = plan.filters().iter().any(|f| matches!(f, AdapterFilter::Pass { mask, id: p } if id & mask == *p));
+        let block = plan.filters().iter().any(|f| matches!(f, AdapterFilter::Block { mask, id: p } if id & mask == *p));
+        pass && !block
+    }
+
+    #[test]
+    pub fn test_range_exact() {
+        let plan = FilterPlanner::new(DEFAULT_MAX_FILTERS, false)
+            .pass(FilterRule::Range { start: 0x4E0, end: 0x4FF })
+            .compile();
+        assert!(plan.is_exact());
+        assert_eq!(1, plan.filters().len());
+        assert!(passes(&plan, 0x4E0) && passes(&plan, 0x4FF));
+        assert!(!passes(&plan, 0x4DF) && !passes(&plan, 0x500));
+    }
+
+    #[test]
+    pub fn test_all_except() {
+        let plan = FilterPlanner::new(DEFAULT_MAX_FILTERS, false)
+            .pass(FilterRule::All)
+            .block(FilterRule::Range { start: 0x400, end: 0x47F })
+            .compile();
+        assert!(plan.is_exact());
+        for id in 0..=0x7FF {
+            assert_eq!(!(0x400..=0x47F).contains(&id), passes(&plan, id), "ID 0x{:03X}", id);
+        }
+    }
+
+    #[test]
+    pub fn test_list_needs_software() {
+        let ids = vec![0x4E8, 0x4E9, 0x5B4, 0x5B5, 0x5B8, 0x5C1, 0x5D9, 0x5DE, 0x5E1, 0x5E6, 0x61F, 0x628, 0x63A, 0x6F3];
+        let plan = FilterPlanner::new(DEFAULT_MAX_FILTERS, false)
+            .pass(FilterRule::List(ids.clone()))
+            .compile();
+        assert!(plan.filters().len() <= DEFAULT_MAX_FILTERS);
+        assert!(!plan.is_exact());
+        // Hardware must never drop a wanted ID
+        for id in &ids {
+            assert!(passes(&plan, *id));
+        }
+        let frames: Vec<HWCanFrame> = (0..=0x7FF)
+            .filter(|id| passes(&plan, *id))
+            .map(|id| HWCanFrame::new(id, &[0x00]))
+            .collect();
+        let filtered = plan.filter_frames(frames);
+        assert_eq!(ids, filtered.iter().map(|f| f.get_id()).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    pub fn test_no_filter_slots() {
+        let plan = FilterPlanner::new(0, false)
+            .pass(FilterRule::Range { start: 0x4E0, end: 0x4FF })
+            .compile();
+        assert!(plan.filters().is_empty());
+        assert!(!plan.is_exact());
+        let frames: Vec<HWCanFrame> = (0..=0x7FF).map(|id| HWCanFrame::new(id, &[0x00])).collect();
+        assert_eq!((0x4E0..=0x4FF).collect::<Vec<u32>>(), plan.filter_frames(frames).iter().map(|f| f.get_id()).collect::<Vec<u32>>());
+
+        // Nothing is wanted, so there is nothing to filter
+        assert!(FilterPlanner::new(0, false).compile().is_exact());
+    }
+}
@@ -6,14 +6,25 @@ use j2534_rust::Loggable;
 use logger::Logger;
 
 pub mod data_structures;
+pub mod filter_planner;
+pub mod bitrate;
+pub mod fault_injection;
+pub mod monitor;
 mod communication_apis;
 
+pub use communication_apis::passthru::PassthruAdapter;
+pub use communication_apis::sim::{SimAdapter, SimHandler};
+#[cfg(target_os = "linux")]
+pub use communication_apis::socketcan::SocketCanAdapter;
+
 extern crate j2534_rust;
 
 #[derive(Debug)]
 pub enum HardwareError {
     HwApiError { code: u32, desc: String },
     IoError(std::io::Error),
+    /// No data was received from the vehicle within the read timeout
+    Timeout,
     Other(String)
 }
 
@@ -29,7 +40,7 @@ impl Into<HardwareError> for j2534_rust::PassthruError {
 
 /// Enum representing the various communication protocols that can be established with the vehicle
 /// as logical communication channels
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AdapterChannel {
     /// Canbus channel (ISO11898)
     Can,
@@ -198,7 +209,8 @@ pub trait AdapterHardware: Sized + Sync + Send + Debug + Clone {
     ///     and instead just return whatever is in its channel FIFO queue.
     /// 
     /// ## Returns
-    /// * An array of data messages read from the vehicle. The size of this array can be less than [max_read]
+    /// * An array of data messages read from the vehicle. The size of this array can be less than [max_read],
+    ///     and is empty if no data was received before the timeout
     fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>>;
 
     /// Writes data to the vehicle. The channel to transmit data on is determined based on the Data type.
@@ -225,7 +237,7 @@ pub trait AdapterHardware: Sized + Sync + Send + Debug + Clone {
     /// If successful, this function will return a response message.
     fn read_and_write<T: HwDataFrame>(&mut self, write: T, write_timeout_ms: u128, read_timeout_ms: u128) -> HardwareResult<T> {
         self.write_data(&[write], write_timeout_ms)?;
-        self.read_data(1, read_timeout_ms).map(|read: Vec<T>| read[0].clone())
+        self.read_data(1, read_timeout_ms)?.into_iter().next().ok_or(HardwareError::Timeout)
     }
 
     /// Configures a channel with a IOCTL parameter
@@ -233,14 +245,14 @@ pub trait AdapterHardware: Sized + Sync + Send + Debug + Clone {
     /// ## Arguments
     /// * channel_id - The ID of the channel to perform the IOCTL operation on
     /// * param - The IOCTL parameter to apply to the channel
-    fn channel_set_ioctl(channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()>;
+    fn channel_set_ioctl(&mut self, channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()>;
 
     /// Reads a channel's IOCTL parameter
     /// 
     /// ## Arguments
     /// * channel_id - The ID of the channel to read the IOCTL parameter from
     /// * param - The IOCTL parameter to read from the channel. The value within will be set if this function succeeds
-    fn channel_get_ioctl(channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()>;
+    fn channel_get_ioctl(&mut self, channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()>;
 
 
     /// Performs a LIN based initialization of a LIN channel
@@ -249,7 +261,24 @@ pub trait AdapterHardware: Sized + Sync + Send + Debug + Clone {
     /// * channel_id - The ID of the LIN channel to initialize
     /// * init_type - Mutable reference to the initialization type of the channel. If this function succeeds,
     ///     the data within this will be replaced by the response from the ECU.
-    fn channel_lin_init(channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()>;
+    fn channel_lin_init(&mut self, channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()>;
+
+    /// Attempts to detect the bitrate of the CAN bus the adapter is connected to. Each candidate bitrate
+    /// is tried in turn by listening on the bus in listen-only mode, without transmitting anything. The
+    /// bitrate which receives valid traffic without any errors is picked.
+    ///
+    /// The adapter must not have a CAN channel open whilst this function runs. Adapters which cannot
+    /// listen passively return an error, as probing with an active controller disturbs the bus.
+    ///
+    /// ## Arguments
+    /// * candidates - The bitrates to try (In bps). See [bitrate::MERCEDES_CAN_BITRATES]
+    /// * listen_ms - How long to listen on the bus at each bitrate
+    ///
+    /// ## Returns
+    /// The detected bitrate, along with the results of every bitrate that was tried
+    fn detect_can_bitrate(&mut self, _candidates: &[u32], _listen_ms: u128) -> HardwareResult<bitrate::BitrateDetection> {
+        Err(bitrate::passive_probing_not_supported())
+    }
 
     /// Attempts to reset the Adapter by closing and opening it again (Turning it off and on again)
     fn reset_device(&mut self) -> HardwareResult<()> {
@@ -310,12 +339,122 @@ pub fn get_device_list(api: HardwareAPI) -> Vec<String> {
                 }
             }
         }
+        #[cfg(target_os = "linux")]
+        HardwareAPI::SocketCAN => {
+            logger.log_debug("Scanning for SocketCAN interfaces".into());
+            SocketCanAdapter::find_all()
+        }
         _ => Vec::new()
     }
 }
 
-pub fn open_device(name: &str, api: HardwareAPI) -> bool {
+/// An adapter from any of the supported hardware APIs. Several adapters can be open at the same time,
+/// each with their own independent device handle, even if they use different APIs.
+#[derive(Debug, Clone)]
+pub enum Adapter {
+    Passthru(PassthruAdapter),
+    Sim(SimAdapter),
+    #[cfg(target_os = "linux")]
+    SocketCAN(SocketCanAdapter)
+}
+
+macro_rules! dispatch {
+    ($self:ident, $a:ident => $e:expr) => {
+        match $self {
+            Adapter::Passthru($a) => $e,
+            Adapter::Sim($a) => $e,
+            #[cfg(target_os = "linux")]
+            Adapter::SocketCAN($a) => $e,
+        }
+    };
+}
+
+impl Adapter {
+    /// Returns the hardware API the adapter uses
+    pub fn api(&self) -> HardwareAPI {
+        match self {
+            Adapter::Passthru(_) => HardwareAPI::Passthru,
+            Adapter::Sim(_) => HardwareAPI::Sim,
+            #[cfg(target_os = "linux")]
+            Adapter::SocketCAN(_) => HardwareAPI::SocketCAN,
+        }
+    }
+}
+
+impl AdapterHardware for Adapter {
+    fn open_device(&mut self) -> HardwareResult<()> {
+        dispatch!(self, a => a.open_device())
+    }
+
+    fn close_device(&mut self) -> HardwareResult<()> {
+        dispatch!(self, a => a.close_device())
+    }
+
+    fn read_voltage(&mut self) -> HardwareResult<f32> {
+        dispatch!(self, a => a.read_voltage())
+    }
+
+    fn open_channel(&mut self, channel_type: AdapterChannel) -> HardwareResult<u32> {
+        dispatch!(self, a => a.open_channel(channel_type))
+    }
+
+    fn close_channel(&mut self, id: u32) -> HardwareResult<()> {
+        dispatch!(self, a => a.close_channel(id))
+    }
+
+    fn add_channel_filter(&mut self, channel_id: u32, filter: AdapterFilter, baud: u32, flags: &[ChannelFlags]) -> HardwareResult<u32> {
+        dispatch!(self, a => a.add_channel_filter(channel_id, filter, baud, flags))
+    }
+
+    fn del_channel_filter(&mut self, channel_id: u32, filter_id: u32) -> HardwareResult<u32> {
+        dispatch!(self, a => a.del_channel_filter(channel_id, filter_id))
+    }
+
+    fn clear_channel_buffer(&mut self, channel_id: u32, buffer: AdapterBuffer) -> HardwareResult<()> {
+        dispatch!(self, a => a.clear_channel_buffer(channel_id, buffer))
+    }
+
+    fn read_data<T: HwDataFrame>(&mut self, max_read: usize, timeout_ms: u128) -> HardwareResult<Vec<T>> {
+        dispatch!(self, a => a.read_data(max_read, timeout_ms))
+    }
+
+    fn write_data<T: HwDataFrame>(&mut self, input: &[T], timeout_ms: u128) -> HardwareResult<()> {
+        dispatch!(self, a => a.write_data(input, timeout_ms))
+    }
+
+    fn channel_set_ioctl(&mut self, channel_id: u32, param: IoctlIdentifier) -> HardwareResult<()> {
+        dispatch!(self, a => a.channel_set_ioctl(channel_id, param))
+    }
+
+    fn channel_get_ioctl(&mut self, channel_id: u32, param: &mut IoctlIdentifier) -> HardwareResult<()> {
+        dispatch!(self, a => a.channel_get_ioctl(channel_id, param))
+    }
+
+    fn channel_lin_init(&mut self, channel_id: u32, init_type: &mut LinInitType) -> HardwareResult<()> {
+        dispatch!(self, a => a.channel_lin_init(channel_id, init_type))
+    }
+
+    fn detect_can_bitrate(&mut self, candidates: &[u32], listen_ms: u128) -> HardwareResult<bitrate::BitrateDetection> {
+        dispatch!(self, a => a.detect_can_bitrate(candidates, listen_ms))
+    }
+}
+
+/// Opens a device by its name, as listed by [get_device_list]
+///
+/// ## Returns
+/// The opened adapter. This function can be called again to open further adapters alongside it.
+pub fn open_device(name: &str, api: HardwareAPI) -> HardwareResult<Adapter> {
     let logger = Logger::new("Hardware");
     logger.log_debug(format!("Trying to open device '{}' using {} API", name, api));
-    return true;
-}
\ No newline at end of file
+    let mut adapter = match api {
+        HardwareAPI::Passthru => PassthruAdapter::from_name(name)
+            .map(Adapter::Passthru)
+            .ok_or_else(|| HardwareError::Other(format!("No Passthru device named '{}'", name)))?,
+        HardwareAPI::Sim => Adapter::Sim(SimAdapter::new(|_, _, _| Vec::new())),
+        #[cfg(target_os = "linux")]
+        HardwareAPI::SocketCAN => Adapter::SocketCAN(SocketCanAdapter::new(name)),
+        _ => return Err(HardwareError::Other(format!("{} API is not supported", api)))
+    };
+    adapter.open_device()?;
+    Ok(adapter)
+}
@@ -0,0 +1,324 @@
+//! Passive CAN bus monitor
+//!
+//! [BusMonitor] receives all traffic on a CAN bus without ever transmitting, and keeps track of the latest
+//! payload, cycle time and changing bytes of every CAN ID, much like `cansniffer`. This is used to
+//! reverse engineer which signals change when something is done in the vehicle, such as pressing a button.
+//!
+//! Bits which change on their own (Counters, checksums) can be masked with [BusMonitor::mask_changing_bits],
+//! so that only new changes are highlighted.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::data_structures::{HWCanFrame, HwDataFrame};
+use crate::{AdapterChannel, AdapterFilter, AdapterHardware, ChannelFlags, HardwareError, HardwareResult};
+
+/// Weight of the newest inter-frame interval in the average cycle time
+const CYCLE_TIME_SMOOTHING: f32 = 0.2;
+
+/// Time to wait before reading again after the adapter returned an error. Doubled after every
+/// consecutive error, up to [MAX_ERROR_BACKOFF]
+const MIN_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The state of a single CAN ID seen on the bus
+#[derive(Debug, Clone)]
+pub struct MonitoredId {
+    /// CAN ID
+    pub id: u32,
+    /// Latest payload received
+    pub data: Vec<u8>,
+    /// Number of frames received
+    pub count: u64,
+    /// Time the latest frame was received
+    pub last_seen: Instant,
+    /// Average time between frames. None until at least 2 frames are received
+    pub cycle_time: Option<Duration>,
+    /// Bits of each byte which changed in the latest frame, excluding masked bits
+    pub changed_bits: Vec<u8>,
+    /// Time each byte last changed, excluding masked bits
+    pub byte_changed_at: Vec<Option<Instant>>,
+    /// Bits which have ever changed since the mask was last updated
+    seen_changing: Vec<u8>,
+    /// Bits which are ignored for change highlighting
+    mask: Vec<u8>
+}
+
+impl MonitoredId {
+    fn new(id: u32, data: &[u8], now: Instant) -> Self {
+        Self {
+            id,
+            data: data.to_vec(),
+            count: 1,
+            last_seen: now,
+            cycle_time: None,
+            changed_bits: vec![0; data.len()],
+            byte_changed_at: vec![None; data.len()],
+            seen_changing: vec![0; data.len()],
+            mask: vec![0; data.len()]
+        }
+    }
+
+    fn update(&mut self, data: &[u8], now: Instant) {
+        let interval = now.saturating_duration_since(self.last_seen);
+        self.cycle_time = Some(match self.cycle_time {
+            Some(avg) => avg.mul_f32(1.0 - CYCLE_TIME_SMOOTHING) + interval.mul_f32(CYCLE_TIME_SMOOTHING),
+            None => interval
+        });
+
+        let len = data.len();
+        for v in [&mut self.changed_bits, &mut self.seen_changing, &mut self.mask] {
+            v.resize(len, 0);
+        }
+        self.byte_changed_at.resize(len, None);
+        for (i, byte) in data.iter().enumerate() {
+            // Bytes which did not exist in the previous frame count as fully changed
+            let diff = byte ^ self.data.get(i).copied().unwrap_or(!byte);
+            self.seen_changing[i] |= diff;
+            self.changed_bits[i] = diff & !self.mask[i];
+            if self.changed_bits[i] != 0 {
+                self.byte_changed_at[i] = Some(now);
+            }
+        }
+        self.data = data.to_vec();
+        self.count += 1;
+        self.last_seen = now;
+    }
+
+    /// Returns true if a byte changed within the hold time, and should be highlighted
+    pub fn is_byte_highlighted(&self, index: usize, hold: Duration, now: Instant) -> bool {
+        match self.byte_changed_at.get(index) {
+            Some(Some(t)) => now.saturating_duration_since(*t) <= hold,
+            _ => false
+        }
+    }
+
+    /// Returns true if the ID has not been seen for more than 3 cycle times (Or 1 second for IDs with no cycle time yet)
+    pub fn is_stale(&self, now: Instant) -> bool {
+        let timeout = self.cycle_time.map(|c| c * 3).unwrap_or_else(|| Duration::from_secs(1));
+        now.saturating_duration_since(self.last_seen) > timeout
+    }
+}
+
+/// Per ID state of the bus, updated by every received frame
+#[derive(Debug, Clone, Default)]
+pub struct MonitorState {
+    ids: BTreeMap<u32, MonitoredId>,
+    frames: u64,
+    error: Option<String>
+}
+
+impl MonitorState {
+    /// Processes a frame received from the bus
+    pub fn process_frame(&mut self, id: u32, data: &[u8], now: Instant) {
+        self.frames += 1;
+        match self.ids.get_mut(&id) {
+            Some(m) => m.update(data, now),
+            None => {
+                self.ids.insert(id, MonitoredId::new(id, data, now));
+            }
+        }
+    }
+
+    /// Returns the state of every ID seen, sorted by ID
+    pub fn ids(&self) -> Vec<MonitoredId> {
+        self.ids.values().cloned().collect()
+    }
+
+    /// Returns the state of a single ID
+    pub fn get(&self, id: u32) -> Option<&MonitoredId> {
+        self.ids.get(&id)
+    }
+
+    /// Total number of frames received
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// The error the adapter returned when the bus was last read, or None if reading works. This is set
+    /// when the adapter is disconnected, and cleared once frames can be read again
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Masks every bit which has changed so far, so it is no longer highlighted
+    pub fn mask_changing_bits(&mut self) {
+        for m in self.ids.values_mut() {
+            for i in 0..m.mask.len() {
+                m.mask[i] |= m.seen_changing[i];
+                m.changed_bits[i] = 0;
+                m.byte_changed_at[i] = None;
+            }
+        }
+    }
+
+    /// Removes all masks
+    pub fn clear_masks(&mut self) {
+        for m in self.ids.values_mut() {
+            m.mask.iter_mut().for_each(|b| *b = 0);
+            m.seen_changing.iter_mut().for_each(|b| *b = 0);
+        }
+    }
+
+    /// Forgets every ID seen
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.frames = 0;
+    }
+}
+
+/// Monitors all traffic on a CAN bus in a background thread, without transmitting anything
+#[derive(Debug)]
+pub struct BusMonitor<A: AdapterHardware + 'static> {
+    adapter: A,
+    channel_id: u32,
+    state: Arc<Mutex<MonitorState>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl<A: AdapterHardware + 'static> BusMonitor<A> {
+    /// Opens a CAN channel which receives every frame on the bus, and starts monitoring it
+    ///
+    /// ## Arguments
+    /// * adapter - The adapter to monitor the bus with. It must already be open
+    /// * baud - The bitrate of the bus (In bps)
+    /// * flags - Channel flags. Use [ChannelFlags::CAN_USE_29BIT_ADDR] to monitor 29bit IDs
+    pub fn start(mut adapter: A, baud: u32, flags: &[ChannelFlags]) -> HardwareResult<Self> {
+        let channel_id = adapter.open_channel(AdapterChannel::Can)?;
+        if let Err(e) = adapter.add_channel_filter(channel_id, AdapterFilter::Pass { mask: 0, id: 0 }, baud, flags) {
+            let _ = adapter.close_channel(channel_id);
+            return Err(e);
+        }
+        let state = Arc::new(Mutex::new(MonitorState::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let mut thread_adapter = adapter.clone();
+        let thread_state = state.clone();
+        let thread_running = running.clone();
+        let thread = std::thread::spawn(move || {
+            let mut backoff = MIN_ERROR_BACKOFF;
+            while thread_running.load(Ordering::Relaxed) {
+                match thread_adapter.read_data::<HWCanFrame>(100, 20) {
+                    Ok(frames) => {
+                        backoff = MIN_ERROR_BACKOFF;
+                        let now = Instant::now();
+                        let mut state = thread_state.lock().unwrap();
+                        state.error = None;
+                        for f in frames {
+                            state.process_frame(f.get_id(), f.get_data(), now);
+                        }
+                    },
+                    Err(HardwareError::Timeout) => {},
+                    Err(e) => {
+                        // The adapter fails immediately once it is unplugged, so wait before trying again
+                        thread_state.lock().unwrap().error = Some(format!("{:?}", e));
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_ERROR_BACKOFF);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            adapter,
+            channel_id,
+            state,
+            running,
+            thread: Some(thread)
+        })
+    }
+
+    /// Returns the state of every ID seen on the bus, sorted by ID
+    pub fn snapshot(&self) -> Vec<MonitoredId> {
+        self.state.lock().unwrap().ids()
+    }
+
+    /// Returns the full monitor state. The lock should only be held briefly, as it blocks receiving
+    pub fn state(&self) -> Arc<Mutex<MonitorState>> {
+        self.state.clone()
+    }
+
+    /// See [MonitorState::mask_changing_bits]
+    pub fn mask_changing_bits(&self) {
+        self.state.lock().unwrap().mask_changing_bits()
+    }
+
+    /// See [MonitorState::clear_masks]
+    pub fn clear_masks(&self) {
+        self.state.lock().unwrap().clear_masks()
+    }
+
+    /// Stops monitoring, and closes the CAN channel
+    pub fn stop(mut self) -> HardwareResult<()> {
+        self.stop_thread();
+        self.adapter.close_channel(self.channel_id)
+    }
+
+    fn stop_thread(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl<A: AdapterHardware + 'static> Drop for BusMonitor<A> {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop_thread();
+            let _ = self.adapter.close_channel(self.channel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::SimAdapter;
+
+    #[test]
+    pub fn test_change_tracking() {
+        let mut state = MonitorState::default();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        // Byte 0 is a rolling counter
+        for counter in 0..4 {
+            state.process_frame(0x0003, &[counter, 0x10, 0x00], at(counter as u64 * 100));
+        }
+        state.mask_changing_bits();
+        state.process_frame(0x0003, &[0x00, 0x10, 0x04], at(400));
+
+        let id = state.get(0x0003).unwrap();
+        assert_eq!(5, id.count);
+        assert_eq!(vec![0x00, 0x00, 0x04], id.changed_bits);
+        assert!(!id.is_byte_highlighted(0, Duration::from_millis(500), at(400)));
+        assert!(id.is_byte_highlighted(2, Duration::from_millis(500), at(400)));
+        assert_eq!(100, (id.cycle_time.unwrap().as_secs_f32() * 1000.0).round() as u32);
+    }
+
+    #[test]
+    pub fn test_monitor_sim() {
+        let mut sim = SimAdapter::new(|_, _, _| Vec::new());
+        sim.open_device().unwrap();
+        let monitor = BusMonitor::start(sim.clone(), 500_000, &[]).unwrap();
+        sim.inject_frame(AdapterChannel::Can, 0x0210, &[0x00, 0x01]);
+        sim.inject_frame(AdapterChannel::Can, 0x0008, &[0xFF]);
+        sim.inject_frame(AdapterChannel::Can, 0x0210, &[0x00, 0x02]);
+        std::thread::sleep(Duration::from_millis(100));
+        let snapshot = monitor.snapshot();
+        assert_eq!(vec![0x0008, 0x0210], snapshot.iter().map(|m| m.id).collect::<Vec<u32>>());
+        assert_eq!(vec![0x00, 0x02], snapshot[1].data);
+        assert_eq!(vec![0x00, 0x03], snapshot[1].changed_bits);
+        assert_eq!(None, monitor.state().lock().unwrap().error());
+
+        // Closing the channel behind the monitor's back makes every read fail, as if the adapter was unplugged
+        sim.close_channel(monitor.channel_id).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(monitor.state().lock().unwrap().error().is_some());
+        drop(monitor);
+    }
+}
@@ -1,4 +1,4 @@
-use hardware::HardwareAPI;
+use hardware::{AdapterHardware, HardwareAPI};
 use iced::{Align, Color, Column, Row, Text};
 use logger::Logger;
 use nfd2::Response;
@@ -110,11 +110,20 @@ impl iced::Application for Launcher {
             },
             LauncherMsg::LaunchPress => {
                 if self.launch_ready {
-                    if hardware::open_device(&self.selected_hw.clone().unwrap(), self.api) {
-                        self.exit = true;
-                        unsafe { super::launcher_ok = true };
-                    } else {
-
+                    match hardware::open_device(&self.selected_hw.clone().unwrap(), self.api) {
+                        Ok(mut adapter) => {
+                            // iced ends the process once the launcher exits, without dropping the launcher,
+                            // so the device is closed here instead of being left open until the process ends
+                            if let Err(e) = adapter.close_device() {
+                                self.logger.log_err(format!("Could not close device: {:?}", e));
+                            }
+                            self.exit = true;
+                            unsafe { super::launcher_ok = true };
+                        },
+                        Err(e) => {
+                            self.logger.log_err(format!("Could not open device: {:?}", e));
+                            self.error = Some(format!("Could not open device: {:?}", e));
+                        }
                     }
                 }
             },